@@ -0,0 +1,102 @@
+//! Flat A* against HPA* on generated maps, run with `cargo bench`.
+
+use std::time::{Duration, Instant};
+
+use rts_game_engine::engine::pathfinding_module::*;
+
+const QUERIES: usize = 50;
+
+// small deterministic LCG so every run benchmarks the same maps
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, max: i32) -> i32 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % max as u64) as i32
+    }
+}
+
+fn generate_terrain(size: i32, rng: &mut Lcg) -> PathfindingTerrain {
+    let mut terrain = PathfindingTerrain::new(size, size, None);
+
+    // scattered rocks plus long walls with gaps, roughly like the LDtk maps
+    for _ in 0..size * size / 8 {
+        terrain.set_node_type(rng.next(size), rng.next(size), NodeType::Unwalkable);
+    }
+    for _ in 0..size / 4 {
+        let (x, y, length) = (rng.next(size), rng.next(size), rng.next(size / 2));
+        let vertical = rng.next(2) == 0;
+        for i in 0..length {
+            let (wx, wy) = if vertical { (x, y + i) } else { (x + i, y) };
+            if wx < size && wy < size && i % 7 != 3 {
+                terrain.set_node_type(wx, wy, NodeType::Unwalkable);
+            }
+        }
+    }
+
+    terrain
+}
+
+fn random_walkable(terrain: &PathfindingTerrain, rng: &mut Lcg) -> Node {
+    loop {
+        let node = terrain
+            .get_node_by_position(rng.next(terrain.width), rng.next(terrain.height))
+            .unwrap();
+        if node.node_type != Some(NodeType::Unwalkable) {
+            return node;
+        }
+    }
+}
+
+fn time_queries(
+    queries: &[(Node, Node)],
+    search: impl Fn(Node, Node) -> Option<Vec<Node>>,
+) -> (Duration, usize) {
+    let now = Instant::now();
+    let found = queries
+        .iter()
+        .filter(|(start, goal)| search(*start, *goal).is_some())
+        .count();
+    (now.elapsed(), found)
+}
+
+fn main() {
+    let mut rng = Lcg(0x5eed);
+
+    for size in [53, 128, 256] {
+        let mut terrain = generate_terrain(size, &mut rng);
+        let queries = (0..QUERIES)
+            .map(|_| {
+                (
+                    random_walkable(&terrain, &mut rng),
+                    random_walkable(&terrain, &mut rng),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        let (flat, flat_found) = time_queries(&queries, |s, g| terrain.get_path(s, g));
+
+        let now = Instant::now();
+        terrain.enable_hierarchy(DEFAULT_CLUSTER_SIZE);
+        let build = now.elapsed();
+
+        let (hierarchical, hierarchical_found) =
+            time_queries(&queries, |s, g| terrain.get_hierarchical_path(s, g));
+
+        // a single tile edit only rebuilds the clusters around it
+        let now = Instant::now();
+        let edited = random_walkable(&terrain, &mut rng);
+        terrain.set_node_type(edited.x, edited.y, NodeType::Unwalkable);
+        terrain.refresh_hierarchy();
+        let rebuild = now.elapsed();
+
+        println!("{size}x{size}, {QUERIES} queries");
+        println!("  flat A* queries  {flat:>12.2?} ({flat_found} found)");
+        println!("  HPA* build       {build:>12.2?}");
+        println!("  HPA* queries     {hierarchical:>12.2?} ({hierarchical_found} found)");
+        println!("  HPA* tile edit   {rebuild:>12.2?}");
+    }
+}
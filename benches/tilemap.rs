@@ -0,0 +1,83 @@
+//! Tilemap submission cost per frame on a 256x256 map, run with `cargo bench`.
+//!
+//! "before" issues a `draw_sprite_ex` per tile of every layer like `Map::draw`
+//! used to, "after" draws the culled static chunks. Only the CPU side of a
+//! frame is measured, there is no GPU in a headless run.
+
+use std::time::{Duration, Instant};
+
+use comfy::*;
+use rts_game_engine::engine::level_module::{TileQuad, TilemapBatch};
+
+const SIZE: i32 = 256;
+const LAYERS: i32 = 2;
+const FRAMES: u32 = 100;
+const TEXTURE_SIZE: Vec2 = Vec2::new(512.0, 512.0);
+
+fn layer_tiles(layer: i32) -> Vec<TileQuad> {
+    (0..SIZE)
+        .flat_map(|y| (0..SIZE).map(move |x| (x, y)))
+        .map(|(x, y)| TileQuad {
+            position: vec2(x as f32, y as f32),
+            size: 1.0,
+            source: IRect::new(ivec2((x + layer) % 8 * 64, y % 8 * 64), ivec2(64, 64)),
+            flip_x: false,
+            flip_y: false,
+        })
+        .collect()
+}
+
+fn time_frames(mut frame: impl FnMut()) -> Duration {
+    let now = Instant::now();
+    for _ in 0..FRAMES {
+        frame();
+        // the renderer would consume the queue at the end of the frame
+        GLOBAL_STATE.borrow_mut().mesh_queue.clear();
+    }
+    now.elapsed() / FRAMES
+}
+
+fn main() {
+    let texture = texture_id_unchecked("tileset");
+    let layers = (0..LAYERS).map(layer_tiles).collect::<Vec<_>>();
+
+    let before = time_frames(|| {
+        for (z, tiles) in layers.iter().enumerate() {
+            for tile in tiles {
+                draw_sprite_ex(
+                    texture,
+                    tile.position,
+                    WHITE,
+                    z as i32,
+                    DrawTextureParams {
+                        dest_size: Some(splat(tile.size).as_world_size()),
+                        source_rect: Some(tile.source),
+                        ..Default::default()
+                    },
+                );
+            }
+        }
+    });
+
+    let now = Instant::now();
+    let mut batch = TilemapBatch::new();
+    for (z, tiles) in layers.iter().enumerate() {
+        batch.add_layer(z as i32, TEXTURE_SIZE, tiles.iter().copied());
+    }
+    let build = now.elapsed();
+
+    println!("{SIZE}x{SIZE}, {LAYERS} layers, mean of {FRAMES} frames");
+    println!("  per-tile sprites    {before:>12.2?}");
+    println!("  chunk build (once)  {build:>12.2?}");
+
+    // the camera's zoom range, 10 to 50 tiles wide at 16:9
+    for zoom in [10.0, 50.0] {
+        let view = AABB::from_center_size(splat(SIZE as f32 / 2.0), vec2(zoom, zoom * 9.0 / 16.0));
+        let visible = batch.visible(view).count();
+        let after = time_frames(|| batch.draw(texture, view));
+        println!("  chunks, zoom {zoom:>4}   {after:>12.2?} ({visible} chunks)");
+    }
+    let everything = AABB::new(Vec2::splat(-1.0), splat(SIZE as f32));
+    let all = time_frames(|| batch.draw(texture, everything));
+    println!("  chunks, whole map   {all:>12.2?}");
+}
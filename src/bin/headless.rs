@@ -0,0 +1,170 @@
+//! Runs a level without a window and prints where the units end up.
+//!
+//! cargo run --bin headless -- [--level NAME] [--ticks N] [--move X,Y]
+//! cargo run --bin headless -- --replay FILE [--ticks N]
+//!
+//! A replay that no longer plays out as recorded exits with a failure.
+
+use std::process::ExitCode;
+
+use comfy::*;
+use rts_game_engine::engine::{
+    level_module::{Map, DEFAULT_LEVEL, RTS_LEVEL_LDTK},
+    replay_module::{Replay, ReplayPlayer, DESYNCS_DIRECTORY},
+    simulation_module::{Command, Simulation, TICK_DT, TICK_RATE},
+    unit_module::{Order, Owner, Unit, UnitArchetypes, UnitType, UNIT_ARCHETYPES_PATH},
+};
+
+const DEFAULT_TICKS: u64 = 200;
+/// Ticks a replay keeps running after its last command by default, so the
+/// last orders can play out.
+const REPLAY_SETTLE_TICKS: u64 = 10 * TICK_RATE as u64;
+
+struct Options {
+    level: String,
+    ticks: Option<u64>,
+    /// Where the local player's units are sent on the first tick.
+    target: Option<Vec2>,
+    /// Replay to play instead, `--level` and `--move` are ignored.
+    replay: Option<String>,
+}
+
+fn parse_options() -> Result<Options, String> {
+    let mut options = Options {
+        level: DEFAULT_LEVEL.to_string(),
+        ticks: None,
+        target: None,
+        replay: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--level" => options.level = value()?,
+            "--ticks" => {
+                options.ticks = Some(
+                    value()?
+                        .parse()
+                        .map_err(|err| format!("bad tick count: {err}"))?,
+                )
+            }
+            "--replay" => options.replay = Some(value()?),
+            "--move" => {
+                let value = value()?;
+                let (x, y) = value
+                    .split_once(',')
+                    .ok_or(format!("expected X,Y, got {value}"))?;
+                let parse = |v: &str| v.trim().parse::<f32>().map_err(|err| err.to_string());
+                options.target = Some(vec2(parse(x)?, parse(y)?));
+            }
+            other => return Err(format!("unknown argument {other}")),
+        }
+    }
+    Ok(options)
+}
+
+fn main() -> ExitCode {
+    let options = match parse_options() {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!(
+                "{err}\nusage: headless [--level NAME] [--ticks N] [--move X,Y] [--replay FILE]"
+            );
+            return ExitCode::FAILURE;
+        }
+    };
+    let mut sim = Simulation::new();
+    match UnitArchetypes::load(UNIT_ARCHETYPES_PATH) {
+        Ok(archetypes) => sim.archetypes = archetypes,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let replay = match options
+        .replay
+        .as_deref()
+        .map(|path| Replay::load(path, &sim.archetypes))
+        .transpose()
+    {
+        Ok(replay) => replay,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let level = replay
+        .as_ref()
+        .map_or(options.level.as_str(), |replay| replay.level.as_str());
+    let map = match Map::load(RTS_LEVEL_LDTK, level) {
+        Ok(map) => map,
+        Err(err) => {
+            eprintln!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let started = Instant::now();
+    match replay {
+        Some(replay) => {
+            let ticks = options
+                .ticks
+                .unwrap_or(replay.last_tick() + REPLAY_SETTLE_TICKS);
+            let mut player = ReplayPlayer::new(replay);
+            player.restart(&mut sim, &map);
+            player.seek(&mut sim, &map, ticks);
+            if let Some(tick) = player.divergence {
+                eprintln!(
+                    "replay diverged at tick {tick}, the world is dumped in {DESYNCS_DIRECTORY}"
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+        None => {
+            sim.load_level(&map);
+            run(&mut sim, &options);
+        }
+    }
+    println!(
+        "{}: {} ticks ({:.1}s of game time) in {:.2?}",
+        map.level().identifier,
+        sim.tick,
+        sim.tick as f32 * TICK_DT,
+        started.elapsed()
+    );
+
+    for (entity, (transform, unit_type, owner)) in
+        sim.world.query::<(&Transform, &UnitType, &Owner)>().iter()
+    {
+        println!(
+            "  {:?} {} of player {} at ({:.2}, {:.2})",
+            entity, unit_type.0, owner.0, transform.position.x, transform.position.y
+        );
+    }
+    ExitCode::SUCCESS
+}
+
+fn run(sim: &mut Simulation, options: &Options) {
+    let mut commands = Vec::new();
+    if let Some(target) = options.target {
+        let units = sim
+            .world
+            .query::<(&Unit, &Owner)>()
+            .iter()
+            .filter(|(_, (_, owner))| owner.0 == 0)
+            .map(|(entity, _)| entity)
+            .collect();
+        commands.push(Command::Order {
+            units,
+            order: Order::Move(target),
+            queued: false,
+            player: None,
+        });
+    }
+
+    for _ in 0..options.ticks.unwrap_or(DEFAULT_TICKS) {
+        sim.step(&commands);
+        commands.clear();
+    }
+}
@@ -13,6 +13,12 @@ pub struct RtsCamera {
     pub height_range: Vec2,
 }
 
+impl Default for RtsCamera {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RtsCamera {
     pub fn new() -> Self {
         Self {
@@ -29,6 +35,16 @@ impl RtsCamera {
         }
     }
 
+    /// World space area on screen, grown by `margin` on every side. Uses
+    /// where the camera is now, not where it is panning to.
+    pub fn visible_bounds(&self, margin: f32) -> AABB {
+        let camera = main_camera();
+        AABB::from_center_size(
+            camera.center,
+            camera.world_viewport() + Vec2::splat(margin * 2.0),
+        )
+    }
+
     pub fn update(&mut self) {
         let vel = (self.speed / (1.0 / self.zoom)) * delta();
         if is_key_down(KeyCode::W) {
@@ -0,0 +1,402 @@
+use comfy::*;
+
+use super::{game_module::GameState, level_module::Map, pathfinding_module::NodeType};
+
+// strokes kept for undo
+const HISTORY_LIMIT: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditorTool {
+    Brush,
+    Rect,
+    Fill,
+}
+
+impl EditorTool {
+    pub fn next(self) -> Self {
+        match self {
+            EditorTool::Brush => EditorTool::Rect,
+            EditorTool::Rect => EditorTool::Fill,
+            EditorTool::Fill => EditorTool::Brush,
+        }
+    }
+}
+
+/// What the editor puts down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Paint {
+    /// The first tile tagged with the node type, so the change is kept when
+    /// the level is saved and the grid rebuilt from it.
+    Node(NodeType),
+    Tile(i64),
+    Erase,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct TileEdit {
+    cell: IVec2,
+    before: Option<i64>,
+    after: Option<i64>,
+}
+
+/// In-game editor for the level's Tiles layer. Edits go to the loaded LDtk
+/// data, the pathfinding grid is derived from it like on load, and `F5`
+/// writes the layer back to the `.ldtk` file.
+pub struct Editor {
+    pub enabled: bool,
+    pub tool: EditorTool,
+    pub paint: Paint,
+    undo: Vec<Vec<TileEdit>>,
+    redo: Vec<Vec<TileEdit>>,
+    // edits of the stroke in progress, one undo step once it ends
+    stroke: Vec<TileEdit>,
+    anchor: Option<IVec2>,
+    last: Option<IVec2>,
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            tool: EditorTool::Brush,
+            paint: Paint::Node(NodeType::Unwalkable),
+            undo: Vec::new(),
+            redo: Vec::new(),
+            stroke: Vec::new(),
+            anchor: None,
+            last: None,
+        }
+    }
+
+    /// Undo history refers to cells of one level, drop it when that changes.
+    pub fn clear_history(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.stroke.clear();
+        self.anchor = None;
+        self.last = None;
+    }
+
+    /// Short description for the HUD.
+    pub fn status(&self) -> String {
+        if self.enabled {
+            format!("{:?} {:?}", self.tool, self.paint)
+        } else {
+            "off".to_string()
+        }
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    /// Starts a stroke at `cell`. The brush paints right away, rectangle
+    /// and fill wait for `end_stroke`. Returns true if the map changed.
+    pub fn begin_stroke(&mut self, map: &mut Map, cell: IVec2) -> bool {
+        self.stroke.clear();
+        self.anchor = Some(cell);
+        self.last = Some(cell);
+        match self.tool {
+            EditorTool::Brush => self.paint_cell(map, cell),
+            EditorTool::Rect | EditorTool::Fill => false,
+        }
+    }
+
+    /// Moves a brush stroke to `cell`, painting every cell on the way so
+    /// fast mouse moves leave no gaps.
+    pub fn drag(&mut self, map: &mut Map, cell: IVec2) -> bool {
+        let Some(last) = self.last.filter(|_| self.tool == EditorTool::Brush) else {
+            return false;
+        };
+        self.last = Some(cell);
+
+        let steps = (cell - last).abs().max_element();
+        let mut changed = false;
+        for i in 1..=steps {
+            let t = i as f32 / steps as f32;
+            let on_line = last.as_vec2().lerp(cell.as_vec2(), t).round().as_ivec2();
+            changed |= self.paint_cell(map, on_line);
+        }
+        changed
+    }
+
+    /// Finishes the stroke: draws the rectangle from where it started to
+    /// `cell`, or flood fills from `cell`, and records one undo step.
+    pub fn end_stroke(&mut self, map: &mut Map, cell: IVec2) -> bool {
+        let Some(anchor) = self.anchor.take() else {
+            return false;
+        };
+        self.last = None;
+
+        let changed = match self.tool {
+            EditorTool::Brush => false,
+            EditorTool::Rect => {
+                let (min, max) = (anchor.min(cell), anchor.max(cell));
+                let mut changed = false;
+                for y in min.y..=max.y {
+                    for x in min.x..=max.x {
+                        changed |= self.paint_cell(map, ivec2(x, y));
+                    }
+                }
+                changed
+            }
+            EditorTool::Fill => self.fill(map, cell),
+        };
+
+        if !self.stroke.is_empty() {
+            self.undo.push(std::mem::take(&mut self.stroke));
+            if self.undo.len() > HISTORY_LIMIT {
+                self.undo.remove(0);
+            }
+            self.redo.clear();
+        }
+        changed
+    }
+
+    /// Corners of the rectangle being dragged, if any.
+    pub fn rect_preview(&self, cell: IVec2) -> Option<(IVec2, IVec2)> {
+        let anchor = self.anchor.filter(|_| self.tool == EditorTool::Rect)?;
+        Some((anchor.min(cell), anchor.max(cell)))
+    }
+
+    pub fn undo(&mut self, map: &mut Map) -> bool {
+        let Some(edits) = self.undo.pop() else {
+            return false;
+        };
+        for edit in edits.iter().rev() {
+            map.set_tile(edit.cell, edit.before);
+        }
+        self.redo.push(edits);
+        true
+    }
+
+    pub fn redo(&mut self, map: &mut Map) -> bool {
+        let Some(edits) = self.redo.pop() else {
+            return false;
+        };
+        for edit in &edits {
+            map.set_tile(edit.cell, edit.after);
+        }
+        self.undo.push(edits);
+        true
+    }
+
+    // tile id to put down, `None` erases; `Err` when no tile is tagged with
+    // the node type
+    fn tile(&self, map: &Map) -> Result<Option<i64>, NodeType> {
+        match self.paint {
+            Paint::Node(node_type) => map
+                .tile_with_tag(&format!("{node_type:?}"))
+                .map(Some)
+                .ok_or(node_type),
+            Paint::Tile(tile) => Ok(Some(tile)),
+            Paint::Erase => Ok(None),
+        }
+    }
+
+    fn paint_cell(&mut self, map: &mut Map, cell: IVec2) -> bool {
+        let after = match self.tile(map) {
+            Ok(after) => after,
+            Err(node_type) => {
+                warn!("No tile in the tileset is tagged {node_type:?}");
+                return false;
+            }
+        };
+        let before = map.tile_at(cell);
+        if before == after || !map.set_tile(cell, after) {
+            return false;
+        }
+        self.stroke.push(TileEdit {
+            cell,
+            before,
+            after,
+        });
+        true
+    }
+
+    // 4-connected flood fill of the cells holding the same tile as `cell`
+    fn fill(&mut self, map: &mut Map, cell: IVec2) -> bool {
+        let Some(size) = map.tile_layer_size() else {
+            return false;
+        };
+        let inside = |c: IVec2| c.x >= 0 && c.y >= 0 && c.x < size.x && c.y < size.y;
+        if !inside(cell) {
+            return false;
+        }
+
+        let target = map.tile_at(cell);
+        if self.tile(map) == Ok(target) {
+            return false;
+        }
+        let mut visited = HashSet::from([cell]);
+        let mut open = vec![cell];
+        let mut changed = false;
+        while let Some(current) = open.pop() {
+            changed |= self.paint_cell(map, current);
+            for next in [IVec2::X, IVec2::NEG_X, IVec2::Y, IVec2::NEG_Y].map(|d| current + d) {
+                if inside(next) && map.tile_at(next) == target && visited.insert(next) {
+                    open.push(next);
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Editor input, `E` toggles the editor. While it is on the left mouse
+/// button paints instead of selecting units.
+pub fn editor_system(state: &mut GameState) {
+    if is_key_pressed(KeyCode::E) {
+        state.editor.enabled = !state.editor.enabled;
+        state.editor.anchor = None;
+        state.editor.last = None;
+    }
+    if !state.editor.enabled {
+        return;
+    }
+
+    let editor = &mut state.editor;
+    let map = &mut state.level;
+    if is_key_pressed(KeyCode::Num1) {
+        editor.paint = Paint::Node(NodeType::Walkable);
+    }
+    if is_key_pressed(KeyCode::Num2) {
+        editor.paint = Paint::Node(NodeType::Unwalkable);
+    }
+    if is_key_pressed(KeyCode::Num3) {
+        let next = match editor.paint {
+            Paint::Tile(tile) => tile + 1,
+            _ => 0,
+        };
+        editor.paint = Paint::Tile(next % map.tile_count().max(1));
+    }
+    if is_key_pressed(KeyCode::Num4) {
+        editor.paint = Paint::Erase;
+    }
+    if is_key_pressed(KeyCode::T) {
+        editor.tool = editor.tool.next();
+    }
+
+    let cell = state.sim.board.world_to_grid(mouse_world());
+    let mut changed = false;
+    if is_key_pressed(KeyCode::Z) {
+        changed |= editor.undo(map);
+    }
+    if is_key_pressed(KeyCode::Y) {
+        changed |= editor.redo(map);
+    }
+    if is_mouse_button_pressed(MouseButton::Left) {
+        changed |= editor.begin_stroke(map, cell);
+    } else if is_mouse_button_down(MouseButton::Left) {
+        changed |= editor.drag(map, cell);
+    }
+    if is_mouse_button_released(MouseButton::Left) {
+        changed |= editor.end_stroke(map, cell);
+    }
+
+    if changed {
+        state.level.rebuild_tiles();
+        state.sim.update_board_from_level(&state.level);
+    }
+
+    if is_key_pressed(KeyCode::F5) {
+        match state.level.save_tiles() {
+            Ok(()) => info!("Saved {}", state.level.map.path),
+            Err(err) => error!("Failed to save {}: {err}", state.level.map.path),
+        }
+    }
+}
+
+pub fn draw_editor(state: &GameState) {
+    if !state.editor.enabled {
+        return;
+    }
+
+    let board = &state.sim.board;
+    let cell = board.world_to_grid(mouse_world());
+    let (min, max) = state.editor.rect_preview(cell).unwrap_or((cell, cell));
+    let (min, max) = (board.grid_to_world(min), board.grid_to_world(max));
+    draw_rect_outline(
+        (min + max) / 2.0,
+        max - min + Vec2::ONE,
+        0.1,
+        YELLOW,
+        super::PATHFINDING_Z_INDEX + 1,
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::level_module::RTS_LEVEL_LDTK;
+
+    fn level() -> Map {
+        Map::load(RTS_LEVEL_LDTK, "Level_0").unwrap()
+    }
+
+    #[test]
+    fn test_brush_strokes_undo_and_redo() {
+        let mut map = level();
+        let mut editor = Editor::new();
+        let wall = map.tile_with_tag("Unwalkable").unwrap();
+        let before = map.tile_at(ivec2(2, 2));
+
+        // one stroke dragged over a gap of several cells
+        assert!(editor.begin_stroke(&mut map, ivec2(2, 2)));
+        assert!(editor.drag(&mut map, ivec2(6, 2)));
+        editor.end_stroke(&mut map, ivec2(6, 2));
+        assert!((2..=6).all(|x| map.tile_at(ivec2(x, 2)) == Some(wall)));
+
+        editor.paint = Paint::Erase;
+        editor.begin_stroke(&mut map, ivec2(4, 2));
+        editor.end_stroke(&mut map, ivec2(4, 2));
+        assert_eq!(map.tile_at(ivec2(4, 2)), None);
+
+        assert!(editor.undo(&mut map));
+        assert_eq!(map.tile_at(ivec2(4, 2)), Some(wall));
+        assert!(editor.undo(&mut map));
+        assert_eq!(map.tile_at(ivec2(2, 2)), before);
+        assert!(!editor.can_undo());
+
+        assert!(editor.redo(&mut map));
+        assert_eq!(map.tile_at(ivec2(6, 2)), Some(wall));
+        assert!(editor.can_redo());
+    }
+
+    #[test]
+    fn test_rect_and_fill_tools() {
+        let mut map = level();
+        let mut editor = Editor::new();
+        let wall = map.tile_with_tag("Unwalkable").unwrap();
+
+        // an empty 3x3 pocket walled off from the rest of the map
+        editor.tool = EditorTool::Rect;
+        editor.begin_stroke(&mut map, ivec2(14, 14));
+        assert!(editor.end_stroke(&mut map, ivec2(10, 10)));
+        editor.paint = Paint::Erase;
+        editor.begin_stroke(&mut map, ivec2(11, 11));
+        editor.end_stroke(&mut map, ivec2(13, 13));
+        assert_eq!(map.tile_at(ivec2(12, 12)), None);
+
+        editor.tool = EditorTool::Fill;
+        editor.paint = Paint::Tile(0);
+        editor.begin_stroke(&mut map, ivec2(12, 12));
+        assert!(editor.end_stroke(&mut map, ivec2(12, 12)));
+        assert!((11..=13).all(|x| map.tile_at(ivec2(x, 11)) == Some(0)));
+        assert_eq!(map.tile_at(ivec2(10, 12)), Some(wall));
+
+        // each stroke is a single undo step
+        assert!(editor.undo(&mut map));
+        assert_eq!(map.tile_at(ivec2(11, 13)), None);
+    }
+}
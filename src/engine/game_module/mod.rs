@@ -1,49 +1,204 @@
 use comfy::*;
 
-use crate::engine::pathfinding_module::*;
-
-use super::{camera_module::RtsCamera, level_module::Map};
-
-#[derive(Debug, Clone, Copy)]
-pub enum PlaceMode {
-    Start,
-    End,
-    Wall,
-    Walkable,
-}
+use super::{
+    camera_module::RtsCamera,
+    editor_module::Editor,
+    level_module::{LevelError, Map, DEFAULT_LEVEL, RTS_LEVEL_LDTK},
+    net_module::{session_from_args, LockstepSession},
+    replay_module::{Replay, ReplayError, ReplayPlayer},
+    selection_module::SelectionBox,
+    simulation_module::{Command, FixedTimestep, Simulation},
+    unit_module::{teardown_move_points, UnitArchetypes, DEFAULT_UNIT_TYPE, UNIT_ARCHETYPES_PATH},
+};
 
+/// The comfy front end: the simulation plus what only matters with a window,
+/// like the camera, the selection box and the editor.
 pub struct GameState {
-    pub board: PathfindingTerrain,
-    pub start: Option<Node>,
-    pub end: Option<Node>,
-    pub place_mode: PlaceMode,
+    pub sim: Simulation,
+    pub timestep: FixedTimestep,
+    // input waiting for the next simulation tick
+    pending_commands: Vec<Command>,
     pub level: Map,
+    pub editor: Editor,
+    pub selection: SelectionBox,
     pub rts_camera: RtsCamera,
     pub draw_pathfinding: bool,
+    /// Dev mode: reload the level when its `.ldtk` file changes on disk.
+    pub hot_reload: bool,
+    /// Everything played on the current level so far.
+    pub replay: Replay,
+    /// Set when watching a replay instead of playing, see `--replay`.
+    pub playback: Option<ReplayPlayer>,
+    /// Set when playing with others, see `session_from_args`.
+    pub network: Option<LockstepSession>,
+    /// Player whose units the local input controls.
+    pub local_player: u32,
+    /// Unit type spawned under the cursor.
+    pub spawn_unit_type: String,
 }
 
 impl GameState {
     pub fn new(_c: &EngineState) -> Self {
+        let mut sim = Simulation::new();
+        match UnitArchetypes::load(UNIT_ARCHETYPES_PATH) {
+            Ok(archetypes) => sim.archetypes = archetypes,
+            Err(err) => error!("Using the built-in unit types: {err}"),
+        }
+        let mut playback =
+            replay_argument().and_then(|path| match Replay::load(&path, &sim.archetypes) {
+                Ok(replay) => Some(ReplayPlayer::new(replay)),
+                Err(err) => {
+                    error!("Failed to load the replay: {err}");
+                    None
+                }
+            });
+        let level_name = playback
+            .as_ref()
+            .map_or(DEFAULT_LEVEL, |player| player.replay.level.as_str());
+        let mut level = Map::load_or_first(RTS_LEVEL_LDTK, level_name).unwrap_or_else(|err| {
+            error!("Failed to load level {level_name}: {err}");
+            std::process::exit(1);
+        });
+        if playback
+            .as_ref()
+            .is_some_and(|player| player.replay.level != level.level().identifier)
+        {
+            error!("The level of the replay is missing, not playing it back");
+            playback = None;
+        }
+        let args = std::env::args().collect::<Vec<_>>();
+        let network = session_from_args(&args, &sim.archetypes).unwrap_or_else(|err| {
+            error!("Playing offline: {err}");
+            None
+        });
+        // edits to the level aren't sent to the other players
+        let hot_reload = cfg!(debug_assertions) && network.is_none();
+        if hot_reload {
+            if let Err(err) = level.watch() {
+                error!("Hot reload disabled: {err}");
+            }
+        }
+
         Self {
-            board: PathfindingTerrain::new(47, 36, None),
-            start: None,
-            end: None,
-            place_mode: PlaceMode::Wall,
-            level: Map::new(),
+            replay: Replay::new(&level.level().identifier, sim.config(), &sim.archetypes),
+            playback,
+            local_player: network.as_ref().map_or(0, |session| session.local_player),
+            network,
+            sim,
+            timestep: FixedTimestep::new(),
+            pending_commands: Vec::new(),
+            level,
+            editor: Editor::new(),
+            selection: SelectionBox::new(),
             rts_camera: RtsCamera::new(),
             draw_pathfinding: false,
+            hot_reload,
+            spawn_unit_type: DEFAULT_UNIT_TYPE.to_string(),
+        }
+    }
+
+    /// Replaces the current level: units and pending paths are dropped and
+    /// the pathfinding grid is rebuilt. The current level is kept on error.
+    pub fn switch_level(&mut self, level: &str) -> Result<(), LevelError> {
+        self.level.set_level(level)?;
+
+        self.sim.clear();
+        self.pending_commands.clear();
+        teardown_move_points();
+        self.editor.clear_history();
+
+        self.start_level();
+        Ok(())
+    }
+
+    /// Queues `commands` for the next tick and runs the ticks that became
+    /// due during a frame of `frame_dt` seconds, if any.
+    ///
+    /// While watching a replay the commands come from it instead and
+    /// `commands` is ignored. Online, a tick only runs once the commands of
+    /// every player for it arrived.
+    pub fn advance(&mut self, commands: Vec<Command>, frame_dt: f32) {
+        if let Some(player) = &mut self.playback {
+            if player.paused {
+                return;
+            }
+            for _ in 0..self.timestep.advance(frame_dt * player.speed) {
+                player.step(&mut self.sim);
+            }
+            return;
+        }
+
+        self.pending_commands.extend(commands);
+        for _ in 0..self.timestep.advance(frame_dt) {
+            let commands = match &mut self.network {
+                Some(session) => {
+                    match session.exchange(self.sim.tick, &mut self.pending_commands) {
+                        Some(commands) => commands,
+                        // waiting for the others, the time is lost
+                        None => break,
+                    }
+                }
+                None => std::mem::take(&mut self.pending_commands),
+            };
+            self.replay.record(self.sim.tick, &commands);
+            self.sim.step(&commands);
+
+            let snapshot = self.sim.snapshot();
+            self.replay
+                .record_checksum(snapshot.tick, snapshot.checksum);
+            if let Some(session) = &mut self.network {
+                session.record(snapshot, &self.sim);
+            }
+        }
+    }
+
+    /// Jumps to `tick` of the replay being watched.
+    pub fn seek(&mut self, tick: u64) {
+        if let Some(player) = &mut self.playback {
+            player.seek(&mut self.sim, &self.level, tick);
         }
     }
 
-    pub fn set_start(&mut self, node: Node) {
-        self.start = Some(node);
+    /// Writes what was played on the current level to the replays
+    /// directory and returns the file.
+    pub fn save_replay(&self) -> Result<String, ReplayError> {
+        self.replay.save_new()
     }
 
-    pub fn set_end(&mut self, node: Node) {
-        self.end = Some(node);
+    /// Loads the current level into the simulation and centres the camera
+    /// on it.
+    pub fn start_level(&mut self) {
+        match &mut self.playback {
+            Some(player) => player.restart(&mut self.sim, &self.level),
+            None => self.sim.load_level(&self.level),
+        }
+        self.replay = Replay::new(
+            &self.level.level().identifier,
+            self.sim.config(),
+            &self.sim.archetypes,
+        );
+        let board = &self.sim.board;
+        self.rts_camera.pos = board.grid_to_world(ivec2(board.width / 2, board.height / 2));
     }
 
-    pub fn set_board_based_on_tilemap(&mut self) {
-        self.board.set_pathfinding_based_on_tilemap(&self.level.map);
+    /// Picks up edits to the level file. The editor's undo history is kept,
+    /// it only holds tile ids per cell.
+    pub fn reload_level_if_changed(&mut self) {
+        match self.level.reload_if_changed() {
+            None => return,
+            Some(Err(err)) => {
+                error!("Failed to reload {}: {err}", self.level.map.path);
+                return;
+            }
+            Some(Ok(())) => info!("Reloaded {}", self.level.map.path),
+        }
+        self.sim.update_board_from_level(&self.level);
     }
 }
+
+// `--replay <file>` on the command line
+fn replay_argument() -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != "--replay");
+    args.next()?;
+    args.next()
+}
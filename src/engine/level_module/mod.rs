@@ -1,28 +1,242 @@
-use comfy::{
-    wgpu::{Extent3d, TextureFormat},
-    wgpu_types::TextureDescriptor,
-    *,
-};
+use std::{fmt, path::Path};
+
+use comfy::{notify::Watcher, *};
+
+mod tile_layer;
+mod tilemap;
+
+pub use tilemap::*;
 
-use super::TILEMAP_Z_INDEX;
+use super::{
+    pathfinding_module::{NodeType, PathfindingTerrain},
+    unit_module::{owner_color, spawn_unit, Health, Owner, UnitArchetypes, DEFAULT_UNIT_TYPE},
+    TILEMAP_Z_INDEX, UNIT_Z_INDEX,
+};
 
 pub const RTS_LEVEL_LDTK: &str = "assets/levels/rts.ldtk";
+pub const DEFAULT_LEVEL: &str = "Level_1";
 pub const RTS_GRID_SIZE: f32 = 64.0;
+pub const ENTITIES_LAYER_TYPE: &str = "Entities";
+pub const TILES_LAYER_TYPE: &str = "Tiles";
+pub const DEFAULT_BUILDING_HEALTH: i32 = 500;
+
+#[derive(Debug)]
+pub enum LevelError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// No level has this identifier or iid.
+    LevelNotFound(String),
+    /// The level has no layer instances, e.g. it is saved in a separate file.
+    MissingLayers(String),
+    /// The layer instance with this iid is not in the file being saved.
+    LayerNotFound(String),
+    Watch(notify::Error),
+}
+
+impl fmt::Display for LevelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LevelError::Io { path, source } => write!(f, "failed to read {path}: {source}"),
+            LevelError::Parse { path, source } => write!(f, "failed to parse {path}: {source}"),
+            LevelError::LevelNotFound(level) => write!(f, "no level named {level:?}"),
+            LevelError::MissingLayers(level) => write!(f, "level {level:?} has no layers"),
+            LevelError::LayerNotFound(iid) => write!(f, "no layer with iid {iid:?}"),
+            LevelError::Watch(source) => write!(f, "failed to watch for changes: {source}"),
+        }
+    }
+}
+
+impl std::error::Error for LevelError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LevelError::Io { source, .. } => Some(source),
+            LevelError::Parse { source, .. } => Some(source),
+            LevelError::Watch(source) => Some(source),
+            _ => None,
+        }
+    }
+}
 
+/// Gameplay object placed in an LDtk Entities layer. Positions are world
+/// space centres.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LevelEntity {
+    Unit {
+        position: Vec2,
+        owner: u32,
+        unit_type: String,
+        /// Overrides the health of the unit type.
+        health: Option<i32>,
+        /// Degrees, counter-clockwise from +x.
+        facing: f32,
+    },
+    Building {
+        position: Vec2,
+        /// In tiles.
+        size: Vec2,
+        owner: u32,
+        building_type: String,
+        health: i32,
+    },
+    Resource {
+        position: Vec2,
+        resource_type: String,
+        amount: u32,
+    },
+}
+
+#[derive(Debug)]
+pub struct Building {
+    pub building_type: String,
+    pub size: Vec2,
+    /// Grid nodes under the footprint, given back to the terrain when the
+    /// building is destroyed.
+    pub covered: Vec<IVec2>,
+}
+
+#[derive(Debug)]
+pub struct Resource {
+    pub resource_type: String,
+    pub amount: u32,
+}
+
+/// An LDtk world and the level of it currently being played.
 pub struct Map {
     pub map: LdtkWorldMap,
+    level: usize,
+    tiles: TilemapBatch,
 }
 
 impl Map {
-    pub fn new() -> Self {
+    /// Reads the `.ldtk` file at `path` and selects the level with the given
+    /// identifier or iid.
+    pub fn load(path: &str, level: &str) -> Result<Self, LevelError> {
+        let json = read_project(path)?;
+        let level = find_level(&json, level)?;
+        Ok(Self::with_level(json, path, level))
+    }
+
+    /// Like `load`, but selects the first level of the project that has
+    /// layers when `level` can't be played. Fails only when the project
+    /// itself can't be read or has no such level.
+    pub fn load_or_first(path: &str, level: &str) -> Result<Self, LevelError> {
+        let json = read_project(path)?;
+        let level = match find_level(&json, level) {
+            Ok(level) => level,
+            Err(err) => {
+                let first = json
+                    .levels
+                    .iter()
+                    .position(|level| level.layer_instances.is_some())
+                    .ok_or(err)?;
+                error!(
+                    "Failed to load level {level}, playing {} instead",
+                    json.levels[first].identifier
+                );
+                first
+            }
+        };
+        Ok(Self::with_level(json, path, level))
+    }
+
+    fn with_level(json: LdtkJson, path: &str, level: usize) -> Self {
+        let tiles = build_tiles(&json, &json.levels[level]);
         Self {
-            map: LdtkWorldMap::new(
-                parse_ldtk_map(include_str!("../../../assets/levels/rts.ldtk")).unwrap(),
-                RTS_LEVEL_LDTK,
-            ),
+            map: LdtkWorldMap::new(json, path),
+            level,
+            tiles,
         }
     }
 
+    pub fn level(&self) -> &Level {
+        &self.map.json.levels[self.level]
+    }
+
+    /// Switches to the level with the given identifier or iid.
+    pub fn set_level(&mut self, level: &str) -> Result<(), LevelError> {
+        self.level = find_level(&self.map.json, level)?;
+        self.tiles = build_tiles(&self.map.json, self.level());
+        Ok(())
+    }
+
+    /// Dev mode: also watch the directory of the project file, so editors
+    /// that save by replacing the file are picked up by `reload_if_changed`.
+    pub fn watch(&mut self) -> Result<(), LevelError> {
+        let directory = Path::new(&self.map.path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        self.map
+            .watcher
+            .watch(directory, notify::RecursiveMode::NonRecursive)
+            .map_err(LevelError::Watch)
+    }
+
+    /// Re-reads the project if it changed on disk since the last call and
+    /// selects the same level again. Returns `None` when nothing changed; on
+    /// error the previous version stays loaded.
+    pub fn reload_if_changed(&mut self) -> Option<Result<(), LevelError>> {
+        let file_name = Path::new(&self.map.path).file_name()?.to_owned();
+        let mut changed = false;
+        while let Ok(event) = self.map.recv.try_recv() {
+            let Ok(event) = event else {
+                continue;
+            };
+            changed |= (event.kind.is_modify() || event.kind.is_create())
+                && event
+                    .paths
+                    .iter()
+                    .any(|path| path.file_name() == Some(file_name.as_os_str()));
+        }
+        if !changed {
+            return None;
+        }
+
+        let identifier = self.level().identifier.clone();
+        Some(read_project(&self.map.path).and_then(|json| {
+            self.level = find_level(&json, &identifier)?;
+            self.map.json = json;
+            self.tiles = build_tiles(&self.map.json, self.level());
+            Ok(())
+        }))
+    }
+
+    /// Identifier of the level after the current one, wrapping around.
+    pub fn next_level(&self) -> &str {
+        let levels = &self.map.json.levels;
+        &levels[(self.level + 1) % levels.len()].identifier
+    }
+
+    /// Objects placed in the current level's Entities layers, `None` if the
+    /// level has no Entities layer.
+    pub fn entities(&self) -> Option<Vec<LevelEntity>> {
+        let level = self.level();
+        let mut layers = level
+            .layer_instances
+            .iter()
+            .flatten()
+            .filter(|layer| layer.layer_instance_type == ENTITIES_LAYER_TYPE)
+            .peekable();
+        layers.peek()?;
+
+        Some(
+            layers
+                .flat_map(|layer| {
+                    layer
+                        .entity_instances
+                        .iter()
+                        .filter_map(move |entity| level_entity(level, layer, entity))
+                })
+                .collect(),
+        )
+    }
+
     pub fn initialize(&mut self, c: &mut EngineContext) {
         c.load_texture_from_bytes(
             "tileset",
@@ -33,47 +247,378 @@ impl Map {
         )
     }
 
-    pub fn draw(&self) {
-        let map_json = &self.map.json;
-        let level = &map_json.levels[1];
-        for (i, layer) in level
-            .layer_instances
-            .as_ref()
-            .unwrap()
+    pub fn tiles(&self) -> &TilemapBatch {
+        &self.tiles
+    }
+
+    /// Draws the tile chunks overlapping `view`, see
+    /// `RtsCamera::visible_bounds`.
+    pub fn draw(&self, view: AABB) {
+        self.tiles.draw(texture_id("tileset"), view);
+    }
+}
+
+/// Batches the tile layers of a level, bottom layer lowest.
+fn build_tiles(map_json: &LdtkJson, level: &Level) -> TilemapBatch {
+    let mut batch = TilemapBatch::new();
+    for (i, layer) in level.layer_instances.iter().flatten().rev().enumerate() {
+        let tileset = layer
+            .tileset_def_uid
+            .and_then(|uid| map_json.defs.tilesets.iter().find(|t| t.uid == uid));
+        let Some(tileset) = tileset else {
+            continue;
+        };
+
+        let grid_size = layer.grid_size as f32;
+        let origin = level_origin(level, layer.grid_size);
+        let tiles = layer
+            .grid_tiles
             .iter()
-            .rev()
-            .enumerate()
-        {
-            let grid_size = layer.grid_size as f32;
-
-            let tileset = layer
-                .tileset_def_uid
-                .and_then(|uid| map_json.defs.tilesets.iter().find(|t| t.uid == uid));
-
-            if let Some(_tileset) = tileset {
-                let texture = texture_id("tileset");
-
-                for tile in layer.grid_tiles.iter() {
-                    let pos = tile.to_world(layer);
-
-                    draw_sprite_ex(
-                        texture,
-                        pos,
-                        WHITE,
-                        TILEMAP_Z_INDEX + i as i32,
-                        DrawTextureParams {
-                            dest_size: Some(splat(grid_size / RTS_GRID_SIZE).as_world_size()),
-                            source_rect: Some(IRect::new(
-                                ivec2(tile.src[0] as i32, tile.src[1] as i32),
-                                ivec2(grid_size as i32, grid_size as i32),
-                            )),
-                            flip_x: tile.f == 1 || tile.f == 3,
-                            flip_y: tile.f == 2 || tile.f == 3,
-                            ..Default::default()
-                        },
-                    );
+            .chain(layer.auto_layer_tiles.iter())
+            .map(|tile| TileQuad {
+                position: tile.to_world(layer) + origin,
+                size: grid_size / RTS_GRID_SIZE,
+                source: IRect::new(
+                    ivec2(tile.src[0] as i32, tile.src[1] as i32),
+                    ivec2(grid_size as i32, grid_size as i32),
+                ),
+                flip_x: tile.f == 1 || tile.f == 3,
+                flip_y: tile.f == 2 || tile.f == 3,
+            });
+        batch.add_layer(
+            TILEMAP_Z_INDEX + i as i32,
+            vec2(tileset.px_wid as f32, tileset.px_hei as f32),
+            tiles,
+        );
+    }
+    batch
+}
+
+/// World position of the level's bottom-left tile centre, in tiles of
+/// `grid_size` pixels. LDtk's y axis points down, the world's up, so the
+/// bottom row of a level sits `px_hei` below its `world_y`.
+pub fn level_origin(level: &Level, grid_size: i64) -> Vec2 {
+    let grid_size = grid_size as f32;
+    vec2(
+        level.world_x as f32 / grid_size,
+        -(level.world_y + level.px_hei) as f32 / grid_size + 0.5,
+    )
+}
+
+fn level_entity(
+    level: &Level,
+    layer: &LayerInstance,
+    entity: &EntityInstance,
+) -> Option<LevelEntity> {
+    let grid_size = layer.grid_size as f32;
+    let size_px = vec2(entity.width as f32, entity.height as f32);
+    let pivot = vec2(entity.pivot[0] as f32, entity.pivot[1] as f32);
+    let centre_px = vec2(entity.px[0] as f32, entity.px[1] as f32) + (0.5 - pivot) * size_px;
+    let position = level_origin(level, layer.grid_size)
+        + vec2(
+            centre_px.x / grid_size - 0.5,
+            layer.c_hei as f32 - centre_px.y / grid_size - 0.5,
+        );
+
+    let owner = int_field(entity, "Owner").unwrap_or(0) as u32;
+    let health = int_field(entity, "Health").map(|health| health as i32);
+
+    match entity.identifier.as_str() {
+        "Unit" => Some(LevelEntity::Unit {
+            position,
+            owner,
+            unit_type: entity
+                .str_field("UnitType")
+                .unwrap_or(DEFAULT_UNIT_TYPE)
+                .to_string(),
+            health,
+            facing: float_field(entity, "Facing").unwrap_or(0.0),
+        }),
+        "Building" => Some(LevelEntity::Building {
+            position,
+            size: size_px / grid_size,
+            owner,
+            building_type: entity
+                .str_field("BuildingType")
+                .unwrap_or("Headquarters")
+                .to_string(),
+            health: health.unwrap_or(DEFAULT_BUILDING_HEALTH),
+        }),
+        "Resource" => Some(LevelEntity::Resource {
+            position,
+            resource_type: entity
+                .str_field("ResourceType")
+                .unwrap_or("Gold")
+                .to_string(),
+            amount: int_field(entity, "Amount").unwrap_or(0) as u32,
+        }),
+        other => {
+            warn!("Ignoring unknown LDtk entity {other}");
+            None
+        }
+    }
+}
+
+fn field<'a>(entity: &'a EntityInstance, name: &str) -> Option<&'a serde_json::Value> {
+    entity
+        .field_instances
+        .iter()
+        .find(|field| field.identifier == name)
+        .and_then(|field| field.value.as_ref())
+}
+
+fn int_field(entity: &EntityInstance, name: &str) -> Option<i64> {
+    field(entity, name)?.as_i64()
+}
+
+fn float_field(entity: &EntityInstance, name: &str) -> Option<f32> {
+    field(entity, name)?.as_f64().map(|value| value as f32)
+}
+
+/// Spawns the ECS entities for a level. Buildings block the nodes under
+/// their footprint.
+pub fn spawn_level_entities(
+    world: &mut World,
+    entities: &[LevelEntity],
+    archetypes: &UnitArchetypes,
+    board: &mut PathfindingTerrain,
+) {
+    for entity in entities {
+        match entity {
+            LevelEntity::Unit {
+                position,
+                owner,
+                unit_type,
+                health,
+                facing,
+            } => match archetypes.get(unit_type) {
+                Some(archetype) => {
+                    spawn_unit(world, archetype, *position, *owner, *health, *facing);
                 }
+                None => warn!("Ignoring a unit of unknown type {unit_type}"),
+            },
+            LevelEntity::Building {
+                position,
+                size,
+                owner,
+                building_type,
+                health,
+            } => {
+                let covered = block_footprint(board, *position, *size);
+                world.spawn((
+                    Transform::position(*position),
+                    Building {
+                        building_type: building_type.clone(),
+                        size: *size,
+                        covered,
+                    },
+                    Owner(*owner),
+                    Health::new(*health),
+                ));
+            }
+            LevelEntity::Resource {
+                position,
+                resource_type,
+                amount,
+            } => {
+                world.spawn((
+                    Transform::position(*position),
+                    Resource {
+                        resource_type: resource_type.clone(),
+                        amount: *amount,
+                    },
+                ));
+            }
+        }
+    }
+}
+
+/// Marks the nodes under a building unwalkable.
+pub fn block_footprint(board: &mut PathfindingTerrain, position: Vec2, size: Vec2) -> Vec<IVec2> {
+    let min = board.world_to_grid(position - size / 2.0 + 0.5);
+    let max = board.world_to_grid(position + size / 2.0 - 0.5);
+    let mut covered = Vec::new();
+    for y in min.y..=max.y {
+        for x in min.x..=max.x {
+            if board.get_node_by_position(x, y).is_some() {
+                covered.push(ivec2(x, y));
+                board.set_node_type(x, y, NodeType::Unwalkable);
+            }
+        }
+    }
+    covered
+}
+
+/// Gives the nodes under a destroyed building back what `terrain`, the grid
+/// of the level without buildings, has for them. Footprints of other
+/// buildings overlapping it have to be blocked again afterwards.
+pub fn unblock_footprint(
+    board: &mut PathfindingTerrain,
+    terrain: &PathfindingTerrain,
+    building: &Building,
+) {
+    for node in &building.covered {
+        let Some(level_node) = terrain.get_node_by_position(node.x, node.y) else {
+            continue;
+        };
+        if let Some(node_type) = level_node.node_type {
+            board.set_node_type(node.x, node.y, node_type);
+        }
+        board.set_node_cost(node.x, node.y, level_node.cost);
+    }
+}
+
+/// Blocks the footprints of every spawned building again, e.g. on a grid
+/// rebuilt from the level data.
+pub fn block_building_footprints(world: &World, board: &mut PathfindingTerrain) {
+    for (_, (transform, building)) in world.query::<(&Transform, &mut Building)>().iter() {
+        building.covered = block_footprint(board, transform.position, building.size);
+    }
+}
+
+pub fn draw_level_entities(world: &World) {
+    for (_, (transform, building, owner)) in world.query::<(&Transform, &Building, &Owner)>().iter()
+    {
+        draw_rect(
+            transform.position,
+            building.size,
+            owner_color(owner.0).alpha(0.6),
+            UNIT_Z_INDEX - 1,
+        );
+    }
+    for (_, (transform, _)) in world.query::<(&Transform, &Resource)>().iter() {
+        draw_circle(transform.position, 0.4, GOLD, UNIT_Z_INDEX - 1);
+    }
+}
+
+fn read_project(path: &str) -> Result<LdtkJson, LevelError> {
+    let source = std::fs::read_to_string(path).map_err(|source| LevelError::Io {
+        path: path.to_string(),
+        source,
+    })?;
+    parse_ldtk_map(&source).map_err(|source| LevelError::Parse {
+        path: path.to_string(),
+        source,
+    })
+}
+
+fn find_level(json: &LdtkJson, level: &str) -> Result<usize, LevelError> {
+    let index = json
+        .levels
+        .iter()
+        .position(|l| l.identifier == level || l.iid == level)
+        .ok_or_else(|| LevelError::LevelNotFound(level.to_string()))?;
+    if json.levels[index].layer_instances.is_none() {
+        return Err(LevelError::MissingLayers(level.to_string()));
+    }
+    Ok(index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_level_by_identifier_or_iid() {
+        let mut map = Map::load(RTS_LEVEL_LDTK, "Level_0").unwrap();
+        assert_eq!(map.level().identifier, "Level_0");
+        assert_eq!(map.next_level(), "Level_1");
+
+        let iid = map.map.json.levels[1].iid.clone();
+        map.set_level(&iid).unwrap();
+        assert_eq!(map.level().identifier, "Level_1");
+        assert_eq!(map.next_level(), "Level_0");
+    }
+
+    #[test]
+    fn test_entities_from_level() {
+        let map = Map::load(RTS_LEVEL_LDTK, "Level_1").unwrap();
+        let entities = map.entities().unwrap();
+        assert_eq!(entities.len(), 9);
+
+        // Level_1 starts 47 tiles to the right of Level_0
+        assert_eq!(
+            entities[0],
+            LevelEntity::Unit {
+                position: vec2(57.0, -32.5),
+                owner: 0,
+                unit_type: "Worker".to_string(),
+                health: Some(60),
+                facing: 0.0,
             }
+        );
+        assert!(entities.contains(&LevelEntity::Building {
+            position: vec2(75.5, -34.0),
+            size: vec2(2.0, 2.0),
+            owner: 0,
+            building_type: "Headquarters".to_string(),
+            health: 500,
+        }));
+    }
+
+    #[test]
+    fn test_stacked_levels_of_different_heights() {
+        let map = Map::load(RTS_LEVEL_LDTK, "Level_0").unwrap();
+        let mut top = map.level().clone();
+        top.world_y = 0;
+        top.px_hei = 36 * 64;
+        let mut bottom = top.clone();
+        bottom.world_y = top.px_hei;
+        bottom.px_hei = 20 * 64;
+
+        // the top row of the lower level is right under the bottom row of
+        // the upper one
+        let top_origin = level_origin(&top, 64);
+        let bottom_origin = level_origin(&bottom, 64);
+        assert_eq!(top_origin.y, -35.5);
+        assert_eq!(bottom_origin.y + 19.0, top_origin.y - 1.0);
+        assert_eq!(bottom_origin.x, top_origin.x);
+    }
+
+    #[test]
+    fn test_reload_after_the_file_changes() {
+        let directory = std::env::temp_dir().join(format!("rts-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("rts.ldtk");
+        std::fs::copy(RTS_LEVEL_LDTK, &path).unwrap();
+
+        let mut map = Map::load(path.to_str().unwrap(), "Level_0").unwrap();
+        map.watch().unwrap();
+        assert!(map.reload_if_changed().is_none());
+
+        let source = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, source.replace("\"pxWid\": 3008", "\"pxWid\": 2944")).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        // a half written file may fail to parse first
+        let mut reloaded = false;
+        while !reloaded && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+            reloaded = matches!(map.reload_if_changed(), Some(Ok(())));
         }
+        assert!(reloaded);
+        assert_eq!(map.level().identifier, "Level_0");
+        assert_eq!(map.level().px_wid, 2944);
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert!(matches!(
+            Map::load(RTS_LEVEL_LDTK, "Level_42"),
+            Err(LevelError::LevelNotFound(_))
+        ));
+        assert!(matches!(
+            Map::load("assets/levels/missing.ldtk", DEFAULT_LEVEL),
+            Err(LevelError::Io { .. })
+        ));
+
+        let map = Map::load_or_first(RTS_LEVEL_LDTK, "Level_42").unwrap();
+        assert_eq!(map.level().identifier, "Level_0");
+        assert!(matches!(
+            Map::load_or_first("assets/levels/missing.ldtk", DEFAULT_LEVEL),
+            Err(LevelError::Io { .. })
+        ));
     }
 }
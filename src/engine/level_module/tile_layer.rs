@@ -0,0 +1,259 @@
+use comfy::*;
+use serde::Serialize;
+
+use super::{build_tiles, LevelError, Map, TILES_LAYER_TYPE};
+
+impl Map {
+    // the top Tiles layer of the current level, the one the editor paints on
+    fn tile_layer(&self) -> Option<(&LayerInstance, &TilesetDefinition)> {
+        self.level()
+            .layer_instances
+            .iter()
+            .flatten()
+            .filter(|layer| layer.layer_instance_type == TILES_LAYER_TYPE)
+            .find_map(|layer| Some((layer, self.tileset(layer.tileset_def_uid?)?)))
+    }
+
+    fn tileset(&self, uid: i64) -> Option<&TilesetDefinition> {
+        self.map.json.defs.tilesets.iter().find(|t| t.uid == uid)
+    }
+
+    /// Size in cells of the layer `tile_at` and `set_tile` work on.
+    pub fn tile_layer_size(&self) -> Option<IVec2> {
+        let (layer, _) = self.tile_layer()?;
+        Some(ivec2(layer.c_wid as i32, layer.c_hei as i32))
+    }
+
+    /// Tile id at a cell of the level's top Tiles layer, in the same
+    /// coordinates as the pathfinding grid (y up). `None` for empty cells.
+    pub fn tile_at(&self, cell: IVec2) -> Option<i64> {
+        let (layer, _) = self.tile_layer()?;
+        let px = cell_px(layer, cell)?;
+        // LDtk draws stacked tiles in order, the last one ends up on top
+        layer
+            .grid_tiles
+            .iter()
+            .rev()
+            .find(|tile| tile.px == px)
+            .map(|tile| tile.t)
+    }
+
+    /// Replaces the tiles at `cell` with `tile`, `None` erases them. Returns
+    /// false if the level has no Tiles layer or the cell is outside it.
+    ///
+    /// The tile batches are not rebuilt, call `rebuild_tiles` once done.
+    pub fn set_tile(&mut self, cell: IVec2, tile: Option<i64>) -> bool {
+        let Some((layer, tileset)) = self.tile_layer() else {
+            return false;
+        };
+        let Some(px) = cell_px(layer, cell) else {
+            return false;
+        };
+        let (layer_iid, tileset) = (layer.iid.clone(), tileset.clone());
+        let coord_id = px[0] / layer.grid_size + px[1] / layer.grid_size * layer.c_wid;
+
+        let level = self.level;
+        let Some(layer) = self.map.json.levels[level]
+            .layer_instances
+            .iter_mut()
+            .flatten()
+            .find(|layer| layer.iid == layer_iid)
+        else {
+            return false;
+        };
+        layer.grid_tiles.retain(|t| t.px != px);
+        if let Some(t) = tile {
+            layer.grid_tiles.push(TileInstance {
+                a: 1.0,
+                d: vec![coord_id],
+                f: 0,
+                px: px.to_vec(),
+                src: tile_src(&tileset, t),
+                t,
+            });
+        }
+        true
+    }
+
+    /// Number of tiles in the tileset of the Tiles layer.
+    pub fn tile_count(&self) -> i64 {
+        self.tile_layer()
+            .map(|(_, tileset)| tileset.c_wid * tileset.c_hei)
+            .unwrap_or(0)
+    }
+
+    /// First tile carrying the enum tag `tag`, e.g. "Unwalkable".
+    pub fn tile_with_tag(&self, tag: &str) -> Option<i64> {
+        let (_, tileset) = self.tile_layer()?;
+        tileset
+            .enum_tags
+            .iter()
+            .find(|t| t.enum_value_id == tag)?
+            .tile_ids
+            .first()
+            .copied()
+    }
+
+    /// Rebuilds the tile batches after edits.
+    pub fn rebuild_tiles(&mut self) {
+        self.tiles = build_tiles(&self.map.json, self.level());
+    }
+
+    /// Writes the Tiles layer of the current level back to the `.ldtk` file.
+    /// Only that layer's `gridTiles` array is replaced, the rest of the
+    /// project is kept as it is on disk, in LDtk's key order.
+    pub fn save_tiles(&self) -> Result<(), LevelError> {
+        let Some((layer, _)) = self.tile_layer() else {
+            return Err(LevelError::MissingLayers(self.level().identifier.clone()));
+        };
+        let path = &self.map.path;
+        let io_error = |source| LevelError::Io {
+            path: path.clone(),
+            source,
+        };
+        let parse_error = |source| LevelError::Parse {
+            path: path.clone(),
+            source,
+        };
+
+        let source = std::fs::read_to_string(path).map_err(io_error)?;
+        let mut project: serde_json::Value = serde_json::from_str(&source).map_err(parse_error)?;
+        let grid_tiles = grid_tiles_mut(&mut project, &self.level().iid, &layer.iid)
+            .ok_or_else(|| LevelError::LayerNotFound(layer.iid.clone()))?;
+        *grid_tiles = layer.grid_tiles.iter().map(tile_json).collect();
+
+        // indented with tabs like LDtk does
+        let mut output = Vec::new();
+        let formatter = serde_json::ser::PrettyFormatter::with_indent(b"\t");
+        let mut serializer = serde_json::Serializer::with_formatter(&mut output, formatter);
+        project.serialize(&mut serializer).map_err(parse_error)?;
+        std::fs::write(path, output).map_err(io_error)
+    }
+}
+
+fn cell_px(layer: &LayerInstance, cell: IVec2) -> Option<[i64; 2]> {
+    let (x, y) = (cell.x as i64, cell.y as i64);
+    if x < 0 || y < 0 || x >= layer.c_wid || y >= layer.c_hei {
+        return None;
+    }
+    Some([x * layer.grid_size, (layer.c_hei - 1 - y) * layer.grid_size])
+}
+
+fn tile_src(tileset: &TilesetDefinition, tile: i64) -> Vec<i64> {
+    let step = tileset.tile_grid_size + tileset.spacing;
+    vec![
+        tileset.padding + tile % tileset.c_wid * step,
+        tileset.padding + tile / tileset.c_wid * step,
+    ]
+}
+
+// A tile the way LDtk writes it, keys in its order and whole alphas
+// without a fraction.
+fn tile_json(tile: &TileInstance) -> serde_json::Value {
+    let alpha = if tile.a.fract() == 0.0 {
+        serde_json::json!(tile.a as i64)
+    } else {
+        serde_json::json!(tile.a)
+    };
+    serde_json::json!({
+        "px": tile.px,
+        "src": tile.src,
+        "f": tile.f,
+        "t": tile.t,
+        "d": tile.d,
+        "a": alpha,
+    })
+}
+
+// The `gridTiles` array of the layer instance `layer_iid` in the level
+// `level_iid` of a parsed project.
+fn grid_tiles_mut<'a>(
+    project: &'a mut serde_json::Value,
+    level_iid: &str,
+    layer_iid: &str,
+) -> Option<&'a mut serde_json::Value> {
+    project["levels"]
+        .as_array_mut()?
+        .iter_mut()
+        .find(|level| level["iid"] == level_iid)?["layerInstances"]
+        .as_array_mut()?
+        .iter_mut()
+        .find(|layer| layer["iid"] == layer_iid)?
+        .get_mut("gridTiles")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn test_edit_and_save_tiles() {
+        let directory = std::env::temp_dir().join(format!("rts-tiles-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("rts.ldtk");
+        std::fs::copy(RTS_LEVEL_LDTK, &path).unwrap();
+        let path = path.to_str().unwrap();
+
+        let project = |path: &str| -> serde_json::Value {
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+        };
+
+        let mut map = Map::load(path, "Level_0").unwrap();
+        // saving without edits keeps the project as it was
+        map.save_tiles().unwrap();
+        assert_eq!(project(path), project(RTS_LEVEL_LDTK));
+
+        let wall = map.tile_with_tag("Unwalkable").unwrap();
+        assert_ne!(map.tile_at(ivec2(3, 4)), Some(wall));
+
+        assert!(map.set_tile(ivec2(3, 4), Some(wall)));
+        assert!(map.set_tile(ivec2(5, 4), None));
+        assert!(!map.set_tile(ivec2(-1, 4), None));
+        assert_eq!(map.tile_at(ivec2(3, 4)), Some(wall));
+        assert_eq!(map.tile_at(ivec2(5, 4)), None);
+        map.save_tiles().unwrap();
+
+        let saved = Map::load(path, "Level_0").unwrap();
+        assert_eq!(saved.tile_at(ivec2(3, 4)), Some(wall));
+        assert_eq!(saved.tile_at(ivec2(5, 4)), None);
+        let (layer, _) = saved.tile_layer().unwrap();
+        assert_eq!(
+            layer.grid_tiles.len(),
+            map.tile_layer().unwrap().0.grid_tiles.len()
+        );
+        // the other level is untouched
+        assert_eq!(
+            saved.map.json.levels[1].px_wid,
+            map.map.json.levels[1].px_wid
+        );
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_save_tiles_to_a_minified_project() {
+        let directory = std::env::temp_dir().join(format!("rts-minified-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("rts.ldtk");
+        let source = std::fs::read_to_string(RTS_LEVEL_LDTK).unwrap();
+        let project: serde_json::Value = serde_json::from_str(&source).unwrap();
+        std::fs::write(&path, serde_json::to_string(&project).unwrap()).unwrap();
+        let path = path.to_str().unwrap();
+
+        let mut map = Map::load(path, "Level_1").unwrap();
+        let wall = map.tile_with_tag("Unwalkable").unwrap();
+        assert!(map.set_tile(ivec2(3, 4), Some(wall)));
+        map.save_tiles().unwrap();
+
+        let saved = Map::load(path, "Level_1").unwrap();
+        assert_eq!(saved.tile_at(ivec2(3, 4)), Some(wall));
+        // only the edited layer changed
+        let level_0 = Map::load(RTS_LEVEL_LDTK, "Level_0").unwrap();
+        let tiles = |map: &Map| map.tile_layer().unwrap().0.grid_tiles.len();
+        let mut saved = saved;
+        saved.set_level("Level_0").unwrap();
+        assert_eq!(tiles(&saved), tiles(&level_0));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
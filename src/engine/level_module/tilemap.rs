@@ -0,0 +1,186 @@
+use comfy::*;
+
+/// Width and height of a static tile batch, in tiles.
+pub const CHUNK_SIZE: f32 = 16.0;
+
+// comfy's sprite functions divide the z index by this to get the depth
+const Z_DIV: f32 = 1000.0;
+
+/// A tile sprite in world space, the same parameters `draw_sprite_ex` takes.
+#[derive(Clone, Copy, Debug)]
+pub struct TileQuad {
+    /// Centre of the tile.
+    pub position: Vec2,
+    /// Width and height in world units.
+    pub size: f32,
+    /// Pixel rect of the tile in the tileset.
+    pub source: IRect,
+    pub flip_x: bool,
+    pub flip_y: bool,
+}
+
+/// The tiles of one layer inside one `CHUNK_SIZE` square, merged into a
+/// single mesh.
+#[derive(Clone, Debug)]
+pub struct TileChunk {
+    pub bounds: AABB,
+    pub z_index: i32,
+    mesh: Mesh,
+}
+
+impl TileChunk {
+    pub fn tile_count(&self) -> usize {
+        self.mesh.vertices.len() / 4
+    }
+}
+
+/// Static tilemap geometry, built once per level instead of issuing a sprite
+/// per tile every frame. Only the chunks overlapping the view are drawn.
+#[derive(Clone, Debug, Default)]
+pub struct TilemapBatch {
+    chunks: Vec<TileChunk>,
+}
+
+impl TilemapBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the tiles of one layer. `texture_size` is the size of the
+    /// tileset in pixels.
+    pub fn add_layer(
+        &mut self,
+        z_index: i32,
+        texture_size: Vec2,
+        tiles: impl IntoIterator<Item = TileQuad>,
+    ) {
+        let mut chunks: HashMap<IVec2, TileChunk> = HashMap::new();
+        for tile in tiles {
+            let key = (tile.position / CHUNK_SIZE).floor().as_ivec2();
+            let half = Vec2::splat(tile.size / 2.0);
+            let chunk = chunks.entry(key).or_insert_with(|| TileChunk {
+                bounds: AABB::new(tile.position - half, tile.position + half),
+                z_index,
+                mesh: Mesh {
+                    z_index,
+                    ..Default::default()
+                },
+            });
+            chunk.bounds.expand_to_include_point(tile.position - half);
+            chunk.bounds.expand_to_include_point(tile.position + half);
+
+            let first = chunk.mesh.vertices.len() as u32;
+            chunk
+                .mesh
+                .vertices
+                .extend(tile_vertices(&tile, z_index, texture_size));
+            chunk
+                .mesh
+                .indices
+                .extend([0, 2, 1, 0, 3, 2].map(|i| first + i));
+        }
+
+        // keep the draw order stable between runs
+        let mut chunks = chunks.into_iter().collect::<Vec<_>>();
+        chunks.sort_by_key(|(key, _)| (key.y, key.x));
+        self.chunks
+            .extend(chunks.into_iter().map(|(_, chunk)| chunk));
+    }
+
+    pub fn chunks(&self) -> &[TileChunk] {
+        &self.chunks
+    }
+
+    pub fn visible(&self, view: AABB) -> impl Iterator<Item = &TileChunk> {
+        self.chunks
+            .iter()
+            .filter(move |chunk| chunk.bounds.intersects(&view))
+    }
+
+    /// Queues the chunks overlapping `view`, one mesh each.
+    pub fn draw(&self, texture: TextureHandle, view: AABB) {
+        for chunk in self.visible(view) {
+            draw_mesh(Mesh {
+                texture: Some(texture),
+                ..chunk.mesh.clone()
+            });
+        }
+    }
+}
+
+// same quad `draw_sprite_ex` would produce for the tile
+fn tile_vertices(tile: &TileQuad, z_index: i32, texture_size: Vec2) -> [SpriteVertex; 4] {
+    rotated_rectangle(
+        tile.position.extend(z_index as f32 / Z_DIV),
+        RawDrawParams {
+            dest_size: Some(Vec2::splat(tile.size)),
+            source_rect: Some(tile.source),
+            flip_x: tile.flip_x,
+            flip_y: tile.flip_y,
+            ..Default::default()
+        },
+        texture_size.x,
+        texture_size.y,
+        WHITE,
+        Vec2::ZERO,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    fn tile(x: f32, y: f32) -> TileQuad {
+        TileQuad {
+            position: vec2(x, y),
+            size: 1.0,
+            source: IRect::new(ivec2(0, 0), ivec2(64, 64)),
+            flip_x: false,
+            flip_y: false,
+        }
+    }
+
+    #[test]
+    fn test_tiles_are_batched_per_chunk_and_culled() {
+        let mut batch = TilemapBatch::new();
+        let tiles = (0..40).flat_map(|y| (0..40).map(move |x| tile(x as f32, y as f32)));
+        batch.add_layer(TILEMAP_Z_INDEX, vec2(512.0, 512.0), tiles);
+        batch.add_layer(TILEMAP_Z_INDEX + 1, vec2(512.0, 512.0), [tile(3.0, 3.0)]);
+
+        // 40 tiles span three chunks per axis
+        assert_eq!(batch.chunks().len(), 10);
+        assert_eq!(
+            batch.chunks().iter().map(|c| c.tile_count()).sum::<usize>(),
+            1601
+        );
+        assert_eq!(batch.chunks()[0].bounds.min, vec2(-0.5, -0.5));
+        assert_eq!(batch.chunks()[0].bounds.max, vec2(15.5, 15.5));
+
+        let view = AABB::from_center_size(vec2(20.0, 20.0), vec2(4.0, 4.0));
+        assert_eq!(batch.visible(view).count(), 1);
+        let view = AABB::from_center_size(vec2(5.0, 5.0), vec2(4.0, 4.0));
+        assert_eq!(batch.visible(view).count(), 2);
+        let view = AABB::from_center_size(vec2(100.0, 5.0), vec2(4.0, 4.0));
+        assert_eq!(batch.visible(view).count(), 0);
+    }
+
+    #[test]
+    fn test_batched_quads_match_sprites() {
+        let mut batch = TilemapBatch::new();
+        let flipped = TileQuad {
+            flip_x: true,
+            ..tile(2.0, 3.0)
+        };
+        batch.add_layer(TILEMAP_Z_INDEX, vec2(512.0, 256.0), [flipped]);
+
+        let mesh = &batch.chunks()[0].mesh;
+        assert_eq!(mesh.indices.as_slice(), &[0, 2, 1, 0, 3, 2]);
+        let xs = mesh
+            .vertices
+            .iter()
+            .map(|v| v.position[0])
+            .collect::<Vec<_>>();
+        assert_eq!(xs, vec![2.5, 1.5, 1.5, 2.5]);
+        assert_eq!(mesh.vertices[0].tex_coords, [0.0, 0.75]);
+    }
+}
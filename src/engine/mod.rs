@@ -1,9 +1,13 @@
 pub mod camera_module;
+pub mod editor_module;
 pub mod game_module;
 pub mod level_module;
+pub mod net_module;
 pub mod pathfinding_module;
+pub mod replay_module;
 pub mod unit_module;
 pub mod selection_module;
+pub mod simulation_module;
 
 pub const TILEMAP_Z_INDEX: i32 = 1;
 pub const PATHFINDING_Z_INDEX: i32 = 10;
@@ -0,0 +1,779 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+use comfy::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    replay_module::DESYNCS_DIRECTORY,
+    simulation_module::{checksum::WorldSnapshot, Command, Simulation},
+    unit_module::UnitArchetypes,
+};
+
+/// Commands given on tick `t` are applied on tick `t + INPUT_DELAY_TICKS`,
+/// which gives them that long to reach the other players.
+pub const INPUT_DELAY_TICKS: u64 = 2;
+// ticks of input resent per packet, keeps datagrams well under the UDP limit
+const MAX_TICKS_PER_PACKET: usize = 32;
+const MAX_PACKET_SIZE: usize = 64 * 1024;
+// snapshots kept to compare against the peers, a few seconds of play
+const CHECKSUM_HISTORY_TICKS: usize = 64;
+// latest checksums sent per packet
+const CHECKSUMS_PER_PACKET: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Packet {
+    player: u32,
+    /// `UnitArchetypes::checksum` of the sender's unit types.
+    archetypes: u64,
+    /// First tick the sender is still missing from the receiver.
+    ack: u64,
+    inputs: Vec<TickInput>,
+    /// `(tick, checksum)` of the sender's latest ticks.
+    checksums: Vec<(u64, u64)>,
+    /// Sent once the sender noticed a desync.
+    report: Option<DesyncReport>,
+}
+
+/// The sender's entities on the first tick it found out of sync, for the
+/// receiver to tell which one differs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DesyncReport {
+    tick: u64,
+    /// Entity bits and the hash of their state.
+    entities: Vec<(u64, u64)>,
+}
+
+/// The first tick on which a peer's world stopped matching ours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Desync {
+    pub tick: u64,
+    pub player: u32,
+    /// First entity whose state differs, once the peer's report is in.
+    pub entity: Option<Entity>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TickInput {
+    tick: u64,
+    commands: Vec<Command>,
+}
+
+struct Peer {
+    player: u32,
+    address: SocketAddr,
+    received: BTreeMap<u64, Vec<Command>>,
+    // first tick not received yet, every earlier one was
+    next_missing: u64,
+    // first tick of ours the peer is still missing
+    acked: u64,
+    // checksums not compared with ours yet
+    checksums: BTreeMap<u64, u64>,
+    report: Option<DesyncReport>,
+    // set once the peer turned out to play with other unit types
+    other_archetypes: bool,
+}
+
+/// Deterministic lockstep over UDP. Every player sends its commands for
+/// each tick to every other player, and a tick only runs once the commands
+/// of all players for it are in, so every client steps its simulation with
+/// the same commands in the same order.
+///
+/// Packets repeat every input the receiver hasn't acknowledged yet, so lost
+/// or reordered datagrams only cost time. They also carry the checksums of
+/// the sender's last ticks, a mismatch is logged with a dump of the world in
+/// `dump_directory`. Packets of players with other unit types are dropped,
+/// the match couldn't stay in sync with them.
+pub struct LockstepSession {
+    pub local_player: u32,
+    /// Set on the first checksum that didn't match a peer's.
+    pub desync: Option<Desync>,
+    /// Where the world is dumped when it goes out of sync.
+    pub dump_directory: String,
+    socket: UdpSocket,
+    // hash of our unit types, sent to the peers
+    archetypes: u64,
+    peers: Vec<Peer>,
+    // our commands per tick, kept until every peer has them
+    local: BTreeMap<u64, Vec<Command>>,
+    snapshots: VecDeque<WorldSnapshot>,
+    // our side of the desync, sent to the peers
+    report: Option<DesyncReport>,
+}
+
+impl LockstepSession {
+    pub fn bind(
+        local_player: u32,
+        address: SocketAddr,
+        archetypes: &UnitArchetypes,
+    ) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            local_player,
+            desync: None,
+            dump_directory: DESYNCS_DIRECTORY.to_string(),
+            socket,
+            archetypes: archetypes.checksum(),
+            peers: Vec::new(),
+            local: BTreeMap::new(),
+            snapshots: VecDeque::new(),
+            report: None,
+        })
+    }
+
+    pub fn local_address(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn add_peer(&mut self, player: u32, address: SocketAddr) {
+        self.peers.push(Peer {
+            player,
+            address,
+            received: BTreeMap::new(),
+            next_missing: INPUT_DELAY_TICKS,
+            acked: INPUT_DELAY_TICKS,
+            checksums: BTreeMap::new(),
+            report: None,
+            other_archetypes: false,
+        });
+        self.peers.sort_by_key(|peer| peer.player);
+    }
+
+    /// Players whose commands for `tick` haven't arrived yet.
+    pub fn waiting_for(&self, tick: u64) -> Vec<u32> {
+        self.peers
+            .iter()
+            .filter(|peer| tick >= peer.next_missing)
+            .map(|peer| peer.player)
+            .collect()
+    }
+
+    /// Schedules `local` for `tick + INPUT_DELAY_TICKS` unless that tick
+    /// already has our input, then exchanges packets with the peers.
+    /// Returns the commands of every player for `tick`, in player order and
+    /// stamped with the player that gave them, once all of them arrived;
+    /// `local` is left alone while the tick after the delay is taken, and
+    /// goes into a later one.
+    ///
+    /// Call with the tick the simulation is about to run and step it with
+    /// what comes back.
+    pub fn exchange(&mut self, tick: u64, local: &mut Vec<Command>) -> Option<Vec<Command>> {
+        let local_player = self.local_player;
+        self.local
+            .entry(tick + INPUT_DELAY_TICKS)
+            .or_insert_with(|| {
+                // selection only matters to the player making it
+                std::mem::take(local)
+                    .into_iter()
+                    .filter(|command| !matches!(command, Command::Select { .. }))
+                    .map(|mut command| {
+                        command.stamp(local_player);
+                        command
+                    })
+                    .collect()
+            });
+
+        self.receive();
+        self.send();
+
+        if tick < INPUT_DELAY_TICKS {
+            return Some(Vec::new());
+        }
+        if !self.waiting_for(tick).is_empty() {
+            return None;
+        }
+
+        let mut inputs = self
+            .peers
+            .iter_mut()
+            .map(|peer| (peer.player, peer.received.remove(&tick).unwrap_or_default()))
+            .collect::<Vec<_>>();
+        let acked = self.peers.iter().map(|peer| peer.acked).min();
+        let local = match acked {
+            // keep our input around until every peer confirmed it
+            Some(acked) if acked <= tick => self.local.get(&tick).cloned(),
+            _ => self.local.remove(&tick),
+        };
+        inputs.push((self.local_player, local.unwrap_or_default()));
+        inputs.sort_by_key(|(player, _)| *player);
+
+        let acked = acked.unwrap_or(u64::MAX);
+        self.local
+            .retain(|scheduled, _| *scheduled >= acked || *scheduled > tick);
+        Some(
+            inputs
+                .into_iter()
+                .flat_map(|(_, commands)| commands)
+                .collect(),
+        )
+    }
+
+    /// Short description for the HUD.
+    pub fn status(&self, tick: u64) -> String {
+        let mut status = format!("player {}", self.local_player);
+        let waiting = self.waiting_for(tick);
+        if !waiting.is_empty() {
+            status += &format!(", waiting for {waiting:?}");
+        }
+        let others = self
+            .peers
+            .iter()
+            .filter(|peer| peer.other_archetypes)
+            .map(|peer| peer.player)
+            .collect::<Vec<_>>();
+        if !others.is_empty() {
+            status += &format!(", other unit types than {others:?}");
+        }
+        if let Some(desync) = self.desync {
+            status += &format!(
+                ", out of sync with player {} since tick {}",
+                desync.player, desync.tick
+            );
+        }
+        status
+    }
+
+    /// Keeps the state of the simulation after a tick to compare with the
+    /// peers. Call after every step with the snapshot of `sim`, which is
+    /// dumped if it turns out to be out of sync.
+    pub fn record(&mut self, snapshot: WorldSnapshot, sim: &Simulation) {
+        self.snapshots.push_back(snapshot);
+        if self.snapshots.len() > CHECKSUM_HISTORY_TICKS {
+            self.snapshots.pop_front();
+        }
+        self.compare(sim);
+    }
+
+    fn snapshot_at(&self, tick: u64) -> Option<&WorldSnapshot> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+
+    // compares what the peers sent with the ticks we have played
+    fn compare(&mut self, sim: &Simulation) {
+        let Some(last) = self.snapshots.back().map(|snapshot| snapshot.tick) else {
+            return;
+        };
+        let mut mismatch = None;
+        for peer in &mut self.peers {
+            let due = peer.checksums.split_off(&(last + 1));
+            for (tick, theirs) in std::mem::replace(&mut peer.checksums, due) {
+                let ours = self.snapshots.iter().find(|snapshot| snapshot.tick == tick);
+                if mismatch.is_none() && ours.is_some_and(|ours| ours.checksum != theirs) {
+                    mismatch = Some((peer.player, tick, theirs));
+                }
+            }
+        }
+
+        if self.desync.is_none() {
+            if let Some((player, tick, theirs)) = mismatch {
+                self.report_desync(player, tick, theirs, sim);
+            }
+        }
+        if self.desync.is_some_and(|desync| desync.entity.is_none()) {
+            self.find_divergent_entity(sim);
+        }
+    }
+
+    fn report_desync(&mut self, player: u32, tick: u64, theirs: u64, sim: &Simulation) {
+        let Some(ours) = self.snapshot_at(tick) else {
+            return;
+        };
+        error!(
+            "Out of sync with player {player} at tick {tick}: checksum {:016x}, theirs {theirs:016x}",
+            ours.checksum
+        );
+        // the checksums arrive a few ticks late, the dump is of the world now
+        match sim.save_dump(
+            &self.dump_directory,
+            &format!("player-{}", self.local_player),
+        ) {
+            Ok(path) => error!("World state at tick {} written to {path}", sim.tick),
+            Err(err) => error!("Failed to write the world state: {err}"),
+        }
+        self.report = Some(DesyncReport {
+            tick,
+            entities: ours
+                .entities
+                .iter()
+                .map(|state| (state.entity.to_bits().get(), state.hash))
+                .collect(),
+        });
+        self.desync = Some(Desync {
+            tick,
+            player,
+            entity: None,
+        });
+    }
+
+    // compares our entities with the report of the peer we are out of sync
+    // with, once it arrived
+    fn find_divergent_entity(&mut self, sim: &Simulation) {
+        let Some(desync) = self.desync else {
+            return;
+        };
+        let Some(report) = self
+            .peers
+            .iter()
+            .find(|peer| peer.player == desync.player)
+            .and_then(|peer| peer.report.as_ref())
+        else {
+            return;
+        };
+        let Some(ours) = self.snapshot_at(report.tick) else {
+            return;
+        };
+        let theirs = report
+            .entities
+            .iter()
+            .filter_map(|(bits, hash)| Some((Entity::from_bits(*bits)?, *hash)))
+            .collect::<Vec<_>>();
+        let Some(entity) = ours.first_divergence(&theirs) else {
+            return;
+        };
+        match (ours.entity(entity), sim.describe(entity)) {
+            (Some(_), Some(description)) => error!(
+                "First entity out of sync with player {}: {entity:?}, at tick {} {description}",
+                desync.player, sim.tick
+            ),
+            (Some(_), None) => error!(
+                "First entity out of sync with player {}: {entity:?}, gone by tick {}",
+                desync.player, sim.tick
+            ),
+            (None, _) => error!(
+                "First entity out of sync with player {}: {entity:?}, which only they have",
+                desync.player
+            ),
+        }
+        self.desync = Some(Desync {
+            entity: Some(entity),
+            ..desync
+        });
+    }
+
+    fn send(&self) {
+        let checksums = self
+            .snapshots
+            .iter()
+            .rev()
+            .take(CHECKSUMS_PER_PACKET)
+            .map(|snapshot| (snapshot.tick, snapshot.checksum))
+            .collect::<Vec<_>>();
+        for peer in &self.peers {
+            let packet = Packet {
+                player: self.local_player,
+                archetypes: self.archetypes,
+                ack: peer.next_missing,
+                inputs: self
+                    .local
+                    .range(peer.acked..)
+                    .take(MAX_TICKS_PER_PACKET)
+                    .map(|(tick, commands)| TickInput {
+                        tick: *tick,
+                        commands: commands.clone(),
+                    })
+                    .collect(),
+                checksums: checksums.clone(),
+                report: self.report.clone(),
+            };
+            let Ok(bytes) = serde_json::to_vec(&packet) else {
+                continue;
+            };
+            if let Err(err) = self.socket.send_to(&bytes, peer.address) {
+                // the peer may simply not be up yet, the input is resent
+                if err.kind() != io::ErrorKind::ConnectionRefused {
+                    warn!("Failed to send to player {}: {err}", peer.player);
+                }
+            }
+        }
+    }
+
+    fn receive(&mut self) {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (size, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                // errors of earlier sends to a peer that isn't up yet
+                Err(err) if err.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(err) => {
+                    warn!("Failed to receive: {err}");
+                    break;
+                }
+            };
+            let packet = match serde_json::from_slice::<Packet>(&buffer[..size]) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    warn!("Dropped a malformed packet from {from}: {err}");
+                    continue;
+                }
+            };
+            // only the peer's own address may speak for it
+            let Some(peer) = self
+                .peers
+                .iter_mut()
+                .find(|p| p.player == packet.player && p.address == from)
+            else {
+                warn!("Dropped a packet for player {} from {from}", packet.player);
+                continue;
+            };
+            if packet.archetypes != self.archetypes {
+                if !peer.other_archetypes {
+                    error!(
+                        "Player {} plays with other unit types, not playing with them",
+                        peer.player
+                    );
+                    peer.other_archetypes = true;
+                }
+                continue;
+            }
+
+            peer.acked = peer.acked.max(packet.ack);
+            for mut input in packet.inputs {
+                if input.tick >= peer.next_missing {
+                    for command in &mut input.commands {
+                        command.stamp(peer.player);
+                    }
+                    peer.received.insert(input.tick, input.commands);
+                }
+            }
+            while peer.received.contains_key(&peer.next_missing) {
+                peer.next_missing += 1;
+            }
+            peer.checksums.extend(packet.checksums);
+            if peer.report.is_none() {
+                peer.report = packet.report;
+            }
+        }
+    }
+}
+
+/// Builds a session from `--player N --bind ADDRESS --peer N@ADDRESS...`
+/// on the command line, `None` without `--bind`.
+pub fn session_from_args(
+    args: &[String],
+    archetypes: &UnitArchetypes,
+) -> Result<Option<LockstepSession>, String> {
+    let mut player = 0;
+    let mut bind = None;
+    let mut peers = Vec::new();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{arg} needs a value"));
+        match arg.as_str() {
+            "--player" => {
+                player = value()?
+                    .parse()
+                    .map_err(|err| format!("bad player: {err}"))?
+            }
+            "--bind" => {
+                bind = Some(
+                    value()?
+                        .parse::<SocketAddr>()
+                        .map_err(|err| format!("bad address: {err}"))?,
+                )
+            }
+            "--peer" => {
+                let value = value()?;
+                let (peer, address) = value
+                    .split_once('@')
+                    .ok_or(format!("expected PLAYER@ADDRESS, got {value}"))?;
+                let peer = peer.parse().map_err(|err| format!("bad player: {err}"))?;
+                let address = address
+                    .parse::<SocketAddr>()
+                    .map_err(|err| format!("bad address: {err}"))?;
+                peers.push((peer, address));
+            }
+            _ => {}
+        }
+    }
+
+    let Some(bind) = bind else {
+        return Ok(None);
+    };
+    let mut session = LockstepSession::bind(player, bind, archetypes)
+        .map_err(|err| format!("failed to bind {bind}: {err}"))?;
+    for (peer, address) in peers {
+        session.add_peer(peer, address);
+    }
+    Ok(Some(session))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        level_module::{Map, DEFAULT_LEVEL, RTS_LEVEL_LDTK},
+        unit_module::{Order, Owner, Unit},
+    };
+
+    const TICKS: u64 = 120;
+
+    struct Client {
+        session: LockstepSession,
+        sim: Simulation,
+        pending: Vec<Command>,
+        scripted: Option<u64>,
+        // tick after which the client nudges one of its units on its own
+        tamper: Option<u64>,
+    }
+
+    impl Client {
+        fn new(player: u32, map: &Map) -> Self {
+            let mut sim = Simulation::new();
+            sim.load_level(map);
+            Self {
+                session: LockstepSession::bind(
+                    player,
+                    "127.0.0.1:0".parse().unwrap(),
+                    &sim.archetypes,
+                )
+                .unwrap(),
+                sim,
+                pending: Vec::new(),
+                scripted: None,
+                tamper: None,
+            }
+        }
+
+        fn units(&self) -> Vec<Entity> {
+            let player = self.session.local_player;
+            self.sim
+                .world
+                .query::<(&Unit, &Owner)>()
+                .iter()
+                .filter(|(_, (_, owner))| owner.0 == player)
+                .map(|(entity, _)| entity)
+                .collect()
+        }
+
+        fn first_unit(&self) -> Entity {
+            self.units()
+                .into_iter()
+                .min_by_key(|unit| unit.to_bits())
+                .unwrap()
+        }
+
+        // what the player does, given once per tick of the simulation
+        fn script(&self) -> Vec<Command> {
+            let move_to = |target| Command::Order {
+                units: self.units(),
+                order: Order::Move(target),
+                queued: false,
+                player: None,
+            };
+            match (self.session.local_player, self.sim.tick) {
+                (0, 5) => vec![
+                    Command::Select {
+                        units: self.units(),
+                    },
+                    move_to(vec2(70.0, -15.5)),
+                ],
+                (1, 12) => vec![Command::SpawnUnit {
+                    position: vec2(80.0, -7.5),
+                    owner: 1,
+                    unit_type: "Worker".to_string(),
+                    health: None,
+                    facing: 0.0,
+                    player: None,
+                }],
+                (1, 20) => vec![move_to(vec2(75.0, -20.5))],
+                (0, 40) => vec![move_to(vec2(80.0, -10.5))],
+                _ => vec![],
+            }
+        }
+
+        fn update(&mut self) {
+            if self.sim.tick >= TICKS {
+                // keep answering so the other client can finish
+                self.session.exchange(self.sim.tick, &mut Vec::new());
+                return;
+            }
+            if self.scripted != Some(self.sim.tick) {
+                self.scripted = Some(self.sim.tick);
+                let commands = self.script();
+                self.pending.extend(commands);
+            }
+            if let Some(commands) = self.session.exchange(self.sim.tick, &mut self.pending) {
+                self.sim.step(&commands);
+                if self.tamper == Some(self.sim.tick) {
+                    let unit = self.first_unit();
+                    self.sim
+                        .world
+                        .get::<&mut Transform>(unit)
+                        .unwrap()
+                        .position
+                        .y += 0.5;
+                }
+                self.session.record(self.sim.snapshot(), &self.sim);
+            }
+        }
+
+        fn positions(&self) -> Vec<(Entity, u32, u32)> {
+            self.sim
+                .world
+                .query::<(&Transform, &Unit)>()
+                .iter()
+                .map(|(entity, (transform, _))| {
+                    let position = transform.position;
+                    (entity, position.x.to_bits(), position.y.to_bits())
+                })
+                .collect()
+        }
+    }
+
+    fn connected_clients(map: &Map) -> [Client; 2] {
+        connect([Client::new(0, map), Client::new(1, map)])
+    }
+
+    fn connect(mut clients: [Client; 2]) -> [Client; 2] {
+        let addresses = clients
+            .iter()
+            .map(|client| client.session.local_address().unwrap())
+            .collect::<Vec<_>>();
+        clients[0].session.add_peer(1, addresses[1]);
+        clients[1].session.add_peer(0, addresses[0]);
+        clients
+    }
+
+    fn run_until(clients: &mut [Client], done: impl Fn(&[Client]) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done(clients) {
+            assert!(Instant::now() < deadline, "clients stalled");
+            for client in clients.iter_mut() {
+                client.update();
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_two_clients_stay_in_sync_over_loopback() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut clients = connected_clients(&map);
+
+        let initial = clients[0].positions();
+
+        // alone, a client can't get past the ticks it has no input for
+        for _ in 0..20 {
+            clients[0].update();
+        }
+        assert_eq!(clients[0].sim.tick, INPUT_DELAY_TICKS);
+        assert_eq!(clients[0].session.waiting_for(INPUT_DELAY_TICKS), vec![1]);
+
+        run_until(&mut clients, |clients| {
+            clients.iter().all(|client| client.sim.tick >= TICKS)
+        });
+
+        assert_eq!(clients[0].positions(), clients[1].positions());
+        assert_eq!(clients[0].session.desync, None);
+        assert_eq!(clients[1].session.desync, None);
+        // both players' commands made it to both simulations
+        assert_eq!(clients[0].units().len(), 3);
+        assert_eq!(clients[1].units().len(), 4);
+        let ordered = clients[0].units();
+        let moved = clients[0]
+            .positions()
+            .into_iter()
+            .filter(|unit| ordered.contains(&unit.0) && !initial.contains(unit))
+            .count();
+        assert_eq!(moved, ordered.len());
+    }
+
+    #[test]
+    fn test_packets_only_count_from_the_peers_address() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut clients = connected_clients(&map);
+        let address = clients[0].session.local_address().unwrap();
+
+        // someone else claiming to be player 1
+        let packet = Packet {
+            player: 1,
+            archetypes: clients[0].sim.archetypes.checksum(),
+            ack: 0,
+            inputs: (0..TICKS)
+                .map(|tick| TickInput {
+                    tick,
+                    commands: Vec::new(),
+                })
+                .collect(),
+            checksums: Vec::new(),
+            report: None,
+        };
+        let spoofer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        spoofer
+            .send_to(&serde_json::to_vec(&packet).unwrap(), address)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        for _ in 0..20 {
+            clients[0].update();
+        }
+        assert_eq!(clients[0].sim.tick, INPUT_DELAY_TICKS);
+        assert_eq!(clients[0].session.waiting_for(INPUT_DELAY_TICKS), vec![1]);
+    }
+
+    #[test]
+    fn test_players_with_other_unit_types_are_refused() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut other = Client::new(1, &map);
+        other.sim.archetypes = UnitArchetypes::parse(
+            r#"[{"name": "Worker", "speed": 3, "radius": 0.5, "health": 10, "vision": 5}]"#,
+            "units.json",
+        )
+        .unwrap();
+        other.session =
+            LockstepSession::bind(1, "127.0.0.1:0".parse().unwrap(), &other.sim.archetypes)
+                .unwrap();
+        let mut clients = connect([Client::new(0, &map), other]);
+
+        for _ in 0..20 {
+            for client in clients.iter_mut() {
+                client.update();
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        for (client, other) in [(0, 1), (1, 0)] {
+            let session = &clients[client].session;
+            assert_eq!(clients[client].sim.tick, INPUT_DELAY_TICKS);
+            assert_eq!(session.waiting_for(INPUT_DELAY_TICKS), vec![other]);
+            assert!(session
+                .status(INPUT_DELAY_TICKS)
+                .contains(&format!("other unit types than [{other}]")));
+        }
+    }
+
+    #[test]
+    fn test_desync_names_the_first_divergent_entity() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut clients = connected_clients(&map);
+        clients[1].tamper = Some(30);
+        let tampered = clients[1].first_unit();
+        let dumps = std::env::temp_dir().join(format!("rts-desyncs-net-{}", std::process::id()));
+        for client in &mut clients {
+            client.session.dump_directory = dumps.to_str().unwrap().to_string();
+        }
+
+        run_until(&mut clients, |clients| {
+            clients.iter().all(|client| {
+                client
+                    .session
+                    .desync
+                    .is_some_and(|desync| desync.entity.is_some())
+            })
+        });
+
+        for (client, other) in [(0, 1), (1, 0)] {
+            let desync = clients[client].session.desync.unwrap();
+            assert_eq!(desync.player, other);
+            assert_eq!(desync.tick, 30);
+            assert_eq!(desync.entity, Some(tampered));
+        }
+        std::fs::remove_dir_all(&dumps).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use pathfinding::prelude::astar;
+
+use super::{Node, PathfindingTerrain, Successor};
+
+// how far away a replacement goal is searched for when the clicked node is
+// too tight for the agent
+const GOAL_SEARCH_RADIUS: i32 = 8;
+
+/// Clearance a unit of `agent_radius` needs on every node it stands on.
+///
+/// A node with clearance `c` has `c - 1` free nodes in every direction, so
+/// the free square around its centre has a half-width of `c - 0.5`.
+pub fn required_clearance(agent_radius: f32) -> i32 {
+    (agent_radius + 0.5).ceil().max(1.0) as i32
+}
+
+impl PathfindingTerrain {
+    /// Recomputes the clearance map if the grid changed since the last call.
+    ///
+    /// Clearance is the Chebyshev distance, in nodes, to the nearest
+    /// `Unwalkable` node or the edge of the map: 0 on walls, 1 next to them.
+    pub fn refresh_clearance(&mut self) {
+        if self.clearance_revision == Some(self.revision) {
+            return;
+        }
+
+        let (width, height) = (self.width, self.height);
+        let mut clearance = vec![i32::MAX; self.nodes.len()];
+        let get = |clearance: &Vec<i32>, x: i32, y: i32| {
+            if x < 0 || x >= width || y < 0 || y >= height {
+                0
+            } else {
+                clearance[(y * width + x) as usize]
+            }
+        };
+
+        // two pass chamfer transform, exact for the 8-neighbourhood
+        for y in 0..height {
+            for x in 0..width {
+                let i = (y * width + x) as usize;
+                if !self.is_walkable(x, y) {
+                    clearance[i] = 0;
+                    continue;
+                }
+                let nearest = [(-1, 0), (-1, -1), (0, -1), (1, -1)]
+                    .iter()
+                    .map(|(dx, dy)| get(&clearance, x + dx, y + dy))
+                    .min()
+                    .unwrap();
+                clearance[i] = clearance[i].min(nearest.saturating_add(1));
+            }
+        }
+        for y in (0..height).rev() {
+            for x in (0..width).rev() {
+                let i = (y * width + x) as usize;
+                let nearest = [(1, 0), (1, 1), (0, 1), (-1, 1)]
+                    .iter()
+                    .map(|(dx, dy)| get(&clearance, x + dx, y + dy))
+                    .min()
+                    .unwrap();
+                clearance[i] = clearance[i].min(nearest.saturating_add(1));
+            }
+        }
+
+        self.clearance = clearance;
+        self.clearance_revision = Some(self.revision);
+    }
+
+    pub fn clearance_at(&self, x: i32, y: i32) -> i32 {
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            return 0;
+        }
+        self.clearance
+            .get((y * self.width + x) as usize)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub(super) fn fits(&self, x: i32, y: i32, clearance: i32) -> bool {
+        if clearance <= 1 {
+            self.is_walkable(x, y)
+        } else {
+            self.clearance_at(x, y) >= clearance
+        }
+    }
+
+    pub fn get_successors_for_clearance(&self, node: &Node, clearance: i32) -> Vec<Successor> {
+        let mut successors = self.get_successors(node);
+        if clearance > 1 {
+            successors.retain(|s| self.clearance_at(s.node.x, s.node.y) >= clearance);
+        }
+        successors
+    }
+
+    /// Closest node to `(x, y)` an agent needing `clearance` can stand on.
+    pub fn nearest_node_with_clearance(&self, x: i32, y: i32, clearance: i32) -> Option<Node> {
+        (0..=GOAL_SEARCH_RADIUS).find_map(|ring| {
+            (-ring..=ring)
+                .flat_map(|dy| (-ring..=ring).map(move |dx| (dx, dy)))
+                .filter(|(dx, dy)| dx.abs() == ring || dy.abs() == ring)
+                .filter(|(dx, dy)| self.fits(x + dx, y + dy, clearance))
+                .min_by_key(|(dx, dy)| dx * dx + dy * dy)
+                .and_then(|(dx, dy)| self.get_node_by_position(x + dx, y + dy))
+        })
+    }
+
+    /// Path an agent of `agent_radius` can physically walk. Agents that fit
+    /// through single nodes use the HPA* search, bigger ones a flat A* over
+    /// nodes with enough clearance. If the goal is too tight the closest
+    /// node that fits is used instead.
+    pub fn get_path_for_agent(
+        &self,
+        start: Node,
+        goal: Node,
+        agent_radius: f32,
+    ) -> Option<Vec<Node>> {
+        let clearance = required_clearance(agent_radius);
+        if clearance <= 1 {
+            return self.get_hierarchical_path(start, goal);
+        }
+
+        let goal = self.nearest_node_with_clearance(goal.x, goal.y, clearance)?;
+        astar(
+            &start,
+            |node| {
+                self.get_successors_for_clearance(node, clearance)
+                    .iter()
+                    .map(|s| (s.node, s.cost))
+                    .collect::<Vec<_>>()
+            },
+            |node| self.heuristic(node, &goal),
+            |node| *node == goal,
+        )
+        .map(|(path, _cost)| path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    // a wall along x = 6 with gaps of one and three nodes
+    fn gapped_wall() -> PathfindingTerrain {
+        let mut terrain = PathfindingTerrain::new(13, 16, None);
+        for y in 0..16 {
+            if y != 3 && !(9..12).contains(&y) {
+                terrain.set_node_type(6, y, NodeType::Unwalkable);
+            }
+        }
+        terrain.refresh();
+        terrain
+    }
+
+    #[test]
+    fn test_clearance_map() {
+        let terrain = gapped_wall();
+        assert_eq!(terrain.clearance_at(6, 0), 0);
+        assert_eq!(terrain.clearance_at(5, 0), 1);
+        assert_eq!(terrain.clearance_at(6, 3), 1);
+        assert_eq!(terrain.clearance_at(6, 10), 2);
+        assert_eq!(terrain.clearance_at(2, 7), 3);
+    }
+
+    #[test]
+    fn test_agent_sizes_pick_gaps_they_fit_through() {
+        let terrain = gapped_wall();
+        let start = terrain.get_node_by_position(3, 3).unwrap();
+        let goal = terrain.get_node_by_position(9, 3).unwrap();
+
+        for (radius, gap) in [(0.5, 3..4), (1.0, 9..12), (1.5, 9..12)] {
+            let path = terrain.get_path_for_agent(start, goal, radius).unwrap();
+            let crossing = path.iter().find(|n| n.x == 6).unwrap();
+            assert!(
+                gap.contains(&crossing.y),
+                "radius {radius} crossed at {crossing:?}"
+            );
+
+            let clearance = required_clearance(radius);
+            assert!(path[1..]
+                .iter()
+                .all(|n| terrain.clearance_at(n.x, n.y) >= clearance));
+        }
+
+        assert!(terrain.get_path_for_agent(start, goal, 2.5).is_none());
+    }
+}
@@ -0,0 +1,292 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use comfy::*;
+
+use super::{Node, PathfindingTerrain};
+
+const INFINITY: u32 = u32::MAX;
+
+type Key = (u32, u32);
+
+/// D* Lite planner (Koenig & Likhachev) for one unit. The search runs
+/// backwards from the goal, so after tiles change only the part of the
+/// search affected by them is redone while the unit keeps moving.
+#[derive(Clone, Debug)]
+pub struct DStarLite {
+    goal: IVec2,
+    start: IVec2,
+    last: IVec2,
+    clearance: i32,
+    k_m: u32,
+    g: HashMap<IVec2, u32>,
+    rhs: HashMap<IVec2, u32>,
+    open: BinaryHeap<Reverse<(Key, i32, i32)>>,
+    // current key of every node in `open`, heap entries with another key are stale
+    open_keys: HashMap<IVec2, Key>,
+}
+
+impl DStarLite {
+    pub fn new(start: IVec2, goal: IVec2, clearance: i32) -> Self {
+        let mut planner = Self {
+            goal,
+            start,
+            last: start,
+            clearance,
+            k_m: 0,
+            g: HashMap::new(),
+            rhs: HashMap::new(),
+            open: BinaryHeap::new(),
+            open_keys: HashMap::new(),
+        };
+        planner.rhs.insert(goal, 0);
+        let key = (planner.distance(start, goal), 0);
+        planner.push(goal, key);
+        planner
+    }
+
+    pub fn goal(&self) -> IVec2 {
+        self.goal
+    }
+
+    /// Re-plans from `from` after the nodes in `changed` were edited and
+    /// returns the new path, `None` if the goal became unreachable.
+    pub fn repair(
+        &mut self,
+        terrain: &PathfindingTerrain,
+        from: IVec2,
+        changed: &[IVec2],
+    ) -> Option<Vec<Node>> {
+        self.notify_changes(terrain, from, changed);
+        self.path(terrain)
+    }
+
+    /// Feeds edits to the planner without extracting a path. The terrain
+    /// must already be refreshed.
+    pub fn notify_changes(&mut self, terrain: &PathfindingTerrain, from: IVec2, changed: &[IVec2]) {
+        self.start = from;
+        self.k_m = self.k_m.saturating_add(self.distance(self.last, from));
+        self.last = from;
+
+        // an edit changes the clearance up to `clearance` nodes away, and
+        // every edge touching those nodes
+        let reach = self.clearance.max(1);
+        let mut touched = HashSet::new();
+        for cell in changed {
+            for dy in -reach..=reach {
+                for dx in -reach..=reach {
+                    touched.insert(*cell + ivec2(dx, dy));
+                }
+            }
+        }
+        for cell in touched {
+            self.update_vertex(terrain, cell);
+        }
+    }
+
+    /// Shortest path from the current start to the goal.
+    pub fn path(&mut self, terrain: &PathfindingTerrain) -> Option<Vec<Node>> {
+        self.compute_shortest_path(terrain);
+        if self.g(self.start) == INFINITY {
+            return None;
+        }
+
+        let mut path = vec![terrain.get_node_by_position(self.start.x, self.start.y)?];
+        let mut current = self.start;
+        let limit = terrain.nodes.len();
+        while current != self.goal {
+            let node = terrain.get_node_by_position(current.x, current.y)?;
+            let next = terrain
+                .get_successors_for_clearance(&node, self.clearance)
+                .into_iter()
+                .filter(|s| self.g(ivec2(s.node.x, s.node.y)) != INFINITY)
+                .min_by_key(|s| s.cost.saturating_add(self.g(ivec2(s.node.x, s.node.y))))?;
+            current = ivec2(next.node.x, next.node.y);
+            path.push(next.node);
+            if path.len() > limit {
+                return None;
+            }
+        }
+        Some(path)
+    }
+
+    fn g(&self, cell: IVec2) -> u32 {
+        self.g.get(&cell).copied().unwrap_or(INFINITY)
+    }
+
+    fn rhs(&self, cell: IVec2) -> u32 {
+        self.rhs.get(&cell).copied().unwrap_or(INFINITY)
+    }
+
+    // octile distance, same as the A* heuristic for 8-way movement
+    fn distance(&self, a: IVec2, b: IVec2) -> u32 {
+        let d = (a - b).abs();
+        let diagonal = d.x.min(d.y) as u32;
+        let straight = (d.x.max(d.y) - d.x.min(d.y)) as u32;
+        diagonal * super::DIAGONAL_COST + straight * super::ORTHOGONAL_COST
+    }
+
+    fn calculate_key(&self, terrain: &PathfindingTerrain, cell: IVec2) -> Key {
+        let best = self.g(cell).min(self.rhs(cell));
+        let h = self.distance(self.start, cell) * terrain.min_cost / super::DEFAULT_TERRAIN_COST;
+        (best.saturating_add(h).saturating_add(self.k_m), best)
+    }
+
+    fn push(&mut self, cell: IVec2, key: Key) {
+        self.open_keys.insert(cell, key);
+        self.open.push(Reverse((key, cell.x, cell.y)));
+    }
+
+    fn top(&mut self) -> Option<(Key, IVec2)> {
+        while let Some(Reverse((key, x, y))) = self.open.peek().copied() {
+            let cell = ivec2(x, y);
+            if self.open_keys.get(&cell) == Some(&key) {
+                return Some((key, cell));
+            }
+            self.open.pop();
+        }
+        None
+    }
+
+    fn update_vertex(&mut self, terrain: &PathfindingTerrain, cell: IVec2) {
+        let Some(node) = terrain.get_node_by_position(cell.x, cell.y) else {
+            return;
+        };
+
+        if cell != self.goal {
+            let rhs = if terrain.fits(cell.x, cell.y, self.clearance) {
+                terrain
+                    .get_successors_for_clearance(&node, self.clearance)
+                    .into_iter()
+                    .map(|s| s.cost.saturating_add(self.g(ivec2(s.node.x, s.node.y))))
+                    .min()
+                    .unwrap_or(INFINITY)
+            } else {
+                INFINITY
+            };
+            self.rhs.insert(cell, rhs);
+        }
+
+        self.open_keys.remove(&cell);
+        if self.g(cell) != self.rhs(cell) {
+            let key = self.calculate_key(terrain, cell);
+            self.push(cell, key);
+        }
+    }
+
+    fn compute_shortest_path(&mut self, terrain: &PathfindingTerrain) {
+        // generous bound so a bug can't hang the frame
+        let mut budget = terrain.nodes.len() * 16;
+
+        while let Some((old_key, cell)) = self.top() {
+            let start_key = self.calculate_key(terrain, self.start);
+            if old_key >= start_key && self.rhs(self.start) == self.g(self.start) {
+                break;
+            }
+            if budget == 0 {
+                warn!("D* Lite gave up after exhausting its expansion budget");
+                break;
+            }
+            budget -= 1;
+
+            let new_key = self.calculate_key(terrain, cell);
+            if old_key < new_key {
+                self.push(cell, new_key);
+            } else if self.g(cell) > self.rhs(cell) {
+                self.open_keys.remove(&cell);
+                self.g.insert(cell, self.rhs(cell));
+                for predecessor in self.predecessors(terrain, cell) {
+                    self.update_vertex(terrain, predecessor);
+                }
+            } else {
+                self.g.insert(cell, INFINITY);
+                self.update_vertex(terrain, cell);
+                for predecessor in self.predecessors(terrain, cell) {
+                    self.update_vertex(terrain, predecessor);
+                }
+            }
+        }
+    }
+
+    // neighbours can step onto `cell` exactly when `cell` can step onto them
+    fn predecessors(&self, terrain: &PathfindingTerrain, cell: IVec2) -> Vec<IVec2> {
+        match terrain.get_node_by_position(cell.x, cell.y) {
+            Some(node) => terrain
+                .get_successors_for_clearance(&node, self.clearance)
+                .into_iter()
+                .map(|s| ivec2(s.node.x, s.node.y))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    fn path_cost(path: &[Node]) -> u32 {
+        path.windows(2)
+            .map(|pair| step_cost(&pair[0], &pair[1]))
+            .sum()
+    }
+
+    #[test]
+    fn test_dstar_lite_matches_astar() {
+        let mut terrain = PathfindingTerrain::new(20, 20, None);
+        for y in 0..15 {
+            terrain.set_node_type(10, y, NodeType::Unwalkable);
+        }
+        terrain.refresh();
+
+        let mut planner = DStarLite::new(ivec2(2, 2), ivec2(18, 2), 1);
+        let path = planner.path(&terrain).unwrap();
+        let start = terrain.get_node_by_position(2, 2).unwrap();
+        let goal = terrain.get_node_by_position(18, 2).unwrap();
+        let expected = terrain.get_path(start, goal).unwrap();
+
+        assert_eq!(path.first(), expected.first());
+        assert_eq!(path.last(), expected.last());
+        assert_eq!(path_cost(&path), path_cost(&expected));
+    }
+
+    #[test]
+    fn test_dstar_lite_repairs_after_blocking() {
+        let mut terrain = PathfindingTerrain::new(20, 20, None);
+        for y in 0..15 {
+            terrain.set_node_type(10, y, NodeType::Unwalkable);
+        }
+        terrain.refresh();
+
+        let mut planner = DStarLite::new(ivec2(2, 2), ivec2(18, 2), 1);
+        let first = planner.path(&terrain).unwrap();
+        assert!(first.iter().any(|n| n.x == 10 && n.y >= 15));
+
+        // the unit walked a bit, then the gap above the wall is closed except
+        // for one tile at the very top
+        let changed = (15..19).map(|y| ivec2(10, y)).collect::<Vec<_>>();
+        for cell in &changed {
+            terrain.set_node_type(cell.x, cell.y, NodeType::Unwalkable);
+        }
+        terrain.refresh();
+
+        let moved = first[3];
+        let repaired = planner
+            .repair(&terrain, ivec2(moved.x, moved.y), &changed)
+            .unwrap();
+        assert!(repaired
+            .iter()
+            .all(|n| n.node_type != Some(NodeType::Unwalkable)));
+        assert!(repaired.iter().any(|n| n.x == 10 && n.y == 19));
+
+        let start = terrain.get_node_by_position(moved.x, moved.y).unwrap();
+        let goal = terrain.get_node_by_position(18, 2).unwrap();
+        let expected = terrain.get_path(start, goal).unwrap();
+        assert_eq!(path_cost(&repaired), path_cost(&expected));
+
+        terrain.set_node_type(10, 19, NodeType::Unwalkable);
+        terrain.refresh();
+        assert!(planner
+            .repair(&terrain, ivec2(moved.x, moved.y), &[ivec2(10, 19)])
+            .is_none());
+    }
+}
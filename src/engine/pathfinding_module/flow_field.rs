@@ -0,0 +1,179 @@
+use std::{cmp::Reverse, collections::BinaryHeap};
+
+use comfy::*;
+
+use super::{step_cost, PathfindingTerrain};
+
+/// Integration field (cost to reach the goal from every node) and the
+/// direction field derived from it, shared by every unit heading to `goal`
+/// that fits through nodes of `clearance`.
+#[derive(Clone, Debug)]
+pub struct FlowField {
+    pub goal: IVec2,
+    pub clearance: i32,
+    pub width: i32,
+    pub height: i32,
+    pub integration: Vec<u32>,
+    pub directions: Vec<IVec2>,
+}
+
+impl FlowField {
+    pub fn new(terrain: &PathfindingTerrain, goal: IVec2, clearance: i32) -> Self {
+        let size = (terrain.width * terrain.height) as usize;
+        let mut integration = vec![u32::MAX; size];
+        let mut directions = vec![IVec2::ZERO; size];
+        let index = |x: i32, y: i32| (y * terrain.width + x) as usize;
+
+        // Dijkstra outwards from the goal, costs are the ones of stepping
+        // from the neighbour towards the node being expanded
+        let mut open = BinaryHeap::new();
+        if terrain.fits(goal.x, goal.y, clearance) {
+            integration[index(goal.x, goal.y)] = 0;
+            open.push(Reverse((0, goal.x, goal.y)));
+        }
+
+        while let Some(Reverse((cost, x, y))) = open.pop() {
+            if cost > integration[index(x, y)] {
+                continue;
+            }
+            let node = terrain.get_node_by_position(x, y).unwrap();
+            for successor in terrain.get_successors_for_clearance(&node, clearance) {
+                let neighbour = successor.node;
+                let new_cost = cost + step_cost(&neighbour, &node);
+                let i = index(neighbour.x, neighbour.y);
+                if new_cost < integration[i] {
+                    integration[i] = new_cost;
+                    open.push(Reverse((new_cost, neighbour.x, neighbour.y)));
+                }
+            }
+        }
+
+        for node in &terrain.nodes {
+            if integration[index(node.x, node.y)] == u32::MAX || ivec2(node.x, node.y) == goal {
+                continue;
+            }
+            let best = terrain
+                .get_successors_for_clearance(node, clearance)
+                .into_iter()
+                .filter(|s| integration[index(s.node.x, s.node.y)] != u32::MAX)
+                .min_by_key(|s| s.cost + integration[index(s.node.x, s.node.y)]);
+            if let Some(best) = best {
+                directions[index(node.x, node.y)] =
+                    ivec2(best.node.x - node.x, best.node.y - node.y);
+            }
+        }
+
+        Self {
+            goal,
+            clearance,
+            width: terrain.width,
+            height: terrain.height,
+            integration,
+            directions,
+        }
+    }
+
+    fn index(&self, x: i32, y: i32) -> Option<usize> {
+        if x < 0 || x >= self.width || y < 0 || y >= self.height {
+            return None;
+        }
+        Some((y * self.width + x) as usize)
+    }
+
+    /// Remaining cost to the goal, `None` when the goal can't be reached.
+    pub fn cost_at(&self, x: i32, y: i32) -> Option<u32> {
+        self.index(x, y)
+            .map(|i| self.integration[i])
+            .filter(|cost| *cost != u32::MAX)
+    }
+
+    /// Step towards the next node on the way to the goal, zero at the goal.
+    pub fn direction_at(&self, x: i32, y: i32) -> Option<IVec2> {
+        self.cost_at(x, y)?;
+        self.index(x, y).map(|i| self.directions[i])
+    }
+}
+
+/// Flow fields keyed by goal node and clearance, dropped when the terrain changes or when
+/// no unit is heading to the goal any more.
+#[derive(Default)]
+pub struct FlowFieldCache {
+    fields: HashMap<(IVec2, i32), FlowField>,
+    revision: u64,
+}
+
+impl FlowFieldCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_or_build(
+        &mut self,
+        terrain: &PathfindingTerrain,
+        goal: IVec2,
+        clearance: i32,
+    ) -> &FlowField {
+        if self.revision != terrain.revision() {
+            self.fields.clear();
+            self.revision = terrain.revision();
+        }
+        self.fields
+            .entry((goal, clearance))
+            .or_insert_with(|| FlowField::new(terrain, goal, clearance))
+    }
+
+    pub fn retain_goals(&mut self, goals: &HashSet<(IVec2, i32)>) {
+        self.fields.retain(|key, _| goals.contains(key));
+    }
+
+    pub fn len(&self) -> usize {
+        self.fields.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.fields.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn test_flow_field_leads_to_goal() {
+        let mut terrain = PathfindingTerrain::new(6, 6, None);
+        for y in 0..5 {
+            terrain.set_node_type(3, y, NodeType::Unwalkable);
+        }
+
+        let field = FlowField::new(&terrain, ivec2(5, 0), 1);
+        assert_eq!(field.direction_at(5, 0), Some(IVec2::ZERO));
+        assert_eq!(field.direction_at(3, 0), None);
+
+        // following the arrows from the far side goes around the wall
+        let mut position = ivec2(0, 0);
+        for _ in 0..20 {
+            position += field.direction_at(position.x, position.y).unwrap();
+            assert!(terrain.is_walkable(position.x, position.y));
+        }
+        assert_eq!(position, ivec2(5, 0));
+    }
+
+    #[test]
+    fn test_flow_field_cache_invalidation() {
+        let mut terrain = PathfindingTerrain::new(4, 4, None);
+        let mut cache = FlowFieldCache::new();
+
+        cache.get_or_build(&terrain, ivec2(3, 3), 1);
+        cache.get_or_build(&terrain, ivec2(0, 3), 1);
+        assert_eq!(cache.len(), 2);
+
+        cache.retain_goals(&HashSet::from([(ivec2(3, 3), 1)]));
+        assert_eq!(cache.len(), 1);
+
+        terrain.set_node_type(3, 2, NodeType::Unwalkable);
+        terrain.set_node_type(2, 3, NodeType::Unwalkable);
+        let field = cache.get_or_build(&terrain, ivec2(3, 3), 1);
+        assert_eq!(field.cost_at(0, 0), None);
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::BTreeMap;
+
+use comfy::*;
+use pathfinding::prelude::astar;
+
+use super::{step_cost, Node, PathfindingTerrain};
+
+pub const DEFAULT_CLUSTER_SIZE: i32 = 10;
+
+// border segments at least this long get an entrance at each end instead of
+// a single one in the middle
+const LONG_ENTRANCE_LENGTH: usize = 6;
+
+type Cell = (i32, i32);
+
+#[derive(Clone, Debug, Default)]
+struct Cluster {
+    entrances: Vec<Cell>,
+    // cached shortest paths between every ordered pair of entrances
+    paths: HashMap<(Cell, Cell), (Vec<Cell>, u32)>,
+}
+
+/// HPA* abstraction over a `PathfindingTerrain`: the grid is split into square
+/// clusters, walkable stretches of cluster borders become entrance nodes and
+/// the paths between entrances of the same cluster are cached.
+#[derive(Clone, Debug)]
+pub struct HierarchicalGraph {
+    pub cluster_size: i32,
+    width: i32,
+    height: i32,
+    clusters_x: i32,
+    clusters_y: i32,
+    clusters: Vec<Cluster>,
+    // entrance pairs straddling the border of two clusters, keyed by
+    // (lower cluster index, higher cluster index); ordered, so the links
+    // built from them are the same on every client
+    borders: BTreeMap<(usize, usize), Vec<(Cell, Cell)>>,
+    // entrances across a border from each entrance, sorted
+    links: HashMap<Cell, Vec<Cell>>,
+    dirty: HashSet<usize>,
+}
+
+impl HierarchicalGraph {
+    pub fn new(cluster_size: i32) -> Self {
+        Self {
+            cluster_size: cluster_size.max(1),
+            width: 0,
+            height: 0,
+            clusters_x: 0,
+            clusters_y: 0,
+            clusters: Vec::new(),
+            borders: BTreeMap::new(),
+            links: HashMap::new(),
+            dirty: HashSet::new(),
+        }
+    }
+
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    pub fn mark_dirty(&mut self, x: i32, y: i32) {
+        if x >= 0 && x < self.width && y >= 0 && y < self.height {
+            let index = self.cluster_index((x, y));
+            self.dirty.insert(index);
+        }
+    }
+
+    pub fn mark_all_dirty(&mut self) {
+        self.dirty.extend(0..self.clusters.len());
+        // forces `refresh` to re-layout the clusters for the new grid size
+        self.width = -1;
+    }
+
+    pub fn entrance_count(&self) -> usize {
+        self.clusters.iter().map(|c| c.entrances.len()).sum()
+    }
+
+    fn cluster_index(&self, cell: Cell) -> usize {
+        ((cell.1 / self.cluster_size) * self.clusters_x + cell.0 / self.cluster_size) as usize
+    }
+
+    // inclusive min and max corner of a cluster
+    fn cluster_bounds(&self, index: usize) -> (Cell, Cell) {
+        let cx = index as i32 % self.clusters_x;
+        let cy = index as i32 / self.clusters_x;
+        let min = (cx * self.cluster_size, cy * self.cluster_size);
+        let max = (
+            (min.0 + self.cluster_size).min(self.width) - 1,
+            (min.1 + self.cluster_size).min(self.height) - 1,
+        );
+        (min, max)
+    }
+
+    fn neighbours(&self, index: usize) -> Vec<usize> {
+        let cx = index as i32 % self.clusters_x;
+        let cy = index as i32 / self.clusters_x;
+        [(-1, 0), (1, 0), (0, -1), (0, 1)]
+            .iter()
+            .map(|(dx, dy)| (cx + dx, cy + dy))
+            .filter(|(x, y)| *x >= 0 && *x < self.clusters_x && *y >= 0 && *y < self.clusters_y)
+            .map(|(x, y)| (y * self.clusters_x + x) as usize)
+            .collect()
+    }
+
+    pub fn refresh(&mut self, terrain: &PathfindingTerrain) {
+        if terrain.width != self.width || terrain.height != self.height {
+            self.width = terrain.width;
+            self.height = terrain.height;
+            self.clusters_x = (terrain.width + self.cluster_size - 1) / self.cluster_size;
+            self.clusters_y = (terrain.height + self.cluster_size - 1) / self.cluster_size;
+            self.clusters = vec![Cluster::default(); (self.clusters_x * self.clusters_y) as usize];
+            self.borders.clear();
+            self.dirty = (0..self.clusters.len()).collect();
+        }
+
+        if self.dirty.is_empty() {
+            return;
+        }
+
+        let mut dirty = self.dirty.drain().collect::<Vec<_>>();
+        dirty.sort();
+
+        let mut touched = HashSet::new();
+        for index in dirty {
+            touched.insert(index);
+            for neighbour in self.neighbours(index) {
+                self.build_border(terrain, index.min(neighbour), index.max(neighbour));
+                touched.insert(neighbour);
+            }
+        }
+
+        for index in touched {
+            self.build_cluster(terrain, index);
+        }
+
+        self.links.clear();
+        for pairs in self.borders.values() {
+            for (a, b) in pairs {
+                self.links.entry(*a).or_default().push(*b);
+                self.links.entry(*b).or_default().push(*a);
+            }
+        }
+        for others in self.links.values_mut() {
+            others.sort();
+            others.dedup();
+        }
+    }
+
+    fn build_border(&mut self, terrain: &PathfindingTerrain, low: usize, high: usize) {
+        let (low_min, low_max) = self.cluster_bounds(low);
+        let same_row = low as i32 / self.clusters_x == high as i32 / self.clusters_x;
+
+        let crossings: Vec<(Cell, Cell)> = if same_row {
+            (low_min.1..=low_max.1)
+                .map(|y| ((low_max.0, y), (low_max.0 + 1, y)))
+                .collect()
+        } else {
+            (low_min.0..=low_max.0)
+                .map(|x| ((x, low_max.1), (x, low_max.1 + 1)))
+                .collect()
+        };
+
+        let mut pairs = Vec::new();
+        let mut segment: Vec<(Cell, Cell)> = Vec::new();
+        for crossing in crossings.into_iter().map(Some).chain(std::iter::once(None)) {
+            let open = crossing
+                .filter(|(a, b)| terrain.is_walkable(a.0, a.1) && terrain.is_walkable(b.0, b.1));
+            match open {
+                Some(crossing) => segment.push(crossing),
+                None if segment.is_empty() => {}
+                None => {
+                    if segment.len() >= LONG_ENTRANCE_LENGTH {
+                        pairs.push(segment[0]);
+                        pairs.push(segment[segment.len() - 1]);
+                    } else {
+                        pairs.push(segment[segment.len() / 2]);
+                    }
+                    segment.clear();
+                }
+            }
+        }
+
+        self.borders.insert((low, high), pairs);
+    }
+
+    fn build_cluster(&mut self, terrain: &PathfindingTerrain, index: usize) {
+        let mut entrances = Vec::new();
+        for neighbour in self.neighbours(index) {
+            let key = (index.min(neighbour), index.max(neighbour));
+            for (a, b) in self.borders.get(&key).into_iter().flatten() {
+                for cell in [a, b] {
+                    if self.cluster_index(*cell) == index && !entrances.contains(cell) {
+                        entrances.push(*cell);
+                    }
+                }
+            }
+        }
+        entrances.sort();
+
+        let bounds = self.cluster_bounds(index);
+        let mut paths = HashMap::new();
+        for (i, from) in entrances.iter().enumerate() {
+            for to in entrances.iter().skip(i + 1) {
+                if let Some((path, cost)) = local_path(terrain, bounds, *from, *to) {
+                    let reversed = path.iter().rev().copied().collect::<Vec<_>>();
+                    let reversed_cost = path_cost(terrain, &reversed);
+                    paths.insert((*from, *to), (path, cost));
+                    paths.insert((*to, *from), (reversed, reversed_cost));
+                }
+            }
+        }
+
+        self.clusters[index] = Cluster { entrances, paths };
+    }
+
+    pub fn find_path(
+        &self,
+        terrain: &PathfindingTerrain,
+        start: Node,
+        goal: Node,
+    ) -> Option<Vec<Node>> {
+        let start_cell = (start.x, start.y);
+        let goal_cell = (goal.x, goal.y);
+        let start_cluster = self.cluster_index(start_cell);
+        let goal_cluster = self.cluster_index(goal_cell);
+
+        if start_cluster == goal_cluster {
+            let bounds = self.cluster_bounds(start_cluster);
+            if let Some((path, _)) = local_path(terrain, bounds, start_cell, goal_cell) {
+                return Some(to_nodes(terrain, &path));
+            }
+        }
+
+        // temporary edges connecting start and goal to their cluster entrances
+        let start_bounds = self.cluster_bounds(start_cluster);
+        let start_links: HashMap<Cell, (Vec<Cell>, u32)> = self.clusters[start_cluster]
+            .entrances
+            .iter()
+            .filter_map(|e| Some((*e, local_path(terrain, start_bounds, start_cell, *e)?)))
+            .collect();
+        let goal_bounds = self.cluster_bounds(goal_cluster);
+        let goal_links: HashMap<Cell, (Vec<Cell>, u32)> = self.clusters[goal_cluster]
+            .entrances
+            .iter()
+            .filter_map(|e| Some((*e, local_path(terrain, goal_bounds, *e, goal_cell)?)))
+            .collect();
+
+        let successors = |cell: &Cell| {
+            let mut result = Vec::new();
+            if *cell == start_cell {
+                // in entrance order, map order would make ties differ between runs
+                result.extend(
+                    self.clusters[start_cluster]
+                        .entrances
+                        .iter()
+                        .filter_map(|e| Some((*e, start_links.get(e)?.1))),
+                );
+            }
+            if let Some((_, cost)) = goal_links.get(cell) {
+                result.push((goal_cell, *cost));
+            }
+            let cluster = &self.clusters[self.cluster_index(*cell)];
+            if cluster.entrances.contains(cell) {
+                for other in &cluster.entrances {
+                    if let Some((_, cost)) = cluster.paths.get(&(*cell, *other)) {
+                        result.push((*other, *cost));
+                    }
+                }
+                for other in self.links.get(cell).into_iter().flatten() {
+                    result.push((*other, cell_step_cost(terrain, *cell, *other)));
+                }
+            }
+            result
+        };
+
+        let (abstract_path, _) = astar(
+            &start_cell,
+            successors,
+            |cell| {
+                let node = terrain.get_node_by_position(cell.0, cell.1).unwrap();
+                terrain.heuristic(&node, &goal)
+            },
+            |cell| *cell == goal_cell,
+        )?;
+
+        // refine every abstract edge back into grid cells, picking the cheapest
+        // of the edges that could have connected the two cells
+        let mut cells = vec![start_cell];
+        for pair in abstract_path.windows(2) {
+            let (from, to) = (pair[0], pair[1]);
+            let cluster = &self.clusters[self.cluster_index(from)];
+            let candidates = [
+                start_links.get(&to).filter(|_| from == start_cell),
+                goal_links.get(&from).filter(|_| to == goal_cell),
+                cluster.paths.get(&(from, to)),
+            ];
+            let segment = match candidates
+                .into_iter()
+                .flatten()
+                .min_by_key(|(_, cost)| *cost)
+            {
+                Some((path, _)) => path.clone(),
+                None => vec![from, to],
+            };
+            cells.extend(segment.into_iter().skip(1));
+        }
+
+        Some(to_nodes(terrain, &cells))
+    }
+}
+
+fn cell_step_cost(terrain: &PathfindingTerrain, from: Cell, to: Cell) -> u32 {
+    let from = terrain.get_node_by_position(from.0, from.1).unwrap();
+    let to = terrain.get_node_by_position(to.0, to.1).unwrap();
+    step_cost(&from, &to)
+}
+
+fn path_cost(terrain: &PathfindingTerrain, path: &[Cell]) -> u32 {
+    path.windows(2)
+        .map(|pair| cell_step_cost(terrain, pair[0], pair[1]))
+        .sum()
+}
+
+fn to_nodes(terrain: &PathfindingTerrain, cells: &[Cell]) -> Vec<Node> {
+    cells
+        .iter()
+        .filter_map(|(x, y)| terrain.get_node_by_position(*x, *y))
+        .collect()
+}
+
+// A* that never leaves the inclusive `bounds` rectangle
+fn local_path(
+    terrain: &PathfindingTerrain,
+    bounds: (Cell, Cell),
+    from: Cell,
+    to: Cell,
+) -> Option<(Vec<Cell>, u32)> {
+    let (min, max) = bounds;
+    let goal = terrain.get_node_by_position(to.0, to.1)?;
+    let start = terrain.get_node_by_position(from.0, from.1)?;
+
+    astar(
+        &start,
+        |node| {
+            terrain
+                .get_successors(node)
+                .into_iter()
+                .filter(|s| {
+                    s.node.x >= min.0 && s.node.x <= max.0 && s.node.y >= min.1 && s.node.y <= max.1
+                })
+                .map(|s| (s.node, s.cost))
+                .collect::<Vec<_>>()
+        },
+        |node| terrain.heuristic(node, &goal),
+        |node| node.x == goal.x && node.y == goal.y,
+    )
+    .map(|(path, cost)| (path.iter().map(|n| (n.x, n.y)).collect(), cost))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    fn walled_terrain() -> PathfindingTerrain {
+        // a wall across the middle with a single gap near the top
+        let mut terrain = PathfindingTerrain::new(30, 30, None);
+        for y in 0..28 {
+            terrain.set_node_type(15, y, NodeType::Unwalkable);
+        }
+        terrain
+    }
+
+    #[test]
+    fn test_hierarchical_path_matches_flat_reachability() {
+        let mut terrain = walled_terrain();
+        terrain.enable_hierarchy(DEFAULT_CLUSTER_SIZE);
+
+        let start = terrain.get_node_by_position(2, 2).unwrap();
+        let goal = terrain.get_node_by_position(27, 3).unwrap();
+
+        let flat = terrain.get_path(start, goal).unwrap();
+        let hierarchical = terrain.get_hierarchical_path(start, goal).unwrap();
+
+        assert_eq!(hierarchical.first(), Some(&start));
+        assert_eq!(hierarchical.last(), Some(&goal));
+        assert!(hierarchical
+            .iter()
+            .all(|n| n.node_type != Some(NodeType::Unwalkable)));
+        for pair in hierarchical.windows(2) {
+            assert!((pair[0].x - pair[1].x).abs() <= 1 && (pair[0].y - pair[1].y).abs() <= 1);
+        }
+        // HPA* is near-optimal, not optimal
+        assert!(hierarchical.len() <= flat.len() * 3 / 2);
+    }
+
+    #[test]
+    fn test_hierarchy_refreshes_after_set_node_type() {
+        let mut terrain = walled_terrain();
+        terrain.enable_hierarchy(DEFAULT_CLUSTER_SIZE);
+
+        let start = terrain.get_node_by_position(2, 2).unwrap();
+        let goal = terrain.get_node_by_position(27, 3).unwrap();
+
+        terrain.set_node_type(15, 28, NodeType::Unwalkable);
+        terrain.set_node_type(15, 29, NodeType::Unwalkable);
+        terrain.refresh_hierarchy();
+        assert!(terrain.get_hierarchical_path(start, goal).is_none());
+
+        terrain.set_node_type(15, 5, NodeType::Walkable);
+        terrain.refresh_hierarchy();
+        let path = terrain.get_hierarchical_path(start, goal).unwrap();
+        assert!(path.iter().any(|n| n.x == 15 && n.y == 5));
+    }
+
+    #[test]
+    fn test_separately_built_graphs_give_the_same_paths() {
+        // open ground with scattered walls, full of equally short paths
+        let mut terrain = PathfindingTerrain::new(40, 40, None);
+        for i in 0..12 {
+            terrain.set_node_type(i * 3 + 2, (i * 7) % 40, NodeType::Unwalkable);
+        }
+        let graphs = (0..4)
+            .map(|_| {
+                let mut terrain = terrain.clone();
+                terrain.enable_hierarchy(DEFAULT_CLUSTER_SIZE);
+                terrain
+            })
+            .collect::<Vec<_>>();
+
+        for (from, to) in [((1, 1), (38, 38)), ((2, 37), (37, 3)), ((0, 20), (39, 21))] {
+            let start = terrain.get_node_by_position(from.0, from.1).unwrap();
+            let goal = terrain.get_node_by_position(to.0, to.1).unwrap();
+            let first = graphs[0].get_hierarchical_path(start, goal).unwrap();
+            for other in &graphs[1..] {
+                assert_eq!(other.get_hierarchical_path(start, goal).unwrap(), first);
+            }
+        }
+    }
+}
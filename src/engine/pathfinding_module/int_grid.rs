@@ -0,0 +1,125 @@
+use comfy::*;
+
+use super::{terrain_cost_for_tag, NodeType, PathfindingTerrain, DEFAULT_TERRAIN_COST};
+
+/// What painting an IntGrid value does to the nodes under it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IntGridRule {
+    pub node_type: NodeType,
+    pub cost: u32,
+}
+
+/// Maps LDtk IntGrid values to node types and costs. Values are looked up
+/// by their identifier in the project, or by their number (e.g. `"1"`) when
+/// they have none.
+#[derive(Clone, Debug)]
+pub struct IntGridTable {
+    rules: HashMap<String, IntGridRule>,
+}
+
+impl IntGridTable {
+    /// A table without any rules, every value is ignored.
+    pub fn empty() -> Self {
+        Self {
+            rules: HashMap::new(),
+        }
+    }
+
+    pub fn set(&mut self, identifier: &str, node_type: NodeType, cost: u32) {
+        self.rules
+            .insert(identifier.to_string(), IntGridRule { node_type, cost });
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<IntGridRule> {
+        self.rules.get(identifier).copied()
+    }
+}
+
+impl Default for IntGridTable {
+    /// Collision and water block, ground is plain walkable and the terrain
+    /// tags (`Road`, `Forest`, ...) get their usual cost.
+    fn default() -> Self {
+        let mut table = Self::empty();
+        for blocking in ["Wall", "Collision", "Water"] {
+            table.set(blocking, NodeType::Unwalkable, DEFAULT_TERRAIN_COST);
+        }
+        table.set("Ground", NodeType::Walkable, DEFAULT_TERRAIN_COST);
+        for tag in ["Road", "Forest", "Mud", "ShallowWater"] {
+            table.set(tag, NodeType::Walkable, terrain_cost_for_tag(tag).unwrap());
+        }
+        table
+    }
+}
+
+impl PathfindingTerrain {
+    /// Applies an IntGrid layer, `csv` holds one value per cell row by row
+    /// from the top like LDtk stores it, 0 meaning empty.
+    pub(super) fn apply_int_grid(
+        &mut self,
+        csv: &[i64],
+        columns: i64,
+        values: &[IntGridValueDefinition],
+    ) {
+        if columns <= 0 {
+            return;
+        }
+        let rows = csv.len() as i64 / columns;
+
+        for (i, value) in csv.iter().enumerate().filter(|(_, value)| **value != 0) {
+            let Some(definition) = values.iter().find(|d| d.value == *value) else {
+                continue;
+            };
+            let key = definition
+                .identifier
+                .clone()
+                .unwrap_or_else(|| value.to_string());
+            let Some(rule) = self.int_grid_table.get(&key) else {
+                continue;
+            };
+
+            let x = (i as i64 % columns) as i32;
+            let y = (rows - i as i64 / columns - 1) as i32;
+            if self.get_node_by_position(x, y).is_some() {
+                self.set_node_type(x, y, rule.node_type);
+                self.set_node_cost(x, y, rule.cost);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    fn value(value: i64, identifier: Option<&str>) -> IntGridValueDefinition {
+        IntGridValueDefinition {
+            color: "#000000".to_string(),
+            group_uid: 0,
+            identifier: identifier.map(str::to_string),
+            tile: None,
+            value,
+        }
+    }
+
+    #[test]
+    fn test_int_grid_values_use_the_table() {
+        let mut terrain = PathfindingTerrain::new(3, 2, None);
+        terrain.int_grid_table.set("3", NodeType::Walkable, 250);
+        let values = [
+            value(1, Some("Wall")),
+            value(2, Some("Road")),
+            value(3, None),
+        ];
+
+        // top row first, as in the LDtk file
+        terrain.apply_int_grid(&[1, 0, 2, 0, 3, 4], 3, &values);
+
+        let node = |x, y| terrain.get_node_by_position(x, y).unwrap();
+        assert_eq!(node(0, 1).node_type, Some(NodeType::Unwalkable));
+        assert_eq!(node(2, 1).cost, 50);
+        assert_eq!(node(1, 0).cost, 250);
+        // 4 isn't defined in the project
+        assert_eq!(node(2, 0).node_type, Some(NodeType::Walkable));
+        assert_eq!(node(2, 0).cost, DEFAULT_TERRAIN_COST);
+    }
+}
@@ -1,8 +1,30 @@
-#[allow(dead_code)]
 use comfy::*;
 use pathfinding::prelude::astar;
-
-use super::PATHFINDING_Z_INDEX;
+use serde::{Deserialize, Serialize};
+
+use super::{level_module::level_origin, PATHFINDING_Z_INDEX};
+
+mod clearance;
+mod dstar_lite;
+mod flow_field;
+mod hierarchical;
+mod int_grid;
+mod requests;
+mod smoothing;
+
+pub use clearance::required_clearance;
+pub use dstar_lite::DStarLite;
+pub use flow_field::{FlowField, FlowFieldCache};
+pub use hierarchical::{HierarchicalGraph, DEFAULT_CLUSTER_SIZE};
+pub use int_grid::{IntGridRule, IntGridTable};
+pub use requests::{
+    PathRequest, PathRequestHandle, PathRequestQueue, PathResult, DEFAULT_MAX_DISPATCH_PER_FRAME,
+    DEFAULT_MAX_RESULTS_PER_FRAME,
+};
+pub use smoothing::{
+    catmull_rom, has_line_of_sight, has_line_of_sight_with_clearance, smooth_path, string_pull,
+    PathSmoothing,
+};
 
 #[allow(dead_code)]
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
@@ -16,26 +38,94 @@ pub enum NodeType {
     Neighbor,
 }
 
+/// Cost of a straight step between two tiles.
+pub const ORTHOGONAL_COST: u32 = 100;
+/// Cost of a diagonal step, `ORTHOGONAL_COST * √2` rounded.
+pub const DIAGONAL_COST: u32 = 141;
+
+/// Movement cost multiplier, in percent, of an ordinary walkable tile.
+pub const DEFAULT_TERRAIN_COST: u32 = 100;
+/// Highest movement cost multiplier a tile can have, which keeps the summed
+/// costs of long paths far from overflowing.
+pub const MAX_TERRAIN_COST: u32 = 10_000;
+
+/// Movement cost multiplier for the `TERRAIN` enum values that only change
+/// how expensive a tile is to cross, not whether it can be crossed.
+pub fn terrain_cost_for_tag(tag: &str) -> Option<u32> {
+    match tag {
+        "Road" => Some(50),
+        "Forest" => Some(200),
+        "Mud" => Some(300),
+        "ShallowWater" => Some(400),
+        _ => None,
+    }
+}
+
+// tile custom data is expected to be a JSON object such as `{ "cost": 250 }`,
+// costs above `MAX_TERRAIN_COST` are clamped to it
+fn terrain_cost_from_custom_data(data: &str) -> Option<u32> {
+    let value: serde_json::Value = serde_json::from_str(data).ok()?;
+    let cost = value.get("cost")?.as_u64()?;
+    Some(cost.min(MAX_TERRAIN_COST as u64) as u32)
+}
+
+/// Which neighbouring tiles `get_successors` is allowed to step to.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub enum Neighborhood {
+    /// Only the four orthogonal neighbours.
+    Four,
+    /// All eight neighbours, diagonals may squeeze past `Unwalkable` corners.
+    Eight,
+    /// All eight neighbours, but a diagonal step is only taken when both
+    /// orthogonal tiles it passes between are walkable.
+    #[default]
+    EightNoCornerCutting,
+}
+
+impl Neighborhood {
+    pub fn next(self) -> Self {
+        match self {
+            Neighborhood::Four => Neighborhood::Eight,
+            Neighborhood::Eight => Neighborhood::EightNoCornerCutting,
+            Neighborhood::EightNoCornerCutting => Neighborhood::Four,
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
 pub struct Node {
     pub x: i32,
     pub y: i32,
     pub node_type: Option<NodeType>,
+    /// Percentage of the base step cost paid for entering this node.
+    pub cost: u32,
 }
 
 impl Node {
-    fn distance(&self, other: &Node) -> f32 {
-        let dx = (self.x - other.x) as f32;
-        let dy = (self.y - other.y) as f32;
-        let d = (dx * dx + dy * dy).sqrt();
-        d
-    }
-
     fn manhattan_distance(&self, other: &Node) -> f32 {
         let dx = (self.x - other.x).abs() as f32;
         let dy = (self.y - other.y).abs() as f32;
         dx + dy
     }
+
+    // exact cost of the shortest 8-way route on an empty grid
+    fn octile_distance(&self, other: &Node) -> u32 {
+        let dx = (self.x - other.x).unsigned_abs();
+        let dy = (self.y - other.y).unsigned_abs();
+        let diagonal = dx.min(dy);
+        let straight = dx.max(dy) - diagonal;
+        diagonal * DIAGONAL_COST + straight * ORTHOGONAL_COST
+    }
+}
+
+// cost of moving from `from` onto the adjacent node `to`
+fn step_cost(from: &Node, to: &Node) -> u32 {
+    let step = if from.x != to.x && from.y != to.y {
+        DIAGONAL_COST
+    } else {
+        ORTHOGONAL_COST
+    };
+    step * to.cost / DEFAULT_TERRAIN_COST
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, PartialOrd)]
@@ -50,11 +140,25 @@ impl PartialEq<(Node, u32)> for Successor {
     }
 }
 
+#[derive(Clone)]
 pub struct PathfindingTerrain {
     pub width: i32,
     pub height: i32,
     pub nodes: Vec<Node>,
     pub offset: Option<Vec2>,
+    // changed through `set_neighborhood`, which tells the caches
+    neighborhood: Neighborhood,
+    /// Used for IntGrid layers by `set_pathfinding_based_on_level`.
+    pub int_grid_table: IntGridTable,
+    // cheapest cost on the grid, keeps the heuristic admissible
+    min_cost: u32,
+    hierarchy: Option<HierarchicalGraph>,
+    // bumped on every walkability or cost change so caches can tell they are stale
+    revision: u64,
+    clearance: Vec<i32>,
+    clearance_revision: Option<u64>,
+    // nodes edited since the last `take_changes`, for incremental replanners
+    changes: Vec<IVec2>,
 }
 
 impl PathfindingTerrain {
@@ -66,6 +170,7 @@ impl PathfindingTerrain {
                     x,
                     y,
                     node_type: Some(NodeType::Walkable),
+                    cost: DEFAULT_TERRAIN_COST,
                 });
             }
         }
@@ -74,12 +179,21 @@ impl PathfindingTerrain {
             height,
             nodes,
             offset,
+            neighborhood: Neighborhood::default(),
+            int_grid_table: IntGridTable::default(),
+            min_cost: DEFAULT_TERRAIN_COST,
+            hierarchy: None,
+            revision: 0,
+            clearance: Vec::new(),
+            clearance_revision: None,
+            changes: Vec::new(),
         }
     }
 
     fn resize(&mut self, width: i32, height: i32) {
         self.width = width;
         self.height = height;
+        self.min_cost = DEFAULT_TERRAIN_COST;
         self.nodes.clear();
         for y in 0..height {
             for x in 0..width {
@@ -87,14 +201,21 @@ impl PathfindingTerrain {
                     x,
                     y,
                     node_type: Some(NodeType::Empty),
+                    cost: DEFAULT_TERRAIN_COST,
                 });
             }
         }
+        self.revision += 1;
+        self.changes.clear();
+        if let Some(hierarchy) = &mut self.hierarchy {
+            hierarchy.mark_all_dirty();
+        }
     }
 
-    fn fill(&mut self, node_type: NodeType) {
-        for node in &mut self.nodes {
-            node.node_type = Some(node_type);
+    fn is_walkable(&self, x: i32, y: i32) -> bool {
+        match self.get_node_by_position(x, y) {
+            Some(n) => n.node_type != Some(NodeType::Unwalkable),
+            None => false,
         }
     }
 
@@ -103,22 +224,32 @@ impl PathfindingTerrain {
         let mut successors = Vec::new();
         for dx in -1i32..=1i32 {
             for dy in -1i32..=1i32 {
-                if (dx + dy).abs() != 1 {
+                if dx == 0 && dy == 0 {
                     continue;
                 }
 
+                let diagonal = dx != 0 && dy != 0;
+                match self.neighborhood {
+                    Neighborhood::Four if diagonal => continue,
+                    Neighborhood::EightNoCornerCutting
+                        if diagonal
+                            && (!self.is_walkable(node.x + dx, node.y)
+                                || !self.is_walkable(node.x, node.y + dy)) =>
+                    {
+                        continue
+                    }
+                    _ => {}
+                }
+
                 let new_x = node.x + dx;
                 let new_y = node.y + dy;
 
-                if new_x < 0 || new_x >= self.width || new_y < 0 || new_y >= self.height {
-                    continue;
-                }
-
                 match self.get_node_by_position(new_x, new_y) {
                     Some(n) => match n.node_type {
                         Some(NodeType::Unwalkable) => continue,
                         _ => {
-                            successors.push(Successor { node: n, cost: 1 });
+                            let cost = step_cost(node, &n);
+                            successors.push(Successor { node: n, cost });
                         }
                     },
                     None => continue,
@@ -129,9 +260,101 @@ impl PathfindingTerrain {
         successors
     }
 
+    fn heuristic(&self, node: &Node, goal: &Node) -> u32 {
+        let distance = match self.neighborhood {
+            Neighborhood::Four => node.manhattan_distance(goal) as u32 * ORTHOGONAL_COST,
+            Neighborhood::Eight | Neighborhood::EightNoCornerCutting => node.octile_distance(goal),
+        };
+        distance * self.min_cost / DEFAULT_TERRAIN_COST
+    }
+
+    pub fn neighborhood(&self) -> Neighborhood {
+        self.neighborhood
+    }
+
+    /// Changes which neighbours paths step to. Every path and flow field
+    /// depends on it, so caches see the whole grid as changed.
+    pub fn set_neighborhood(&mut self, neighborhood: Neighborhood) {
+        if self.neighborhood == neighborhood {
+            return;
+        }
+        self.neighborhood = neighborhood;
+        self.revision += 1;
+        if let Some(hierarchy) = &mut self.hierarchy {
+            hierarchy.mark_all_dirty();
+        }
+    }
+
     pub fn set_node_type(&mut self, x: i32, y: i32, node_type: NodeType) {
         let node = &mut self.nodes[(y * self.width + x) as usize];
+        let was_walkable = node.node_type != Some(NodeType::Unwalkable);
         node.node_type = Some(node_type);
+
+        if was_walkable != (node_type != NodeType::Unwalkable) {
+            self.revision += 1;
+            self.changes.push(ivec2(x, y));
+            if let Some(hierarchy) = &mut self.hierarchy {
+                hierarchy.mark_dirty(x, y);
+            }
+        }
+    }
+
+    pub fn set_node_cost(&mut self, x: i32, y: i32, cost: u32) {
+        let cost = cost.min(MAX_TERRAIN_COST);
+        let node = &mut self.nodes[(y * self.width + x) as usize];
+        let changed = node.cost != cost;
+        node.cost = cost;
+        self.min_cost = self.min_cost.min(cost);
+
+        if changed {
+            self.revision += 1;
+            self.changes.push(ivec2(x, y));
+            if let Some(hierarchy) = &mut self.hierarchy {
+                hierarchy.mark_dirty(x, y);
+            }
+        }
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Copies the node types and costs of `other` through `set_node_type` and
+    /// `set_node_cost`, so only the nodes that differ are reported as
+    /// changes. Returns `false` without touching anything when the grids
+    /// have different sizes or positions.
+    pub fn update_from(&mut self, other: &PathfindingTerrain) -> bool {
+        if (self.width, self.height, self.offset) != (other.width, other.height, other.offset) {
+            return false;
+        }
+        for node in &other.nodes {
+            if let Some(node_type) = node.node_type {
+                self.set_node_type(node.x, node.y, node_type);
+            }
+            self.set_node_cost(node.x, node.y, node.cost);
+        }
+        true
+    }
+
+    /// Nodes whose walkability or cost changed since the previous call.
+    pub fn take_changes(&mut self) -> Vec<IVec2> {
+        std::mem::take(&mut self.changes)
+    }
+
+    /// World position of node `(0, 0)`.
+    pub fn origin(&self) -> Vec2 {
+        self.offset.unwrap_or(Vec2::ZERO)
+    }
+
+    /// World position of the centre of a node.
+    pub fn grid_to_world(&self, position: IVec2) -> Vec2 {
+        position.as_vec2() + self.origin()
+    }
+
+    /// Node under a world position, every node spans half a tile around its
+    /// centre. May be outside the grid.
+    pub fn world_to_grid(&self, position: Vec2) -> IVec2 {
+        (position - self.origin()).round().as_ivec2()
     }
 
     pub fn get_node_by_position(&self, x: i32, y: i32) -> Option<Node> {
@@ -150,13 +373,42 @@ impl PathfindingTerrain {
                     .map(|s| (s.node, s.cost))
                     .collect::<Vec<_>>()
             },
-            |node| node.manhattan_distance(&goal) as u32,
+            |node| self.heuristic(node, &goal),
             |node| *node == goal,
         );
 
-        match result {
-            Some((path, _cost)) => Some(path),
-            None => None,
+        result.map(|(path, _cost)| path)
+    }
+
+    /// Rebuilds the data derived from the grid (clearance map, HPA* clusters)
+    /// after `set_node_type`/`set_node_cost` edits.
+    pub fn refresh(&mut self) {
+        self.refresh_clearance();
+        self.refresh_hierarchy();
+    }
+
+    /// Builds the HPA* abstraction over the grid, see `get_hierarchical_path`.
+    pub fn enable_hierarchy(&mut self, cluster_size: i32) {
+        let mut hierarchy = HierarchicalGraph::new(cluster_size);
+        hierarchy.refresh(self);
+        self.hierarchy = Some(hierarchy);
+    }
+
+    /// Rebuilds the clusters touched by `set_node_type`/`set_node_cost` since
+    /// the last refresh.
+    pub fn refresh_hierarchy(&mut self) {
+        if let Some(mut hierarchy) = self.hierarchy.take() {
+            hierarchy.refresh(self);
+            self.hierarchy = Some(hierarchy);
+        }
+    }
+
+    /// Near-optimal path through the cluster graph. Falls back to the flat
+    /// `get_path` when the hierarchy is disabled or has unrefreshed changes.
+    pub fn get_hierarchical_path(&self, start: Node, goal: Node) -> Option<Vec<Node>> {
+        match &self.hierarchy {
+            Some(hierarchy) if !hierarchy.is_dirty() => hierarchy.find_path(self, start, goal),
+            _ => self.get_path(start, goal),
         }
     }
 
@@ -168,68 +420,109 @@ impl PathfindingTerrain {
         }
     }
 
-    pub fn set_pathfinding_based_on_tilemap(&mut self, level_map: &LdtkWorldMap) {
-        let map = level_map;
-        let map_json = &map.json;
-        let level = &map_json.levels[1];
-        for (_, layer) in level
+    /// Rebuilds the grid from the terrain layers of an LDtk level.
+    ///
+    /// Layers are composited bottom to top, in the order of the LDtk layer
+    /// list: a layer only overrides the nodes it paints and keeps everything
+    /// below elsewhere. A ground layer can mark the whole map walkable, a
+    /// cliff layer above it block parts of it and a bridge layer on top open
+    /// a crossing again. Within one layer the tile enum tags apply first,
+    /// then tile custom data costs, then IntGrid values.
+    ///
+    /// Layers without terrain information (entities) are skipped, as are
+    /// layers whose grid size differs from the bottom terrain layer.
+    pub fn set_pathfinding_based_on_level(&mut self, map_json: &LdtkJson, level: &Level) {
+        let layers = level
             .layer_instances
-            .as_ref()
-            .unwrap()
             .iter()
+            .flatten()
             .rev()
-            .enumerate()
-        {
-            let grid_size = layer.grid_size;
-            let width = level.px_wid / grid_size;
-            let height = level.px_hei / grid_size;
+            .filter_map(|layer| {
+                let tileset = layer
+                    .tileset_def_uid
+                    .and_then(|uid| map_json.defs.tilesets.iter().find(|t| t.uid == uid));
+                let int_grid_values = map_json
+                    .defs
+                    .layers
+                    .iter()
+                    .find(|d| d.uid == layer.layer_def_uid)
+                    .map(|d| &d.int_grid_values)
+                    .filter(|_| layer.layer_instance_type == "IntGrid");
+                if tileset.is_none() && int_grid_values.is_none() {
+                    return None;
+                }
+                Some((layer, tileset, int_grid_values))
+            })
+            .collect::<Vec<_>>();
+
+        let Some(grid_size) = layers.first().map(|(layer, _, _)| layer.grid_size) else {
+            warn!("Level {} has no terrain layers", level.identifier);
+            return;
+        };
+        self.resize(
+            (level.px_wid / grid_size) as i32,
+            (level.px_hei / grid_size) as i32,
+        );
+        self.offset = Some(level_origin(level, grid_size));
 
-            self.resize(width as i32, height as i32);
+        for (layer, tileset, int_grid_values) in layers {
+            if layer.grid_size != grid_size {
+                warn!(
+                    "Skipping layer {}: grid size {} differs from {}",
+                    layer.identifier, layer.grid_size, grid_size
+                );
+                continue;
+            }
+            if let Some(tileset) = tileset {
+                self.apply_tile_layer(layer, tileset);
+            }
+            if let Some(values) = int_grid_values {
+                self.apply_int_grid(&layer.int_grid_csv, layer.c_wid, values);
+            }
+        }
+    }
 
-            let tileset = layer
-                .tileset_def_uid
-                .and_then(|uid| map_json.defs.tilesets.iter().find(|t| t.uid == uid));
+    fn apply_tile_layer(&mut self, layer: &LayerInstance, tileset: &TilesetDefinition) {
+        let mut tags = HashMap::new();
+        for tag in &tileset.enum_tags {
+            for tile_id in &tag.tile_ids {
+                tags.insert(*tile_id, tag.enum_value_id.as_str());
+            }
+        }
+        let costs = tileset
+            .custom_data
+            .iter()
+            .filter_map(|meta| Some((meta.tile_id, terrain_cost_from_custom_data(&meta.data)?)))
+            .collect::<HashMap<_, _>>();
+
+        // auto-layer tiles are tagged like hand placed ones
+        for tile in layer.grid_tiles.iter().chain(layer.auto_layer_tiles.iter()) {
+            let pos = tile.to_world(layer);
+            let Some(node) = self.get_node_by_position(pos.x as i32, pos.y as i32) else {
+                continue;
+            };
 
-            if let Some(tileset) = tileset {
-                tileset.enum_tags.iter().for_each(|tag| {
-                    layer.grid_tiles.iter().for_each(|tile| {
-                        let pos = tile.to_world(layer);
-                        let x = pos.x as i32;
-                        let y = pos.y as i32;
-                        let node = self.get_node_by_position(x, y);
-                        tag.tile_ids.iter().for_each(|tile_id| {
-                            if tile.t == *tile_id {
-                                match tag.enum_value_id.as_str() {
-                                    "Start" => {
-                                        if let Some(node) = node {
-                                            self.set_node_type(node.x, node.y, NodeType::Start);
-                                        }
-                                    }
-                                    "End" => {
-                                        if let Some(node) = node {
-                                            self.set_node_type(node.x, node.y, NodeType::End);
-                                        }
-                                    }
-                                    "Unwalkable" => {
-                                        if let Some(node) = node {
-                                            self.set_node_type(
-                                                node.x,
-                                                node.y,
-                                                NodeType::Unwalkable,
-                                            );
-                                        }
-                                    }
-                                    "Walkable" => {
-                                        if let Some(node) = node {
-                                            self.set_node_type(node.x, node.y, NodeType::Walkable);
-                                        }
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        });
-                    });
-                });
+            match tags.get(&tile.t).copied() {
+                Some("Start") => self.set_node_type(node.x, node.y, NodeType::Start),
+                Some("End") => self.set_node_type(node.x, node.y, NodeType::End),
+                Some("Unwalkable") => self.set_node_type(node.x, node.y, NodeType::Unwalkable),
+                // plain walkable tiles also clear the cost of the layers below
+                Some("Walkable") => {
+                    self.set_node_type(node.x, node.y, NodeType::Walkable);
+                    self.set_node_cost(node.x, node.y, DEFAULT_TERRAIN_COST);
+                }
+                Some(other) => {
+                    if let Some(cost) = terrain_cost_for_tag(other) {
+                        self.set_node_type(node.x, node.y, NodeType::Walkable);
+                        self.set_node_cost(node.x, node.y, cost);
+                    }
+                }
+                None => {}
+            }
+
+            // custom data takes precedence over the enum tag costs
+            if let Some(cost) = costs.get(&tile.t) {
+                self.set_node_cost(node.x, node.y, *cost);
             }
         }
     }
@@ -238,6 +531,10 @@ impl PathfindingTerrain {
         for node in &self.nodes {
             let color = match node.node_type {
                 Some(NodeType::Empty) => BLACK,
+                Some(NodeType::Walkable) if node.cost != DEFAULT_TERRAIN_COST => {
+                    let weight = node.cost as f32 / (node.cost + DEFAULT_TERRAIN_COST) as f32;
+                    Color::new(1.0, 0.6, 0.0, 0.3 * weight)
+                }
                 Some(NodeType::Walkable) => Color::new(0.0, 0.0, 0.0, 0.0),
                 Some(NodeType::Unwalkable) => Color::new(255.0, 0.0, 0.0, 0.1),
                 Some(NodeType::Path) => Color::new(0.0, 0.0, 255.0, 0.2),
@@ -247,25 +544,12 @@ impl PathfindingTerrain {
                 None => GRAY,
             };
 
-            if let Some(offset) = self.offset {
-                draw_rect(
-                    vec2(node.x as f32, node.y as f32) + offset,
-                    vec2(1.0, 1.0),
-                    color,
-                    PATHFINDING_Z_INDEX,
-                );
-            } else {
-                draw_rect(
-                    vec2(node.x as f32, node.y as f32),
-                    vec2(1.0, 1.0),
-                    color,
-                    PATHFINDING_Z_INDEX,
-                );
-            }
+            let position = self.grid_to_world(ivec2(node.x, node.y));
+            draw_rect(position, vec2(1.0, 1.0), color, PATHFINDING_Z_INDEX);
             if display_coords {
                 draw_text(
                     &format!("[{},{}]", node.x, node.y),
-                    vec2(node.x as f32, node.y as f32),
+                    position,
                     WHITE,
                     TextAlign::Center,
                 );
@@ -295,4 +579,133 @@ mod tests {
 
         assert!(path.is_some());
     }
+
+    fn path_between(terrain: &PathfindingTerrain, from: (i32, i32), to: (i32, i32)) -> Vec<Node> {
+        let start = terrain.get_node_by_position(from.0, from.1).unwrap();
+        let end = terrain.get_node_by_position(to.0, to.1).unwrap();
+        terrain.get_path(start, end).unwrap()
+    }
+
+    #[test]
+    fn test_neighborhood_diagonal_paths() {
+        let mut terrain = PathfindingTerrain::new(5, 5, None);
+
+        terrain.set_neighborhood(Neighborhood::Four);
+        assert_eq!(path_between(&terrain, (0, 0), (4, 4)).len(), 9);
+
+        terrain.set_neighborhood(Neighborhood::Eight);
+        assert_eq!(path_between(&terrain, (0, 0), (4, 4)).len(), 5);
+    }
+
+    #[test]
+    fn test_no_corner_cutting() {
+        let mut terrain = PathfindingTerrain::new(2, 2, None);
+        terrain.set_node_type(1, 0, NodeType::Unwalkable);
+
+        terrain.set_neighborhood(Neighborhood::Eight);
+        assert_eq!(path_between(&terrain, (0, 0), (1, 1)).len(), 2);
+
+        terrain.set_neighborhood(Neighborhood::EightNoCornerCutting);
+        let path = path_between(&terrain, (0, 0), (1, 1));
+        assert_eq!(path.len(), 3);
+        assert_eq!((path[1].x, path[1].y), (0, 1));
+    }
+
+    #[test]
+    fn test_weighted_terrain_is_avoided() {
+        let mut terrain = PathfindingTerrain::new(3, 3, None);
+        terrain.set_neighborhood(Neighborhood::Four);
+        terrain.set_node_cost(1, 0, terrain_cost_for_tag("ShallowWater").unwrap());
+
+        let path = path_between(&terrain, (0, 0), (2, 0));
+        assert!(path.iter().all(|node| node.y != 0 || node.x != 1));
+
+        terrain.set_node_cost(0, 1, terrain_cost_for_tag("Road").unwrap());
+        terrain.set_node_cost(1, 1, terrain_cost_for_tag("Road").unwrap());
+        terrain.set_node_cost(2, 1, terrain_cost_for_tag("Road").unwrap());
+        let path = path_between(&terrain, (0, 0), (2, 0));
+        assert_eq!(path.len(), 5);
+        assert!(path[1..4].iter().all(|node| node.y == 1));
+    }
+
+    #[test]
+    fn test_world_grid_conversion() {
+        let mut terrain = PathfindingTerrain::new(10, 10, None);
+        assert_eq!(terrain.world_to_grid(vec2(2.4, 2.6)), ivec2(2, 3));
+        assert_eq!(terrain.world_to_grid(vec2(-0.4, 0.0)), ivec2(0, 0));
+
+        terrain.offset = Some(vec2(47.0, -35.5));
+        assert_eq!(terrain.grid_to_world(ivec2(3, 4)), vec2(50.0, -31.5));
+        assert_eq!(terrain.world_to_grid(vec2(50.3, -31.1)), ivec2(3, 4));
+        assert_eq!(terrain.world_to_grid(vec2(46.0, -35.5)), ivec2(-1, 0));
+    }
+
+    #[test]
+    fn test_layers_are_composited_bottom_to_top() {
+        use crate::engine::level_module::{Map, RTS_LEVEL_LDTK};
+
+        let map = Map::load(RTS_LEVEL_LDTK, "Level_0").unwrap();
+        let mut json = map.map.json.clone();
+        let level = &mut json.levels[0];
+        let layers = level.layer_instances.as_mut().unwrap();
+        let ground = layers
+            .iter()
+            .find(|l| l.layer_instance_type == "Tiles")
+            .unwrap()
+            .clone();
+
+        // a cliff on the top-left tile, and a bridge over the wall at x = 40
+        // in the top row
+        let mut cliffs = ground.clone();
+        cliffs.grid_tiles.retain(|t| t.px == [0, 0]);
+        cliffs.grid_tiles[0].t = 3;
+        let mut bridge = ground.clone();
+        bridge.grid_tiles.retain(|t| t.px == [40 * 64, 0]);
+        bridge.grid_tiles[0].t = 0;
+        layers.insert(0, cliffs);
+        layers.insert(0, bridge);
+
+        let mut terrain = PathfindingTerrain::new(1, 1, None);
+        terrain.set_pathfinding_based_on_level(&json, &json.levels[0]);
+
+        assert_eq!((terrain.width, terrain.height), (47, 36));
+        assert!(!terrain.is_walkable(0, 35));
+        assert!(terrain.is_walkable(1, 35));
+        assert!(terrain.is_walkable(40, 35));
+        assert!(!terrain.is_walkable(41, 35));
+        assert!(!terrain.is_walkable(40, 0));
+    }
+
+    #[test]
+    fn test_update_from_reports_only_differences() {
+        let mut terrain = PathfindingTerrain::new(4, 4, None);
+        let mut edited = terrain.clone();
+        edited.set_node_type(1, 2, NodeType::Unwalkable);
+        edited.set_node_cost(3, 3, 200);
+
+        assert!(terrain.update_from(&edited));
+        assert_eq!(terrain.take_changes(), vec![ivec2(1, 2), ivec2(3, 3)]);
+        assert!(!terrain.is_walkable(1, 2));
+
+        let resized = PathfindingTerrain::new(5, 4, None);
+        assert!(!terrain.update_from(&resized));
+        assert_eq!(terrain.width, 4);
+    }
+
+    #[test]
+    fn test_terrain_cost_from_custom_data() {
+        assert_eq!(
+            terrain_cost_from_custom_data(r#"{ "cost": 250 }"#),
+            Some(250)
+        );
+        assert_eq!(terrain_cost_from_custom_data("not json"), None);
+        assert_eq!(
+            terrain_cost_from_custom_data(r#"{ "cost": 40000000 }"#),
+            Some(MAX_TERRAIN_COST)
+        );
+        assert_eq!(
+            terrain_cost_from_custom_data(r#"{ "cost": 5000000000 }"#),
+            Some(MAX_TERRAIN_COST)
+        );
+    }
 }
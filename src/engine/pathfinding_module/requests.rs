@@ -0,0 +1,516 @@
+use std::{
+    sync::{
+        atomic::AtomicBool,
+        mpsc::{channel, Receiver, Sender},
+    },
+    thread::JoinHandle,
+};
+
+use comfy::*;
+
+use super::{Node, PathfindingTerrain};
+
+pub const DEFAULT_MAX_DISPATCH_PER_FRAME: usize = 32;
+pub const DEFAULT_MAX_RESULTS_PER_FRAME: usize = 32;
+// how long `finish_before` waits on the workers before solving what's left
+// itself, e.g. after a worker panicked
+const WORKER_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct PathRequestHandle(u64);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PathRequest {
+    pub start: IVec2,
+    pub goal: IVec2,
+    /// Exact point inside the goal node the finished path should end on.
+    pub target: Vec2,
+    /// Radius of the agent that will walk the path, in tiles.
+    pub agent_radius: f32,
+}
+
+#[derive(Debug)]
+pub struct PathResult {
+    pub owner: Entity,
+    pub handle: PathRequestHandle,
+    pub request: PathRequest,
+    /// `None` when the goal can't be reached.
+    pub path: Option<Vec<Node>>,
+}
+
+struct Job {
+    handle: PathRequestHandle,
+    request: PathRequest,
+    terrain: Arc<PathfindingTerrain>,
+    cancelled: Arc<AtomicBool>,
+}
+
+struct Pending {
+    handle: PathRequestHandle,
+    request: PathRequest,
+    cancelled: Arc<AtomicBool>,
+    // terrain the request was handed to the workers with
+    terrain: Option<Arc<PathfindingTerrain>>,
+}
+
+fn solve(request: &PathRequest, terrain: &PathfindingTerrain) -> Option<Vec<Node>> {
+    let start = terrain.get_node_by_position(request.start.x, request.start.y)?;
+    let goal = terrain.get_node_by_position(request.goal.x, request.goal.y)?;
+    terrain.get_path_for_agent(start, goal, request.agent_radius)
+}
+
+/// Solves path requests on a pool of worker threads against a snapshot of the
+/// terrain. Every owner has at most one live request, submitting a new one
+/// cancels the previous.
+pub struct PathRequestQueue {
+    pub max_dispatch_per_frame: usize,
+    pub max_results_per_frame: usize,
+    next_handle: u64,
+    queued: VecDeque<(Entity, PathRequestHandle)>,
+    pending: HashMap<Entity, Pending>,
+    // results solved without the workers, or received by `finish_before`
+    // for requests it wasn't waiting on
+    finished: HashMap<PathRequestHandle, Option<Vec<Node>>>,
+    snapshot: Option<Arc<PathfindingTerrain>>,
+    jobs: Option<Sender<Job>>,
+    results: Receiver<(PathRequestHandle, Option<Vec<Node>>)>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl PathRequestQueue {
+    pub fn new(worker_count: usize) -> Self {
+        let (job_sender, job_receiver) = channel::<Job>();
+        let (result_sender, results) = channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..worker_count.max(1))
+            .map(|i| {
+                let jobs = job_receiver.clone();
+                let results = result_sender.clone();
+                std::thread::Builder::new()
+                    .name(format!("pathfinding-{i}"))
+                    .spawn(move || loop {
+                        let job = match jobs.lock().recv() {
+                            Ok(job) => job,
+                            Err(_) => break,
+                        };
+                        if job.cancelled.load(Ordering::Relaxed) {
+                            continue;
+                        }
+                        let path = solve(&job.request, &job.terrain);
+                        if results.send((job.handle, path)).is_err() {
+                            break;
+                        }
+                    })
+                    .expect("failed to spawn pathfinding worker")
+            })
+            .collect();
+
+        Self {
+            max_dispatch_per_frame: DEFAULT_MAX_DISPATCH_PER_FRAME,
+            max_results_per_frame: DEFAULT_MAX_RESULTS_PER_FRAME,
+            next_handle: 0,
+            queued: VecDeque::new(),
+            pending: HashMap::new(),
+            finished: HashMap::new(),
+            snapshot: None,
+            jobs: Some(job_sender),
+            results,
+            workers,
+        }
+    }
+
+    pub fn submit(&mut self, owner: Entity, request: PathRequest) -> PathRequestHandle {
+        self.cancel(owner);
+
+        let handle = PathRequestHandle(self.next_handle);
+        self.next_handle += 1;
+        self.pending.insert(
+            owner,
+            Pending {
+                handle,
+                request,
+                cancelled: Arc::new(AtomicBool::new(false)),
+                terrain: None,
+            },
+        );
+        self.queued.push_back((owner, handle));
+        handle
+    }
+
+    pub fn cancel(&mut self, owner: Entity) {
+        if let Some(pending) = self.pending.remove(&owner) {
+            pending.cancelled.store(true, Ordering::Relaxed);
+            self.finished.remove(&pending.handle);
+        }
+    }
+
+    pub fn cancel_all(&mut self) {
+        for (_, pending) in self.pending.drain() {
+            pending.cancelled.store(true, Ordering::Relaxed);
+        }
+        self.queued.clear();
+        self.finished.clear();
+    }
+
+    /// Handle the next submitted request will get. Requests submitted
+    /// earlier all have smaller handles.
+    pub fn next_handle(&self) -> PathRequestHandle {
+        PathRequestHandle(self.next_handle)
+    }
+
+    pub fn is_pending(&self, owner: Entity) -> bool {
+        self.pending.contains_key(&owner)
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Hands up to `max_dispatch_per_frame` queued requests to the workers,
+    /// re-snapshotting the terrain first if it changed. Without workers
+    /// they are solved right away instead.
+    ///
+    /// The terrain should be refreshed (see `PathfindingTerrain::refresh`).
+    pub fn dispatch(&mut self, terrain: &PathfindingTerrain) {
+        let budget = self.max_dispatch_per_frame;
+        self.dispatch_while(terrain, |dispatched, _| dispatched < budget);
+    }
+
+    fn dispatch_while(
+        &mut self,
+        terrain: &PathfindingTerrain,
+        mut more: impl FnMut(usize, PathRequestHandle) -> bool,
+    ) {
+        let stale = match &self.snapshot {
+            Some(snapshot) => snapshot.revision() != terrain.revision(),
+            None => true,
+        };
+        if stale && !self.queued.is_empty() {
+            self.snapshot = Some(Arc::new(terrain.clone()));
+        }
+
+        let Some(snapshot) = &self.snapshot else {
+            return;
+        };
+
+        let mut dispatched = 0;
+        while let Some(&(owner, handle)) = self.queued.front() {
+            if !more(dispatched, handle) {
+                break;
+            }
+            self.queued.pop_front();
+            // skip requests that were superseded before reaching a worker
+            let Some(pending) = self.pending.get_mut(&owner).filter(|p| p.handle == handle) else {
+                continue;
+            };
+            pending.terrain = Some(snapshot.clone());
+            let job = Job {
+                handle,
+                request: pending.request,
+                terrain: snapshot.clone(),
+                cancelled: pending.cancelled.clone(),
+            };
+            let sent = self
+                .jobs
+                .as_ref()
+                .is_some_and(|jobs| jobs.send(job).is_ok());
+            if !sent {
+                // every worker is gone, nothing would ever answer
+                self.jobs = None;
+                let path = solve(&pending.request, snapshot);
+                self.finished.insert(handle, path);
+            }
+            dispatched += 1;
+        }
+    }
+
+    /// Collects up to `max_results_per_frame` finished requests that are still
+    /// current for their owner.
+    pub fn poll(&mut self) -> Vec<PathResult> {
+        // the ones already in hand first, oldest first
+        let mut ready = self.finished.drain().collect::<Vec<_>>();
+        ready.sort_by_key(|(handle, _)| *handle);
+        let mut ready = ready.into_iter();
+
+        let mut finished = Vec::new();
+        while finished.len() < self.max_results_per_frame {
+            let (handle, path) = match ready.next() {
+                Some(result) => result,
+                None => match self.results.try_recv() {
+                    Ok(result) => result,
+                    Err(_) => break,
+                },
+            };
+            let owner = self
+                .pending
+                .iter()
+                .find(|(_, pending)| pending.handle == handle)
+                .map(|(owner, _)| *owner);
+            if let Some(owner) = owner {
+                let pending = self.pending.remove(&owner).unwrap();
+                finished.push(PathResult {
+                    owner,
+                    handle,
+                    request: pending.request,
+                    path,
+                });
+            }
+        }
+        // over the budget, left for the next poll
+        self.finished.extend(ready);
+        finished
+    }
+
+    /// Waits for every live request submitted before `handle` and returns
+    /// them in submission order, dispatching them first regardless of the
+    /// per frame budgets. Unlike `poll`, what comes back depends only on
+    /// what was submitted, not on how fast the workers were, so simulations
+    /// that must replay identically collect results this way.
+    ///
+    /// Requests the workers don't answer in time are solved on the calling
+    /// thread against the same terrain, so a dead worker costs time but
+    /// doesn't change the results.
+    pub fn finish_before(
+        &mut self,
+        handle: PathRequestHandle,
+        terrain: &PathfindingTerrain,
+    ) -> Vec<PathResult> {
+        self.dispatch_while(terrain, |_, next| next < handle);
+
+        let mut waiting = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.handle < handle)
+            .map(|(owner, pending)| (pending.handle, *owner))
+            .collect::<Vec<_>>();
+        waiting.sort_by_key(|(handle, _)| *handle);
+
+        while waiting
+            .iter()
+            .any(|(handle, _)| !self.finished.contains_key(handle))
+        {
+            let Ok((handle, path)) = self.results.recv_timeout(WORKER_TIMEOUT) else {
+                // the workers stopped answering, don't wait on them again
+                self.jobs = None;
+                self.solve_inline(&waiting, terrain);
+                break;
+            };
+            let live = self.pending.values().any(|p| p.handle == handle);
+            if live {
+                self.finished.insert(handle, path);
+            }
+        }
+
+        waiting
+            .into_iter()
+            .filter_map(|(handle, owner)| {
+                let path = self.finished.remove(&handle)?;
+                let pending = self.pending.remove(&owner)?;
+                Some(PathResult {
+                    owner,
+                    handle,
+                    request: pending.request,
+                    path,
+                })
+            })
+            .collect()
+    }
+
+    // solves the requests in `waiting` that have no result yet
+    fn solve_inline(
+        &mut self,
+        waiting: &[(PathRequestHandle, Entity)],
+        terrain: &PathfindingTerrain,
+    ) {
+        let mut current = None;
+        for (handle, owner) in waiting {
+            if self.finished.contains_key(handle) {
+                continue;
+            }
+            let Some(pending) = self.pending.get(owner) else {
+                continue;
+            };
+            // never dispatched, the workers would have got the terrain as
+            // it is now
+            let snapshot = pending.terrain.clone().unwrap_or_else(|| {
+                current
+                    .get_or_insert_with(|| Arc::new(terrain.clone()))
+                    .clone()
+            });
+            let path = solve(&pending.request, &snapshot);
+            self.finished.insert(*handle, path);
+        }
+    }
+}
+
+impl Drop for PathRequestQueue {
+    fn drop(&mut self) {
+        // closing the job channel stops the workers
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    fn wait_for_results(queue: &mut PathRequestQueue, count: usize) -> Vec<PathResult> {
+        let mut results = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while results.len() < count && Instant::now() < deadline {
+            results.extend(queue.poll());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        results
+    }
+
+    fn request(goal: IVec2) -> PathRequest {
+        PathRequest {
+            start: ivec2(0, 0),
+            goal,
+            target: goal.as_vec2(),
+            agent_radius: 0.5,
+        }
+    }
+
+    #[test]
+    fn test_path_requests_are_solved_by_workers() {
+        let terrain = PathfindingTerrain::new(20, 20, None);
+        let mut world = World::new();
+        let (a, b) = (world.spawn(()), world.spawn(()));
+
+        let mut queue = PathRequestQueue::new(2);
+        let handle_a = queue.submit(a, request(ivec2(19, 19)));
+        let handle_b = queue.submit(b, request(ivec2(5, 0)));
+        queue.dispatch(&terrain);
+
+        let mut results = wait_for_results(&mut queue, 2);
+        results.sort_by_key(|r| r.handle);
+        assert_eq!(results.len(), 2);
+        assert_eq!((results[0].owner, results[0].handle), (a, handle_a));
+        assert_eq!((results[1].owner, results[1].handle), (b, handle_b));
+        assert_eq!(results[1].path.as_ref().unwrap().len(), 6);
+        assert_eq!(queue.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_newer_request_supersedes_older_one() {
+        let terrain = PathfindingTerrain::new(20, 20, None);
+        let mut world = World::new();
+        let owner = world.spawn(());
+
+        let mut queue = PathRequestQueue::new(1);
+        queue.submit(owner, request(ivec2(19, 19)));
+        let newest = queue.submit(owner, request(ivec2(3, 0)));
+        queue.dispatch(&terrain);
+
+        let results = wait_for_results(&mut queue, 1);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].handle, newest);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(queue.poll().is_empty());
+    }
+
+    #[test]
+    fn test_dispatch_budget() {
+        let terrain = PathfindingTerrain::new(10, 10, None);
+        let mut world = World::new();
+
+        let mut queue = PathRequestQueue::new(1);
+        queue.max_dispatch_per_frame = 1;
+        for _ in 0..3 {
+            queue.submit(world.spawn(()), request(ivec2(9, 9)));
+        }
+
+        queue.dispatch(&terrain);
+        assert_eq!(wait_for_results(&mut queue, 1).len(), 1);
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(queue.poll().is_empty());
+
+        queue.dispatch(&terrain);
+        queue.dispatch(&terrain);
+        assert_eq!(wait_for_results(&mut queue, 2).len(), 2);
+    }
+
+    #[test]
+    fn test_finish_before_returns_in_submission_order() {
+        let terrain = PathfindingTerrain::new(20, 20, None);
+        let mut world = World::new();
+        let owners = (0..4).map(|_| world.spawn(())).collect::<Vec<_>>();
+
+        let mut queue = PathRequestQueue::new(2);
+        // the budget only applies to `dispatch`
+        queue.max_dispatch_per_frame = 0;
+        let first = queue.submit(owners[0], request(ivec2(19, 19)));
+        let second = queue.submit(owners[1], request(ivec2(2, 0)));
+        queue.submit(owners[2], request(ivec2(9, 9)));
+        queue.cancel(owners[2]);
+        let watermark = queue.next_handle();
+        let later = queue.submit(owners[3], request(ivec2(4, 4)));
+
+        let results = queue.finish_before(watermark, &terrain);
+        let handles = results.iter().map(|r| r.handle).collect::<Vec<_>>();
+        assert_eq!(handles, vec![first, second]);
+        assert!(queue.is_pending(owners[3]));
+
+        let results = queue.finish_before(queue.next_handle(), &terrain);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].handle, later);
+        assert_eq!(queue.pending_count(), 0);
+    }
+
+    #[test]
+    fn test_finish_before_without_workers() {
+        let terrain = PathfindingTerrain::new(20, 20, None);
+        let mut world = World::new();
+        let owners = (0..2).map(|_| world.spawn(())).collect::<Vec<_>>();
+        let solve_all = |queue: &mut PathRequestQueue| {
+            queue.submit(owners[0], request(ivec2(19, 19)));
+            queue.submit(owners[1], request(ivec2(2, 0)));
+            queue.finish_before(queue.next_handle(), &terrain)
+        };
+        let expected = solve_all(&mut PathRequestQueue::new(2));
+
+        // closing the job channel stops the workers as if they had died
+        let mut queue = PathRequestQueue::new(2);
+        queue.jobs = None;
+        let results = solve_all(&mut queue);
+        assert_eq!(results.len(), 2);
+        for (result, expected) in results.iter().zip(&expected) {
+            assert_eq!(result.owner, expected.owner);
+            assert_eq!(result.path, expected.path);
+        }
+    }
+
+    #[test]
+    fn test_poll_without_workers() {
+        let terrain = PathfindingTerrain::new(20, 20, None);
+        let mut world = World::new();
+
+        let mut queue = PathRequestQueue::new(1);
+        queue.jobs = None;
+        queue.max_results_per_frame = 2;
+        let handles = (0..3)
+            .map(|_| queue.submit(world.spawn(()), request(ivec2(9, 9))))
+            .collect::<Vec<_>>();
+        queue.dispatch(&terrain);
+        assert!(queue.queued.is_empty());
+
+        let first = queue.poll();
+        assert_eq!(
+            first.iter().map(|r| r.handle).collect::<Vec<_>>(),
+            handles[..2]
+        );
+        assert!(first.iter().all(|r| r.path.is_some()));
+        let second = queue.poll();
+        assert_eq!(second.len(), 1);
+        assert_eq!(second[0].handle, handles[2]);
+        assert!(queue.finished.is_empty());
+        assert_eq!(queue.pending_count(), 0);
+    }
+}
@@ -0,0 +1,268 @@
+use comfy::*;
+use serde::{Deserialize, Serialize};
+
+use super::{required_clearance, Node, PathfindingTerrain};
+
+/// Post-processing applied to grid paths before units follow them.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PathSmoothing {
+    /// Drop every waypoint that can be skipped with a straight walkable line.
+    pub string_pulling: bool,
+    /// Points sampled per segment of a Catmull-Rom curve through the
+    /// waypoints, 0 keeps straight segments.
+    pub curve_samples: usize,
+}
+
+impl Default for PathSmoothing {
+    fn default() -> Self {
+        Self {
+            string_pulling: true,
+            curve_samples: 0,
+        }
+    }
+}
+
+/// Whether the straight segment between two points only crosses walkable
+/// nodes. Points are in grid space: node `(x, y)` covers the square of size 1
+/// centred on `(x, y)`, see `PathfindingTerrain::origin`.
+pub fn has_line_of_sight(terrain: &PathfindingTerrain, from: Vec2, to: Vec2) -> bool {
+    has_line_of_sight_with_clearance(terrain, from, to, 1)
+}
+
+/// `has_line_of_sight` for agents that need `clearance` on every node.
+pub fn has_line_of_sight_with_clearance(
+    terrain: &PathfindingTerrain,
+    from: Vec2,
+    to: Vec2,
+    clearance: i32,
+) -> bool {
+    // shift so that node (x, y) covers [x, x + 1) and walk the grid (Amanatides & Woo)
+    let from = from + 0.5;
+    let to = to + 0.5;
+    let mut cell = from.floor().as_ivec2();
+    let last = to.floor().as_ivec2();
+    let delta = to - from;
+
+    let step = ivec2(delta.x.signum() as i32, delta.y.signum() as i32);
+    let t_delta = vec2(
+        if delta.x != 0.0 {
+            1.0 / delta.x.abs()
+        } else {
+            f32::INFINITY
+        },
+        if delta.y != 0.0 {
+            1.0 / delta.y.abs()
+        } else {
+            f32::INFINITY
+        },
+    );
+    let boundary = |position: f32, cell: i32, step: i32| {
+        if step > 0 {
+            cell as f32 + 1.0 - position
+        } else {
+            position - cell as f32
+        }
+    };
+    let mut t_max = vec2(
+        boundary(from.x, cell.x, step.x) * t_delta.x,
+        boundary(from.y, cell.y, step.y) * t_delta.y,
+    );
+
+    if !terrain.fits(cell.x, cell.y, clearance) {
+        return false;
+    }
+
+    // bounded so float error can't walk past `last` forever
+    let steps = (last - cell).abs();
+    for _ in 0..=steps.x + steps.y {
+        if cell == last {
+            break;
+        }
+        if (t_max.x - t_max.y).abs() < 1e-6 {
+            // passing exactly through a corner touches both side nodes
+            if !terrain.fits(cell.x + step.x, cell.y, clearance)
+                || !terrain.fits(cell.x, cell.y + step.y, clearance)
+            {
+                return false;
+            }
+            cell += step;
+            t_max += t_delta;
+        } else if t_max.x < t_max.y {
+            cell.x += step.x;
+            t_max.x += t_delta.x;
+        } else {
+            cell.y += step.y;
+            t_max.y += t_delta.y;
+        }
+
+        if !terrain.fits(cell.x, cell.y, clearance) {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Keeps only the waypoints where the walkable line of sight breaks.
+pub fn string_pull(terrain: &PathfindingTerrain, points: &[Vec2], clearance: i32) -> Vec<Vec2> {
+    if points.len() < 3 {
+        return points.to_vec();
+    }
+
+    let mut result = vec![points[0]];
+    let mut anchor = points[0];
+    for i in 1..points.len() - 1 {
+        if !has_line_of_sight_with_clearance(terrain, anchor, points[i + 1], clearance) {
+            anchor = points[i];
+            result.push(anchor);
+        }
+    }
+    result.push(points[points.len() - 1]);
+    result
+}
+
+/// Catmull-Rom curve through the waypoints. Curve pieces that would leave the
+/// walkable area are replaced by the straight segment.
+pub fn catmull_rom(
+    terrain: &PathfindingTerrain,
+    points: &[Vec2],
+    samples: usize,
+    clearance: i32,
+) -> Vec<Vec2> {
+    if points.len() < 3 || samples == 0 {
+        return points.to_vec();
+    }
+
+    let mut result = vec![points[0]];
+    for i in 0..points.len() - 1 {
+        let p0 = points[i.saturating_sub(1)];
+        let p1 = points[i];
+        let p2 = points[i + 1];
+        let p3 = points[(i + 2).min(points.len() - 1)];
+
+        let curve = (1..=samples)
+            .map(|s| {
+                let t = s as f32 / samples as f32;
+                let t2 = t * t;
+                let t3 = t2 * t;
+                0.5 * (2.0 * p1
+                    + (p2 - p0) * t
+                    + (2.0 * p0 - 5.0 * p1 + 4.0 * p2 - p3) * t2
+                    + (3.0 * p1 - p0 - 3.0 * p2 + p3) * t3)
+            })
+            .collect::<Vec<_>>();
+
+        let walkable = std::iter::once(p1)
+            .chain(curve.iter().copied())
+            .collect::<Vec<_>>()
+            .windows(2)
+            .all(|pair| has_line_of_sight_with_clearance(terrain, pair[0], pair[1], clearance));
+
+        if walkable {
+            result.extend(curve);
+        } else {
+            result.push(p2);
+        }
+    }
+    result
+}
+
+/// Turns a node path into the world space waypoints a unit of `agent_radius`
+/// at `start` follows, ending exactly on `target`.
+pub fn smooth_path(
+    terrain: &PathfindingTerrain,
+    start: Vec2,
+    path: &[Node],
+    target: Vec2,
+    smoothing: PathSmoothing,
+    agent_radius: f32,
+) -> Vec<Vec2> {
+    let clearance = required_clearance(agent_radius);
+    let origin = terrain.origin();
+    let mut points = vec![start - origin];
+    // the first node is the one the unit stands on, the last is replaced by
+    // the exact target inside it
+    if path.len() > 2 {
+        points.extend(
+            path[1..path.len() - 1]
+                .iter()
+                .map(|node| vec2(node.x as f32, node.y as f32)),
+        );
+    }
+    points.push(target - origin);
+
+    if smoothing.string_pulling {
+        points = string_pull(terrain, &points, clearance);
+    }
+    points = catmull_rom(terrain, &points, smoothing.curve_samples, clearance);
+
+    // the unit is already at the start
+    points.remove(0);
+    points.into_iter().map(|point| point + origin).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::*;
+
+    #[test]
+    fn test_line_of_sight() {
+        let mut terrain = PathfindingTerrain::new(10, 10, None);
+        terrain.set_node_type(5, 5, NodeType::Unwalkable);
+
+        assert!(!has_line_of_sight(&terrain, vec2(0.0, 0.0), vec2(9.0, 9.0)));
+        assert!(has_line_of_sight(&terrain, vec2(0.0, 0.0), vec2(9.0, 4.0)));
+        assert!(has_line_of_sight(&terrain, vec2(5.0, 0.0), vec2(5.0, 4.4)));
+        assert!(!has_line_of_sight(&terrain, vec2(5.0, 0.0), vec2(5.0, 4.6)));
+    }
+
+    #[test]
+    fn test_string_pulling_reduces_waypoints_and_ends_on_target() {
+        let mut terrain = PathfindingTerrain::new(10, 10, None);
+        terrain.set_neighborhood(Neighborhood::Four);
+        for y in 0..8 {
+            terrain.set_node_type(5, y, NodeType::Unwalkable);
+        }
+
+        let start = terrain.get_node_by_position(0, 0).unwrap();
+        let goal = terrain.get_node_by_position(9, 0).unwrap();
+        let path = terrain.get_path(start, goal).unwrap();
+        let target = vec2(9.3, 0.2);
+
+        let points = smooth_path(
+            &terrain,
+            vec2(0.1, 0.0),
+            &path,
+            target,
+            PathSmoothing::default(),
+            0.5,
+        );
+        assert!(points.len() < 5);
+        assert_eq!(points.last(), Some(&target));
+
+        let mut from = vec2(0.1, 0.0);
+        for point in &points {
+            assert!(has_line_of_sight(&terrain, from, *point));
+            from = *point;
+        }
+    }
+
+    #[test]
+    fn test_catmull_rom_stays_walkable() {
+        let mut terrain = PathfindingTerrain::new(10, 10, None);
+        terrain.set_node_type(3, 3, NodeType::Unwalkable);
+
+        let points = [
+            vec2(0.0, 0.0),
+            vec2(2.0, 4.0),
+            vec2(6.0, 4.0),
+            vec2(9.0, 9.0),
+        ];
+        let curve = catmull_rom(&terrain, &points, 8, 1);
+        assert_eq!(curve.first(), points.first());
+        assert_eq!(curve.last(), points.last());
+        for pair in curve.windows(2) {
+            assert!(has_line_of_sight(&terrain, pair[0], pair[1]));
+        }
+    }
+}
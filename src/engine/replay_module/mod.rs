@@ -0,0 +1,468 @@
+use std::fmt;
+
+use comfy::*;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    game_module::GameState,
+    level_module::Map,
+    simulation_module::{Command, Simulation, SimulationConfig, TICK_RATE},
+    unit_module::UnitArchetypes,
+};
+
+pub const DESYNCS_DIRECTORY: &str = "desyncs";
+
+/// Bumped whenever recorded commands stop meaning the same thing.
+pub const REPLAY_VERSION: u32 = 2;
+pub const REPLAYS_DIRECTORY: &str = "replays";
+
+pub const MIN_PLAYBACK_SPEED: f32 = 0.25;
+pub const MAX_PLAYBACK_SPEED: f32 = 8.0;
+/// How far the arrow keys jump while watching a replay.
+pub const SEEK_SECONDS: u64 = 5;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// The replay was recorded with another version of the commands.
+    Version(u32),
+    /// The replay was recorded at another tick rate.
+    TickRate(u32),
+    /// The replay was recorded with other unit types.
+    Archetypes,
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io { path, source } => write!(f, "failed to access {path}: {source}"),
+            ReplayError::Parse { path, source } => write!(f, "failed to parse {path}: {source}"),
+            ReplayError::Version(version) => write!(
+                f,
+                "replay version {version} is not supported, expected {REPLAY_VERSION}"
+            ),
+            ReplayError::TickRate(rate) => write!(
+                f,
+                "replay was recorded at {rate} ticks per second, expected {TICK_RATE}"
+            ),
+            ReplayError::Archetypes => write!(f, "replay was recorded with other unit types"),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReplayError::Io { source, .. } => Some(source),
+            ReplayError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// The commands of a match and the ticks they were applied on, enough to
+/// play it again on a fresh simulation. Edits made with the tile editor are
+/// not recorded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    pub version: u32,
+    /// Identifier of the level the match was played on.
+    pub level: String,
+    pub tick_rate: u32,
+    /// `UnitArchetypes::checksum` of the unit types the match was played
+    /// with.
+    pub archetypes: u64,
+    /// Settings at tick 0, later changes are recorded as commands.
+    pub config: SimulationConfig,
+    pub commands: Vec<RecordedCommand>,
+    /// `(tick, checksum)` of the world after every tick that was played,
+    /// used to notice when playback drifts from the match.
+    #[serde(default)]
+    pub checksums: Vec<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedCommand {
+    pub tick: u64,
+    pub command: Command,
+}
+
+impl Replay {
+    pub fn new(level: &str, config: SimulationConfig, archetypes: &UnitArchetypes) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            level: level.to_string(),
+            tick_rate: TICK_RATE,
+            archetypes: archetypes.checksum(),
+            config,
+            commands: Vec::new(),
+            checksums: Vec::new(),
+        }
+    }
+
+    /// Adds the commands applied on `tick`.
+    pub fn record(&mut self, tick: u64, commands: &[Command]) {
+        self.commands
+            .extend(commands.iter().map(|command| RecordedCommand {
+                tick,
+                command: command.clone(),
+            }));
+    }
+
+    /// Adds the checksum of the world after `tick` was played.
+    pub fn record_checksum(&mut self, tick: u64, checksum: u64) {
+        self.checksums.push((tick, checksum));
+    }
+
+    /// Recorded checksum of the world at `tick`.
+    pub fn checksum_at(&self, tick: u64) -> Option<u64> {
+        self.checksums
+            .binary_search_by_key(&tick, |(recorded, _)| *recorded)
+            .ok()
+            .map(|index| self.checksums[index].1)
+    }
+
+    /// Tick of the last recorded command.
+    pub fn last_tick(&self) -> u64 {
+        self.commands.last().map_or(0, |recorded| recorded.tick)
+    }
+
+    /// Reads a replay, which has to have been recorded with `archetypes`.
+    pub fn load(path: &str, archetypes: &UnitArchetypes) -> Result<Self, ReplayError> {
+        let source = std::fs::read_to_string(path).map_err(|source| ReplayError::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        let replay: Replay =
+            serde_json::from_str(&source).map_err(|source| ReplayError::Parse {
+                path: path.to_string(),
+                source,
+            })?;
+        if replay.version != REPLAY_VERSION {
+            return Err(ReplayError::Version(replay.version));
+        }
+        if replay.tick_rate != TICK_RATE {
+            return Err(ReplayError::TickRate(replay.tick_rate));
+        }
+        if replay.archetypes != archetypes.checksum() {
+            return Err(ReplayError::Archetypes);
+        }
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), ReplayError> {
+        let io_error = |source| ReplayError::Io {
+            path: path.to_string(),
+            source,
+        };
+        if let Some(directory) = std::path::Path::new(path).parent() {
+            std::fs::create_dir_all(directory).map_err(io_error)?;
+        }
+        let json = serde_json::to_string_pretty(self).map_err(|source| ReplayError::Parse {
+            path: path.to_string(),
+            source,
+        })?;
+        std::fs::write(path, json).map_err(io_error)
+    }
+
+    /// Saves the replay to a new file in `REPLAYS_DIRECTORY` and returns its
+    /// path.
+    pub fn save_new(&self) -> Result<String, ReplayError> {
+        let seconds = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let path = format!("{REPLAYS_DIRECTORY}/{}-{seconds}.json", self.level);
+        self.save(&path)?;
+        Ok(path)
+    }
+}
+
+/// Feeds a replay back into a simulation.
+pub struct ReplayPlayer {
+    pub replay: Replay,
+    pub paused: bool,
+    /// Game seconds played per real second.
+    pub speed: f32,
+    /// First tick after which the world stopped matching the recording.
+    pub divergence: Option<u64>,
+    /// Where the world is dumped when it diverges.
+    pub dump_directory: String,
+    // first command not fed to the simulation yet
+    next: usize,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            paused: false,
+            speed: 1.0,
+            divergence: None,
+            dump_directory: DESYNCS_DIRECTORY.to_string(),
+            next: 0,
+        }
+    }
+
+    /// Starts `sim` over at tick 0 on `map`, which must be on the level of
+    /// the replay.
+    pub fn restart(&mut self, sim: &mut Simulation, map: &Map) {
+        debug_assert_eq!(map.level().identifier, self.replay.level);
+        sim.clear();
+        sim.configure(&self.replay.config);
+        sim.load_level(map);
+        self.next = 0;
+    }
+
+    /// Commands recorded for `tick`. Ticks have to be asked for in order.
+    pub fn commands_for(&mut self, tick: u64) -> Vec<Command> {
+        let mut commands = Vec::new();
+        while let Some(recorded) = self.replay.commands.get(self.next) {
+            if recorded.tick > tick {
+                break;
+            }
+            if recorded.tick == tick {
+                commands.push(recorded.command.clone());
+            }
+            self.next += 1;
+        }
+        commands
+    }
+
+    /// Advances `sim` one tick with the commands recorded for it and checks
+    /// the result against the recorded checksum.
+    pub fn step(&mut self, sim: &mut Simulation) {
+        let commands = self.commands_for(sim.tick);
+        sim.step(&commands);
+        self.verify(sim);
+    }
+
+    // the first divergence is logged and dumped, later ones follow from it
+    fn verify(&mut self, sim: &Simulation) {
+        if self.divergence.is_some() {
+            return;
+        }
+        let Some(expected) = self.replay.checksum_at(sim.tick) else {
+            return;
+        };
+        let checksum = sim.checksum();
+        if checksum == expected {
+            return;
+        }
+        self.divergence = Some(sim.tick);
+        error!(
+            "Replay diverged at tick {}: checksum {checksum:016x}, recorded {expected:016x}",
+            sim.tick
+        );
+        match sim.save_dump(&self.dump_directory, "replay") {
+            Ok(path) => error!("World state written to {path}"),
+            Err(err) => error!("Failed to write the world state: {err}"),
+        }
+    }
+
+    /// Brings `sim` to `tick`, replaying from the start when it is already
+    /// past it.
+    pub fn seek(&mut self, sim: &mut Simulation, map: &Map, tick: u64) {
+        if tick < sim.tick {
+            self.restart(sim, map);
+        }
+        while sim.tick < tick {
+            self.step(sim);
+        }
+    }
+
+    pub fn is_finished(&self, tick: u64) -> bool {
+        self.next >= self.replay.commands.len() && tick > self.replay.last_tick()
+    }
+
+    pub fn faster(&mut self) {
+        self.speed = (self.speed * 2.0).min(MAX_PLAYBACK_SPEED);
+    }
+
+    pub fn slower(&mut self) {
+        self.speed = (self.speed / 2.0).max(MIN_PLAYBACK_SPEED);
+    }
+
+    /// Short description for the HUD.
+    pub fn status(&self, tick: u64) -> String {
+        let seconds = |tick: u64| tick as f32 / TICK_RATE as f32;
+        format!(
+            "replay {:.1}s/{:.1}s x{}{}{}",
+            seconds(tick),
+            seconds(self.replay.last_tick()),
+            self.speed,
+            if self.paused { ", paused" } else { "" },
+            match self.divergence {
+                Some(tick) => format!(", diverged at {:.1}s", seconds(tick)),
+                None => String::new(),
+            }
+        )
+    }
+}
+
+/// Space pauses the replay being watched, left and right jump back and
+/// forward, up and down change the speed.
+pub fn playback_system(state: &mut GameState) {
+    let Some(player) = &mut state.playback else {
+        return;
+    };
+    if is_key_pressed(KeyCode::Space) {
+        player.paused = !player.paused;
+    }
+    if is_key_pressed(KeyCode::Up) {
+        player.faster();
+    }
+    if is_key_pressed(KeyCode::Down) {
+        player.slower();
+    }
+
+    let jump = SEEK_SECONDS * TICK_RATE as u64;
+    if is_key_pressed(KeyCode::Left) {
+        state.seek(state.sim.tick.saturating_sub(jump));
+    }
+    if is_key_pressed(KeyCode::Right) {
+        state.seek(state.sim.tick + jump);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        level_module::{DEFAULT_LEVEL, RTS_LEVEL_LDTK},
+        unit_module::{Order, Owner, Unit},
+    };
+
+    fn positions(sim: &Simulation) -> Vec<(Entity, u32, u32)> {
+        sim.world
+            .query::<(&Transform, &Unit)>()
+            .iter()
+            .map(|(entity, (transform, _))| {
+                let position = transform.position;
+                (entity, position.x.to_bits(), position.y.to_bits())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_replay_reproduces_the_match() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut sim = Simulation::new();
+        sim.load_level(&map);
+        let mut replay = Replay::new(DEFAULT_LEVEL, sim.config(), &sim.archetypes);
+
+        let units = sim
+            .world
+            .query::<(&Unit, &Owner)>()
+            .iter()
+            .filter(|(_, (_, owner))| owner.0 == 0)
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        let spawn = Command::SpawnUnit {
+            position: vec2(70.0, -25.5),
+            owner: 0,
+            unit_type: "Worker".to_string(),
+            health: None,
+            facing: 0.0,
+            player: None,
+        };
+        let mut midway = Vec::new();
+        for _ in 0..60 {
+            let commands = match sim.tick {
+                2 => vec![
+                    Command::Select {
+                        units: units.clone(),
+                    },
+                    Command::Order {
+                        units: units.clone(),
+                        order: Order::Move(vec2(70.0, -15.5)),
+                        queued: false,
+                        player: None,
+                    },
+                ],
+                10 => vec![spawn.clone()],
+                15 => vec![Command::Order {
+                    units: units.clone(),
+                    order: Order::Patrol(vec2(80.0, -23.5)),
+                    queued: true,
+                    player: None,
+                }],
+                _ => vec![],
+            };
+            replay.record(sim.tick, &commands);
+            sim.step(&commands);
+            replay.record_checksum(sim.tick, sim.checksum());
+            if sim.tick == 30 {
+                midway = positions(&sim);
+            }
+        }
+
+        let path = std::env::temp_dir()
+            .join(format!("rts-replay-{}.json", std::process::id()))
+            .to_str()
+            .unwrap()
+            .to_string();
+        replay.save(&path).unwrap();
+        let loaded = Replay::load(&path, &sim.archetypes).unwrap();
+        let others = UnitArchetypes::parse(
+            r#"[{"name": "Worker", "speed": 3, "radius": 0.5, "health": 10, "vision": 5}]"#,
+            "units.json",
+        )
+        .unwrap();
+        assert!(matches!(
+            Replay::load(&path, &others),
+            Err(ReplayError::Archetypes)
+        ));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, replay);
+
+        let mut player = ReplayPlayer::new(loaded);
+        let mut played = Simulation::new();
+        player.restart(&mut played, &map);
+        player.seek(&mut played, &map, 60);
+        assert_eq!(positions(&played), positions(&sim));
+        assert!(player.is_finished(played.tick));
+        assert_eq!(player.divergence, None);
+
+        // seeking back replays from the start
+        player.seek(&mut played, &map, 30);
+        assert_eq!(positions(&played), midway);
+        player.seek(&mut played, &map, 60);
+        assert_eq!(positions(&played), positions(&sim));
+        assert_eq!(player.divergence, None);
+    }
+
+    #[test]
+    fn test_replay_notices_divergence() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut sim = Simulation::new();
+        sim.load_level(&map);
+        let mut replay = Replay::new(DEFAULT_LEVEL, sim.config(), &sim.archetypes);
+        for _ in 0..20 {
+            sim.step(&[]);
+            replay.record_checksum(sim.tick, sim.checksum());
+        }
+
+        let mut player = ReplayPlayer::new(replay);
+        let dumps = std::env::temp_dir().join(format!("rts-desyncs-{}", std::process::id()));
+        player.dump_directory = dumps.to_str().unwrap().to_string();
+        let mut played = Simulation::new();
+        player.restart(&mut played, &map);
+        player.seek(&mut played, &map, 10);
+        assert_eq!(player.divergence, None);
+
+        // something the recording never had happen
+        let (unit, _) = played.world.query::<&Unit>().iter().next().unwrap();
+        played.world.get::<&mut Transform>(unit).unwrap().position.x += 1.0;
+        player.seek(&mut played, &map, 20);
+        assert_eq!(player.divergence, Some(11));
+        assert!(dumps.join("tick-11-replay.txt").exists());
+        std::fs::remove_dir_all(&dumps).unwrap();
+    }
+}
@@ -1,9 +1,13 @@
 use comfy::{hecs::With, *};
 
-use super::{unit_module::Unit, UNIT_Z_INDEX};
+use super::{
+    unit_module::{interpolated_position, PreviousPosition, Unit},
+    UNIT_Z_INDEX,
+};
 
 pub struct SelectedUnit {}
 
+#[derive(Default)]
 pub struct SelectionBox {
     pub start: Vec2,
     pub end: Vec2,
@@ -11,94 +15,97 @@ pub struct SelectionBox {
     pub height: f32,
 }
 
+impl SelectionBox {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+pub fn selected_units(world: &World) -> Vec<Entity> {
+    world
+        .query::<With<(), &SelectedUnit>>()
+        .iter()
+        .map(|(entity, _)| entity)
+        .collect()
+}
+
 pub fn initialize_selection_module(c: &mut EngineContext) {
     c.load_texture_from_bytes(
         "selection",
         include_bytes!("../../../assets/sprites/selection.png"),
     );
-
-    commands().spawn((
-        SelectionBox {
-            start: vec2(0.0, 0.0),
-            end: vec2(0.0, 0.0),
-            width: 0.0,
-            height: 0.0,
-        },
-        Transform::position(vec2(0.0, 0.0)),
-    ));
 }
 
-pub fn selection_box_system() {
-    match world().query::<&mut SelectionBox>().iter().next() {
-        Some((_, selection_box)) => {
-            if is_mouse_button_pressed(MouseButton::Left) {
-                let start = mouse_world();
-                selection_box.start = start;
-            }
-            if is_mouse_button_down(MouseButton::Left) {
-                let end = mouse_world();
-                selection_box.end = end;
-                selection_box.width = end.x - selection_box.start.x;
-                selection_box.height = end.y - selection_box.start.y;
-            }
-            if is_mouse_button_released(MouseButton::Left) {
-                selection_box.width = 0.0;
-                selection_box.height = 0.0;
-                selection_box.start = vec2(0.0, 0.0);
-                selection_box.end = vec2(0.0, 0.0);
-            }
-        }
-        None => return,
+pub fn selection_box_system(selection_box: &mut SelectionBox) {
+    if is_mouse_button_pressed(MouseButton::Left) {
+        let start = mouse_world();
+        selection_box.start = start;
+    }
+    if is_mouse_button_down(MouseButton::Left) {
+        let end = mouse_world();
+        selection_box.end = end;
+        selection_box.width = end.x - selection_box.start.x;
+        selection_box.height = end.y - selection_box.start.y;
+    }
+    if is_mouse_button_released(MouseButton::Left) {
+        selection_box.width = 0.0;
+        selection_box.height = 0.0;
+        selection_box.start = vec2(0.0, 0.0);
+        selection_box.end = vec2(0.0, 0.0);
     }
 }
 
-pub fn get_units_in_selection_system() {
-    let binding = world();
-    let mut binding = binding.query::<&SelectionBox>();
-    let selection_box_query = binding.iter().next();
-    if let Some(selection_box_query) = selection_box_query {
-        let (_, selection_box) = selection_box_query;
+pub fn get_units_in_selection_system(selection_box: &SelectionBox, world: &mut World) {
+    let min_x = selection_box.start.x.min(selection_box.end.x);
+    let max_x = selection_box.start.x.max(selection_box.end.x);
 
-        for (entity, (transform, _)) in &mut world().query::<(&Transform, &Unit)>().iter() {
-            let min_x = selection_box.start.x.min(selection_box.end.x);
-            let max_x = selection_box.start.x.max(selection_box.end.x);
+    let min_y = selection_box.start.y.min(selection_box.end.y);
+    let max_y = selection_box.start.y.max(selection_box.end.y);
 
-            let min_y = selection_box.start.y.min(selection_box.end.y);
-            let max_y = selection_box.start.y.max(selection_box.end.y);
-
-            if transform.position.x > min_x
+    let inside = world
+        .query::<(&Transform, &Unit)>()
+        .iter()
+        .filter(|(_, (transform, _))| {
+            transform.position.x > min_x
                 && transform.position.x < max_x
                 && transform.position.y > min_y
                 && transform.position.y < max_y
-            {
-                commands().insert_one(entity, SelectedUnit {});
-            }
-        }
+        })
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+    for entity in inside {
+        let _ = world.insert_one(entity, SelectedUnit {});
     }
 }
 
-pub fn deselect_units_system() {
+pub fn deselect_units_system(world: &mut World) {
     if is_mouse_button_pressed(MouseButton::Left) {
-        for (entity, _) in world().query::<&SelectedUnit>().iter() {
-            commands().remove_one::<SelectedUnit>(entity);
+        let selected = world
+            .query::<&SelectedUnit>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        for entity in selected {
+            let _ = world.remove_one::<SelectedUnit>(entity);
         }
     }
 }
 
-pub fn draw_selection_box() {
-    for (_, selection_box) in world().query::<&SelectionBox>().iter() {
-        let center = Vec2::new(selection_box.width / 2.0, selection_box.height / 2.0);
-        let size = Vec2::new(selection_box.width, selection_box.height);
+pub fn draw_selection_box(selection_box: &SelectionBox) {
+    let center = Vec2::new(selection_box.width / 2.0, selection_box.height / 2.0);
+    let size = Vec2::new(selection_box.width, selection_box.height);
 
-        draw_rect_outline(selection_box.start + center, size, 0.2, WHITE, 100);
-    }
+    draw_rect_outline(selection_box.start + center, size, 0.2, WHITE, 100);
 }
 
-pub fn draw_selection_on_units() {
-    for (_, transform) in world().query::<With<&Transform, &SelectedUnit>>().iter() {
+pub fn draw_selection_on_units(world: &World, alpha: f32) {
+    for (_, (transform, previous)) in world
+        .query::<With<(&Transform, Option<&PreviousPosition>), &SelectedUnit>>()
+        .iter()
+    {
         draw_sprite_ex(
             texture_id("selection"),
-            transform.position,
+            interpolated_position(transform, previous, alpha),
             WHITE,
             UNIT_Z_INDEX + 1,
             DrawTextureParams {
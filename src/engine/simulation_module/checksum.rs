@@ -0,0 +1,506 @@
+use std::fmt::{Debug, Write};
+
+use comfy::{
+    hecs::{Component, EntityRef},
+    *,
+};
+
+use super::Simulation;
+use crate::engine::{
+    level_module::{Building, Resource},
+    pathfinding_module::NodeType,
+    unit_module::{
+        Armor, Attack, CollisionAvoidance, Health, MoveSpeed, Order, Orders, Owner, PathPlanner,
+        Unit, UnitArchetypes, UnitFlowField, UnitPath, UnitType, Vision,
+    },
+};
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// FNV-1a, unlike `DefaultHasher` it is the same in every build
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Hash of the components of an entity that affect the game.
+#[derive(Debug, Clone, Copy)]
+pub struct EntityState {
+    pub entity: Entity,
+    pub hash: u64,
+}
+
+/// State of the simulation after a tick, see `Simulation::snapshot`.
+#[derive(Debug, Clone)]
+pub struct WorldSnapshot {
+    pub tick: u64,
+    pub checksum: u64,
+    /// Sorted by entity.
+    pub entities: Vec<EntityState>,
+}
+
+impl WorldSnapshot {
+    pub fn entity_hashes(&self) -> Vec<(Entity, u64)> {
+        self.entities
+            .iter()
+            .map(|state| (state.entity, state.hash))
+            .collect()
+    }
+
+    /// First entity, in entity order, whose state differs from `theirs` or
+    /// that only one side has.
+    pub fn first_divergence(&self, theirs: &[(Entity, u64)]) -> Option<Entity> {
+        let mut ours = self.entity_hashes();
+        let mut theirs = theirs.to_vec();
+        ours.sort_by_key(|(entity, _)| entity.to_bits());
+        theirs.sort_by_key(|(entity, _)| entity.to_bits());
+
+        let mut ours = ours.into_iter().peekable();
+        let mut theirs = theirs.into_iter().peekable();
+        loop {
+            match (ours.peek().copied(), theirs.peek().copied()) {
+                (None, None) => return None,
+                (Some((entity, _)), None) | (None, Some((entity, _))) => return Some(entity),
+                (Some((a, a_hash)), Some((b, b_hash))) => {
+                    if a != b {
+                        return Some(if a.to_bits() < b.to_bits() { a } else { b });
+                    }
+                    if a_hash != b_hash {
+                        return Some(a);
+                    }
+                    ours.next();
+                    theirs.next();
+                }
+            }
+        }
+    }
+
+    pub fn entity(&self, entity: Entity) -> Option<&EntityState> {
+        self.entities.iter().find(|state| state.entity == entity)
+    }
+}
+
+impl Simulation {
+    /// Hashes everything that decides how the game plays out from here:
+    /// the tick, the settings, the grid and the gameplay
+    /// components of every entity. Selection and the positions kept for
+    /// drawing are left out, they differ between players.
+    pub fn snapshot(&self) -> WorldSnapshot {
+        let mut entities = self
+            .world
+            .iter()
+            .map(|entity| EntityState {
+                entity: entity.entity(),
+                hash: hash_entity(&entity),
+            })
+            .collect::<Vec<_>>();
+        entities.sort_by_key(|state| state.entity.to_bits());
+
+        let mut hasher = StateHasher::new();
+        hasher.u64(self.tick);
+        let config = self.config();
+        hasher.bytes(&[config.neighborhood as u8]);
+        hasher.bool(config.path_smoothing.string_pulling);
+        hasher.u64(config.path_smoothing.curve_samples as u64);
+        hasher.bool(config.incremental_replanning);
+        hasher.i32(self.board.width);
+        hasher.i32(self.board.height);
+        for node in &self.board.nodes {
+            hasher.node_type(node.node_type);
+            hasher.u32(node.cost);
+        }
+        for state in &entities {
+            hasher.entity(state.entity);
+            hasher.u64(state.hash);
+        }
+
+        WorldSnapshot {
+            tick: self.tick,
+            checksum: hasher.finish(),
+            entities,
+        }
+    }
+
+    pub fn checksum(&self) -> u64 {
+        self.snapshot().checksum
+    }
+
+    /// The gameplay components of `entity` written out with exact floats,
+    /// `None` once it is gone.
+    pub fn describe(&self, entity: Entity) -> Option<String> {
+        self.world
+            .entity(entity)
+            .ok()
+            .map(|entity| describe(&entity))
+    }
+
+    /// Every entity on its own line with its hash, for diffing the dumps
+    /// of two clients.
+    pub fn dump(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut dump = format!("tick {} checksum {:016x}\n", self.tick, snapshot.checksum);
+        for state in &snapshot.entities {
+            let description = self.describe(state.entity).unwrap_or_default();
+            let _ = writeln!(dump, "{:?} {:016x} {description}", state.entity, state.hash);
+        }
+        dump
+    }
+
+    /// Writes `dump` to `directory`, named after the tick and `label`, and
+    /// returns the path.
+    pub fn save_dump(&self, directory: &str, label: &str) -> std::io::Result<String> {
+        std::fs::create_dir_all(directory)?;
+        let path = format!("{directory}/tick-{}-{label}.txt", self.tick);
+        std::fs::write(&path, self.dump())?;
+        Ok(path)
+    }
+}
+
+impl UnitArchetypes {
+    /// Hash of the stats of every type, in order. Sprites are left out,
+    /// they don't change how the game plays out.
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = StateHasher::new();
+        for archetype in self.iter() {
+            hasher.str(&archetype.name);
+            hasher.f32(archetype.speed);
+            hasher.f32(archetype.radius);
+            hasher.i32(archetype.health);
+            hasher.i32(archetype.armor);
+            hasher.f32(archetype.vision);
+            hasher.u32(archetype.cost);
+            hasher.u64(archetype.abilities.len() as u64);
+            for ability in &archetype.abilities {
+                hasher.str(ability);
+            }
+            hasher.option(archetype.attack, |hasher, attack| {
+                hasher.i32(attack.damage);
+                hasher.f32(attack.range);
+                hasher.f32(attack.cooldown);
+            });
+        }
+        hasher.finish()
+    }
+}
+
+// feeds the state to FNV-1a field by field, floats by their bits
+struct StateHasher(u64);
+
+impl StateHasher {
+    fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.0 = fnv1a(self.0, bytes);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.bytes(&[value as u8]);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn i32(&mut self, value: i32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.u32(value.to_bits());
+    }
+
+    fn vec2(&mut self, value: Vec2) {
+        self.f32(value.x);
+        self.f32(value.y);
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u64(value.len() as u64);
+        self.bytes(value.as_bytes());
+    }
+
+    fn entity(&mut self, entity: Entity) {
+        self.u64(entity.to_bits().get());
+    }
+
+    fn option<T>(&mut self, value: Option<T>, hash: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.bool(true);
+                hash(self, value);
+            }
+            None => self.bool(false),
+        }
+    }
+
+    fn node_type(&mut self, node_type: Option<NodeType>) {
+        self.bytes(&[node_type.map_or(0, |node_type| node_type as u8 + 1)]);
+    }
+}
+
+trait StateHash {
+    fn hash_state(&self, hasher: &mut StateHasher);
+}
+
+impl StateHash for Transform {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.vec2(self.position);
+        hasher.f32(self.rotation);
+        hasher.f32(self.scale);
+    }
+}
+
+impl StateHash for Unit {
+    fn hash_state(&self, _: &mut StateHasher) {}
+}
+
+impl StateHash for Owner {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.u32(self.0);
+    }
+}
+
+impl StateHash for UnitType {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.str(&self.0);
+    }
+}
+
+impl StateHash for Health {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.i32(self.current);
+        hasher.i32(self.max);
+    }
+}
+
+impl StateHash for Armor {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.i32(self.0);
+    }
+}
+
+impl StateHash for MoveSpeed {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.f32(self.0);
+    }
+}
+
+impl StateHash for Attack {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.i32(self.damage);
+        hasher.f32(self.range);
+        hasher.u64(self.cooldown_ticks);
+        hasher.u64(self.ready_at);
+        hasher.option(self.target, StateHasher::entity);
+        hasher.option(self.last_hit, StateHasher::u64);
+    }
+}
+
+impl StateHash for Vision {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.f32(self.0);
+    }
+}
+
+impl StateHash for CollisionAvoidance {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.f32(self.radius);
+    }
+}
+
+impl StateHash for UnitPath {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.u64(self.path.len() as u64);
+        for point in &self.path {
+            hasher.vec2(*point);
+        }
+        hasher.u64(self.current_node as u64);
+    }
+}
+
+impl StateHash for UnitFlowField {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.i32(self.goal.x);
+        hasher.i32(self.goal.y);
+        hasher.i32(self.clearance);
+        hasher.vec2(self.target);
+    }
+}
+
+impl StateHash for Order {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        match *self {
+            Order::Move(point) => {
+                hasher.bytes(&[0]);
+                hasher.vec2(point);
+            }
+            Order::Stop => hasher.bytes(&[1]),
+            Order::Hold => hasher.bytes(&[2]),
+            Order::Patrol(point) => {
+                hasher.bytes(&[3]);
+                hasher.vec2(point);
+            }
+            Order::AttackMove(point) => {
+                hasher.bytes(&[4]);
+                hasher.vec2(point);
+            }
+            Order::Follow(entity) => {
+                hasher.bytes(&[5]);
+                hasher.entity(entity);
+            }
+            Order::Attack(entity) => {
+                hasher.bytes(&[6]);
+                hasher.entity(entity);
+            }
+        }
+    }
+}
+
+impl StateHash for Orders {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.u64(self.queue.len() as u64);
+        for order in &self.queue {
+            order.hash_state(hasher);
+        }
+        hasher.option(self.started_at(), StateHasher::vec2);
+    }
+}
+
+impl StateHash for Building {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.str(&self.building_type);
+        hasher.vec2(self.size);
+        hasher.u64(self.covered.len() as u64);
+        for node in &self.covered {
+            hasher.i32(node.x);
+            hasher.i32(node.y);
+        }
+    }
+}
+
+impl StateHash for Resource {
+    fn hash_state(&self, hasher: &mut StateHasher) {
+        hasher.str(&self.resource_type);
+        hasher.u32(self.amount);
+    }
+}
+
+// the planner's search follows from the rest, only its presence matters
+impl StateHash for PathPlanner {
+    fn hash_state(&self, _: &mut StateHasher) {}
+}
+
+fn hash_entity(entity: &EntityRef) -> u64 {
+    let mut hasher = StateHasher::new();
+    // each component is tagged so that different sets of them can't collide
+    hash_component::<Transform>(entity, 0, &mut hasher);
+    hash_component::<Unit>(entity, 1, &mut hasher);
+    hash_component::<Owner>(entity, 2, &mut hasher);
+    hash_component::<UnitType>(entity, 3, &mut hasher);
+    hash_component::<Health>(entity, 4, &mut hasher);
+    hash_component::<Armor>(entity, 5, &mut hasher);
+    hash_component::<MoveSpeed>(entity, 6, &mut hasher);
+    hash_component::<Attack>(entity, 7, &mut hasher);
+    hash_component::<Vision>(entity, 8, &mut hasher);
+    hash_component::<CollisionAvoidance>(entity, 9, &mut hasher);
+    hash_component::<UnitPath>(entity, 10, &mut hasher);
+    hash_component::<UnitFlowField>(entity, 11, &mut hasher);
+    hash_component::<Orders>(entity, 12, &mut hasher);
+    hash_component::<Building>(entity, 13, &mut hasher);
+    hash_component::<Resource>(entity, 14, &mut hasher);
+    hash_component::<PathPlanner>(entity, 15, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_component<T: Component + StateHash>(entity: &EntityRef, tag: u8, hasher: &mut StateHasher) {
+    if let Some(component) = entity.get::<&T>() {
+        hasher.bytes(&[tag]);
+        component.hash_state(hasher);
+    }
+}
+
+fn describe(entity: &EntityRef) -> String {
+    let mut parts = Vec::new();
+    if let Some(transform) = entity.get::<&Transform>() {
+        parts.push(format!(
+            "Transform {{ position: {:?}, rotation: {:?}, scale: {:?} }}",
+            transform.position, transform.rotation, transform.scale
+        ));
+    }
+    describe_component::<Unit>(entity, &mut parts);
+    describe_component::<Owner>(entity, &mut parts);
+    describe_component::<UnitType>(entity, &mut parts);
+    describe_component::<Health>(entity, &mut parts);
+    describe_component::<Armor>(entity, &mut parts);
+    describe_component::<MoveSpeed>(entity, &mut parts);
+    describe_component::<Attack>(entity, &mut parts);
+    describe_component::<Vision>(entity, &mut parts);
+    describe_component::<CollisionAvoidance>(entity, &mut parts);
+    describe_component::<UnitPath>(entity, &mut parts);
+    describe_component::<UnitFlowField>(entity, &mut parts);
+    describe_component::<Orders>(entity, &mut parts);
+    describe_component::<Building>(entity, &mut parts);
+    describe_component::<Resource>(entity, &mut parts);
+    if entity.has::<PathPlanner>() {
+        parts.push("PathPlanner".to_string());
+    }
+    parts.join(" ")
+}
+
+fn describe_component<T: Component + Debug>(entity: &EntityRef, parts: &mut Vec<String>) {
+    if let Some(component) = entity.get::<&T>() {
+        parts.push(format!("{:?}", *component));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::unit_module::spawn_unit;
+
+    #[test]
+    fn test_first_divergence() {
+        let mut sim = Simulation::new();
+        let worker = sim.archetypes.get("Worker").unwrap().clone();
+        let a = spawn_unit(&mut sim.world, &worker, vec2(1.0, 1.0), 0, None, 0.0);
+        let b = spawn_unit(&mut sim.world, &worker, vec2(2.0, 1.0), 0, None, 0.0);
+        let before = sim.snapshot();
+        assert_eq!(before.checksum, sim.checksum());
+        assert_eq!(before.first_divergence(&before.entity_hashes()), None);
+
+        sim.world.get::<&mut Transform>(b).unwrap().position.x += f32::EPSILON * 2.0;
+        let after = sim.snapshot();
+        assert_ne!(after.checksum, before.checksum);
+        assert_eq!(after.first_divergence(&before.entity_hashes()), Some(b));
+        assert_eq!(
+            after.entity(a).unwrap().hash,
+            before.entity(a).unwrap().hash
+        );
+
+        // selection doesn't count
+        let _ = sim
+            .world
+            .insert_one(a, crate::engine::selection_module::SelectedUnit {});
+        assert_eq!(sim.checksum(), after.checksum);
+
+        let _ = sim.world.despawn(a);
+        assert_eq!(
+            sim.snapshot().first_divergence(&after.entity_hashes()),
+            Some(a)
+        );
+    }
+}
@@ -0,0 +1,634 @@
+use comfy::{hecs::With, *};
+use serde::{Deserialize, Serialize};
+
+pub mod checksum;
+pub mod serialization;
+
+use super::{
+    level_module::{block_building_footprints, spawn_level_entities, Map},
+    pathfinding_module::*,
+    selection_module::SelectedUnit,
+    unit_module::{
+        apply_damage_system, apply_path_results_system, cleanup_flow_fields_system,
+        collision_avoidance_system, combat_system, execute_orders_system, give_order,
+        initialize_units, move_units_along_path_system, relocate_stranded_units,
+        remember_positions_system, remove_dead_system, repath_units, replan_paths_system,
+        spawn_unit, DamageQueue, Order, Owner, UnitArchetypes,
+    },
+};
+
+const PATHFINDING_WORKERS: usize = 2;
+
+/// Simulation steps per second.
+pub const TICK_RATE: u32 = 20;
+/// Seconds of game time every step advances.
+pub const TICK_DT: f32 = 1.0 / TICK_RATE as f32;
+/// Path requests are solved in the background and applied this many ticks
+/// after they were made, waiting for the workers if they are late.
+pub const PATH_LATENCY_TICKS: u64 = 2;
+// frames longer than this are slowed down instead of running a burst of ticks
+const MAX_FRAME_TIME: f32 = 0.25;
+
+/// Everything a player can ask the simulation to do. The front end turns
+/// mouse and keyboard input into these; tests and tools build them directly.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Command {
+    SpawnUnit {
+        #[serde(with = "serialization::vec2")]
+        position: Vec2,
+        owner: u32,
+        unit_type: String,
+        /// Overrides the health of the unit type.
+        #[serde(default)]
+        health: Option<i32>,
+        /// Degrees, counter-clockwise from +x.
+        facing: f32,
+        /// Player that gave the command, only `owner` may spawn units.
+        #[serde(default)]
+        player: Option<u32>,
+    },
+    /// Gives `order` to the units, after the orders they already have if
+    /// `queued`.
+    Order {
+        #[serde(with = "serialization::entities")]
+        units: Vec<Entity>,
+        order: Order,
+        queued: bool,
+        /// Player that gave the command, units it doesn't own are left
+        /// alone.
+        #[serde(default)]
+        player: Option<u32>,
+    },
+    /// Makes `units` the selection of the local player. Selecting changes
+    /// nothing in the game, it is only kept so replays show it.
+    Select {
+        #[serde(with = "serialization::entities")]
+        units: Vec<Entity>,
+    },
+    Configure(SimulationConfig),
+}
+
+impl Command {
+    /// Marks the command as given by `player`. Commands without a player
+    /// come from tools and tests and may order any unit; everything that
+    /// arrives over the network is stamped with its sender.
+    pub fn stamp(&mut self, player: u32) {
+        match self {
+            Command::SpawnUnit { player: issuer, .. } | Command::Order { player: issuer, .. } => {
+                *issuer = Some(player)
+            }
+            Command::Select { .. } | Command::Configure(_) => {}
+        }
+    }
+}
+
+/// Settings that change how the simulation plays out, so they are part of
+/// every replay.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct SimulationConfig {
+    pub neighborhood: Neighborhood,
+    pub path_smoothing: PathSmoothing,
+    pub incremental_replanning: bool,
+}
+
+/// Game state and rules without a window: owns its own ECS world and reads
+/// no input, time or rendering globals, so it runs the same under the
+/// comfy front end, in tests and in the headless binary.
+pub struct Simulation {
+    pub world: World,
+    pub board: PathfindingTerrain,
+    /// The grid as the level data has it, without the buildings blocking
+    /// it. Destroyed buildings give their nodes back from it.
+    pub terrain: PathfindingTerrain,
+    pub flow_fields: FlowFieldCache,
+    pub path_requests: PathRequestQueue,
+    /// Unit types that can be spawned.
+    pub archetypes: UnitArchetypes,
+    /// Damage dealt this tick, applied at its end.
+    pub damage: DamageQueue,
+    pub path_smoothing: PathSmoothing,
+    /// Repair blocked paths with D* Lite instead of re-requesting them.
+    pub incremental_replanning: bool,
+    /// Number of steps taken.
+    pub tick: u64,
+    // tick at which the requests submitted before a handle must be applied
+    path_deadlines: VecDeque<(u64, PathRequestHandle)>,
+}
+
+impl Default for Simulation {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Simulation {
+    pub fn new() -> Self {
+        Self {
+            world: World::new(),
+            board: PathfindingTerrain::new(47, 36, None),
+            terrain: PathfindingTerrain::new(47, 36, None),
+            flow_fields: FlowFieldCache::new(),
+            path_requests: PathRequestQueue::new(PATHFINDING_WORKERS),
+            archetypes: UnitArchetypes::builtin(),
+            damage: DamageQueue::new(),
+            path_smoothing: PathSmoothing::default(),
+            incremental_replanning: true,
+            tick: 0,
+            path_deadlines: VecDeque::new(),
+        }
+    }
+
+    /// Builds the grid for the current level of `map` and spawns its
+    /// entities, or the default units if it has none.
+    pub fn load_level(&mut self, map: &Map) {
+        self.set_board_from_level(map);
+        match map.entities() {
+            Some(entities) => spawn_level_entities(
+                &mut self.world,
+                &entities,
+                &self.archetypes,
+                &mut self.board,
+            ),
+            None => initialize_units(&mut self.world, &self.archetypes, &self.board),
+        }
+    }
+
+    /// Drops every unit, building, pending path and flow field and starts
+    /// over from tick 0. The world is replaced rather than emptied, so the
+    /// next level gets the same entities as in a fresh simulation.
+    pub fn clear(&mut self) {
+        self.world = World::new();
+        self.path_requests.cancel_all();
+        self.path_deadlines.clear();
+        self.damage.clear();
+        self.flow_fields = FlowFieldCache::new();
+        self.tick = 0;
+    }
+
+    pub fn config(&self) -> SimulationConfig {
+        SimulationConfig {
+            neighborhood: self.board.neighborhood(),
+            path_smoothing: self.path_smoothing,
+            incremental_replanning: self.incremental_replanning,
+        }
+    }
+
+    pub fn configure(&mut self, config: &SimulationConfig) {
+        self.board.set_neighborhood(config.neighborhood);
+        self.path_smoothing = config.path_smoothing;
+        self.incremental_replanning = config.incremental_replanning;
+    }
+
+    fn set_board_from_level(&mut self, map: &Map) {
+        self.board
+            .set_pathfinding_based_on_level(&map.map.json, map.level());
+        self.terrain = self.board.clone();
+        self.board.enable_hierarchy(DEFAULT_CLUSTER_SIZE);
+    }
+
+    /// Re-derives the grid from the level data after it changed. The grid
+    /// is patched in place when its size didn't change, so only paths
+    /// crossing edited nodes get repaired; otherwise it is rebuilt and every
+    /// moving unit re-paths. Units left standing on blocked nodes are moved
+    /// to the closest free one.
+    pub fn update_board_from_level(&mut self, map: &Map) {
+        let mut fresh = PathfindingTerrain::new(0, 0, None);
+        fresh.set_neighborhood(self.board.neighborhood());
+        fresh.int_grid_table = self.board.int_grid_table.clone();
+        fresh.set_pathfinding_based_on_level(&map.map.json, map.level());
+        self.terrain = fresh.clone();
+        block_building_footprints(&self.world, &mut fresh);
+
+        if !self.board.update_from(&fresh) {
+            self.set_board_from_level(map);
+            block_building_footprints(&self.world, &mut self.board);
+            repath_units(self);
+        }
+        self.board.refresh();
+        relocate_stranded_units(&self.world, &self.board);
+    }
+
+    pub fn apply(&mut self, command: &Command) {
+        match command {
+            Command::SpawnUnit {
+                player: Some(player),
+                owner,
+                ..
+            } if player != owner => {
+                warn!("Ignoring a spawn by player {player} for player {owner}");
+            }
+            Command::SpawnUnit {
+                position,
+                owner,
+                unit_type,
+                health,
+                facing,
+                ..
+            } => match self.archetypes.get(unit_type) {
+                Some(archetype) => {
+                    spawn_unit(
+                        &mut self.world,
+                        archetype,
+                        *position,
+                        *owner,
+                        *health,
+                        *facing,
+                    );
+                }
+                // every client ignores it the same way
+                None => warn!("Ignoring a spawn of unknown unit type {unit_type}"),
+            },
+            Command::Order {
+                units,
+                order,
+                queued,
+                player,
+            } => {
+                let units = units
+                    .iter()
+                    .copied()
+                    .filter(|unit| {
+                        player.is_none_or(|player| {
+                            self.world
+                                .get::<&Owner>(*unit)
+                                .is_ok_and(|owner| owner.0 == player)
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                give_order(self, &units, *order, *queued);
+            }
+            Command::Select { units } => {
+                let mut buffer = CommandBuffer::new();
+                for (entity, _) in self.world.query::<With<(), &SelectedUnit>>().iter() {
+                    if !units.contains(&entity) {
+                        buffer.remove_one::<SelectedUnit>(entity);
+                    }
+                }
+                for entity in units {
+                    if self.world.contains(*entity) {
+                        buffer.insert_one(*entity, SelectedUnit {});
+                    }
+                }
+                buffer.run_on(&mut self.world);
+            }
+            Command::Configure(config) => self.configure(config),
+        }
+    }
+
+    /// Applies `commands` in order, then advances the world by `TICK_DT`.
+    /// The same commands on the same ticks always give the same world.
+    pub fn step(&mut self, commands: &[Command]) {
+        remember_positions_system(&self.world);
+        for command in commands {
+            self.apply(command);
+        }
+
+        execute_orders_system(self);
+        combat_system(self);
+        replan_paths_system(self);
+        apply_path_results_system(self);
+        move_units_along_path_system(self, TICK_DT);
+        collision_avoidance_system(&self.world, TICK_DT);
+        apply_damage_system(self);
+        remove_dead_system(self);
+        cleanup_flow_fields_system(self);
+
+        self.path_deadlines.push_back((
+            self.tick + PATH_LATENCY_TICKS,
+            self.path_requests.next_handle(),
+        ));
+        self.tick += 1;
+    }
+
+    /// Path results due on this tick, in the order they were requested.
+    pub fn take_due_path_results(&mut self) -> Vec<PathResult> {
+        self.board.refresh();
+        // start on everything early, only the collection waits for the deadline
+        self.path_requests.dispatch(&self.board);
+
+        let mut due = None;
+        while let Some(&(tick, handle)) = self.path_deadlines.front() {
+            if tick > self.tick {
+                break;
+            }
+            due = Some(handle);
+            self.path_deadlines.pop_front();
+        }
+        match due {
+            Some(handle) => self.path_requests.finish_before(handle, &self.board),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Turns frame time into simulation ticks for the front end.
+#[derive(Default)]
+pub struct FixedTimestep {
+    accumulator: f32,
+}
+
+impl FixedTimestep {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a frame of `frame_dt` seconds and returns how many ticks are due.
+    pub fn advance(&mut self, frame_dt: f32) -> u32 {
+        self.accumulator += frame_dt.min(MAX_FRAME_TIME);
+        let ticks = (self.accumulator / TICK_DT) as u32;
+        self.accumulator -= ticks as f32 * TICK_DT;
+        ticks
+    }
+
+    /// How far the frame is between the last tick and the next one, used to
+    /// draw units between their previous and current positions.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / TICK_DT).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        level_module::{LevelEntity, DEFAULT_LEVEL, RTS_LEVEL_LDTK},
+        unit_module::{CollisionAvoidance, Health, MoveSpeed, Orders, Unit, UnitType},
+    };
+
+    fn spawn_worker(position: Vec2) -> Command {
+        Command::SpawnUnit {
+            position,
+            owner: 0,
+            unit_type: "Worker".to_string(),
+            health: None,
+            facing: 0.0,
+            player: None,
+        }
+    }
+
+    fn move_to(units: &[Entity], target: Vec2) -> Command {
+        Command::Order {
+            units: units.to_vec(),
+            order: Order::Move(target),
+            queued: false,
+            player: None,
+        }
+    }
+
+    fn position_of(sim: &Simulation, entity: Entity) -> Vec2 {
+        sim.world.get::<&Transform>(entity).unwrap().position
+    }
+
+    #[test]
+    fn test_move_order_without_a_window() {
+        let mut sim = Simulation::new();
+        sim.board = PathfindingTerrain::new(20, 20, None);
+        for y in 0..15 {
+            sim.board.set_node_type(10, y, NodeType::Unwalkable);
+        }
+
+        sim.step(&[spawn_worker(vec2(2.0, 2.0))]);
+        let units = sim
+            .world
+            .query::<&Unit>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        assert_eq!(units.len(), 1);
+
+        let target = vec2(18.0, 2.0);
+        let mut commands = vec![move_to(&units, target)];
+        let mut position = vec2(2.0, 2.0);
+        while position.distance(target) > 0.2 && sim.tick < 200 {
+            sim.step(&commands);
+            commands.clear();
+            position = position_of(&sim, units[0]);
+        }
+        assert!(position.distance(target) <= 0.2, "stopped at {position}");
+        // the straight line would take 32 ticks at 10 tiles a second, going
+        // around the wall takes well over that
+        assert!(sim.tick > 50, "arrived after {} ticks", sim.tick);
+    }
+
+    #[test]
+    fn test_spawned_units_take_their_type() {
+        let mut sim = Simulation::new();
+        let spawn = |unit_type: &str, health| Command::SpawnUnit {
+            position: vec2(2.0, 2.0),
+            owner: 1,
+            unit_type: unit_type.to_string(),
+            health,
+            facing: 0.0,
+            player: None,
+        };
+        sim.step(&[
+            spawn("Tank", None),
+            spawn("Tank", Some(40)),
+            spawn("Dragon", None),
+        ]);
+
+        let mut tanks = sim
+            .world
+            .query::<(&UnitType, &Health, &MoveSpeed, &CollisionAvoidance)>()
+            .iter()
+            .map(|(_, (unit_type, health, speed, avoidance))| {
+                assert_eq!(unit_type.0, "Tank");
+                assert_eq!((speed.0, avoidance.radius), (6.0, 1.5));
+                health.current
+            })
+            .collect::<Vec<_>>();
+        tanks.sort();
+        assert_eq!(tanks, vec![40, 250]);
+    }
+
+    #[test]
+    fn test_players_only_command_their_own_units() {
+        let mut sim = Simulation::new();
+        sim.board = PathfindingTerrain::new(20, 20, None);
+        let worker = sim.archetypes.get("Worker").unwrap().clone();
+        let mine = spawn_unit(&mut sim.world, &worker, vec2(2.0, 2.0), 1, None, 0.0);
+        let theirs = spawn_unit(&mut sim.world, &worker, vec2(4.0, 2.0), 0, None, 0.0);
+
+        let mut order = move_to(&[mine, theirs], vec2(10.0, 10.0));
+        order.stamp(1);
+        let mut spawn = spawn_worker(vec2(6.0, 2.0));
+        spawn.stamp(1);
+        sim.step(&[order, spawn]);
+
+        let has_orders = |unit| !sim.world.get::<&Orders>(unit).unwrap().queue.is_empty();
+        assert!(has_orders(mine));
+        assert!(!has_orders(theirs));
+        assert_eq!(sim.world.query::<&Unit>().iter().count(), 2);
+    }
+
+    #[test]
+    fn test_queued_orders_run_in_turn() {
+        let mut sim = Simulation::new();
+        sim.board = PathfindingTerrain::new(20, 20, None);
+        let worker = sim.archetypes.get("Worker").unwrap().clone();
+        let unit = spawn_unit(&mut sim.world, &worker, vec2(2.0, 2.0), 0, None, 0.0);
+
+        let (first, second) = (vec2(8.0, 2.0), vec2(8.0, 8.0));
+        sim.step(&[move_to(&[unit], first)]);
+        sim.step(&[Command::Order {
+            units: vec![unit],
+            order: Order::Move(second),
+            queued: true,
+            player: None,
+        }]);
+        let mut visited_first = false;
+        while sim.tick < 100 {
+            sim.step(&[]);
+            visited_first |= position_of(&sim, unit).distance(first) < 0.2;
+        }
+        assert!(visited_first);
+        assert!(position_of(&sim, unit).distance(second) < 0.2);
+        assert!(sim.world.get::<&Orders>(unit).unwrap().queue.is_empty());
+
+        // a patrol keeps going back and forth, stop ends it
+        sim.step(&[Command::Order {
+            units: vec![unit],
+            order: Order::Patrol(vec2(8.0, 14.0)),
+            queued: false,
+            player: None,
+        }]);
+        let (mut top, mut bottom) = (0, 0);
+        for _ in 0..150 {
+            sim.step(&[]);
+            let position = position_of(&sim, unit);
+            top += (position.distance(vec2(8.0, 14.0)) < 0.2) as u32;
+            bottom += (position.distance(second) < 0.2) as u32;
+        }
+        assert!(top > 0 && bottom > 1, "top {top}, bottom {bottom}");
+
+        sim.step(&[Command::Order {
+            units: vec![unit],
+            order: Order::Stop,
+            queued: true,
+            player: None,
+        }]);
+        let stopped = position_of(&sim, unit);
+        for _ in 0..10 {
+            sim.step(&[]);
+        }
+        assert_eq!(position_of(&sim, unit), stopped);
+        assert!(sim.world.get::<&Orders>(unit).unwrap().queue.is_empty());
+    }
+
+    fn run_level(ticks: u64) -> Vec<(u32, u32)> {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut sim = Simulation::new();
+        sim.load_level(&map);
+        for i in 0..8 {
+            sim.apply(&spawn_worker(vec2(60.0 + i as f32 * 0.3, -25.5)));
+        }
+
+        let units = sim
+            .world
+            .query::<&Unit>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        let (group, single) = units.split_at(units.len() - 2);
+        for tick in 0..ticks {
+            let commands = match tick {
+                0 => vec![move_to(group, vec2(80.0, -15.5))],
+                3 => vec![move_to(single, vec2(70.0, -5.5))],
+                _ => vec![],
+            };
+            sim.step(&commands);
+        }
+
+        let positions = sim
+            .world
+            .query::<(&Transform, &Unit)>()
+            .iter()
+            .map(|(_, (transform, _))| {
+                (
+                    transform.position.x.to_bits(),
+                    transform.position.y.to_bits(),
+                )
+            })
+            .collect();
+        positions
+    }
+
+    #[test]
+    fn test_same_commands_give_the_same_world() {
+        let first = run_level(80);
+        assert_eq!(first, run_level(80));
+        assert_ne!(first, run_level(5));
+    }
+
+    #[test]
+    fn test_fixed_timestep() {
+        let mut timestep = FixedTimestep::new();
+        assert_eq!(timestep.advance(TICK_DT * 0.5), 0);
+        assert!((timestep.alpha() - 0.5).abs() < 1e-4);
+        assert_eq!(timestep.advance(TICK_DT * 2.0), 2);
+        assert!((timestep.alpha() - 0.5).abs() < 1e-4);
+        // a long hitch doesn't queue up seconds of ticks
+        assert_eq!(timestep.advance(10.0), (MAX_FRAME_TIME / TICK_DT) as u32);
+    }
+
+    #[test]
+    fn test_configure_neighborhood_invalidates_paths() {
+        let mut sim = Simulation::new();
+        sim.board = PathfindingTerrain::new(10, 10, None);
+        let world = World::new();
+        let owner = world.reserve_entity();
+        let goal = ivec2(5, 5);
+        let solve = |sim: &mut Simulation| {
+            sim.path_requests.submit(
+                owner,
+                PathRequest {
+                    start: IVec2::ZERO,
+                    goal,
+                    target: goal.as_vec2(),
+                    agent_radius: 0.5,
+                },
+            );
+            let results = sim
+                .path_requests
+                .finish_before(sim.path_requests.next_handle(), &sim.board);
+            results[0].path.as_ref().unwrap().len()
+        };
+        let field_cost = |sim: &mut Simulation| {
+            sim.flow_fields
+                .get_or_build(&sim.board, goal, 1)
+                .cost_at(0, 0)
+                .unwrap()
+        };
+        assert_eq!(solve(&mut sim), 6);
+        let diagonal_cost = field_cost(&mut sim);
+
+        let mut config = sim.config();
+        config.neighborhood = Neighborhood::Four;
+        sim.configure(&config);
+        assert_eq!(solve(&mut sim), 11);
+        assert!(field_cost(&mut sim) > diagonal_cost);
+    }
+
+    #[test]
+    fn test_load_level_without_a_window() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut sim = Simulation::new();
+        sim.load_level(&map);
+
+        let entities = map.entities().unwrap();
+        let expected_units = entities
+            .iter()
+            .filter(|e| matches!(e, LevelEntity::Unit { .. }))
+            .count();
+        assert_eq!(sim.world.query::<&Unit>().iter().count(), expected_units);
+        assert_eq!(sim.world.len() as usize, entities.len());
+
+        // the headquarters blocks the nodes under it
+        let hq = sim.board.world_to_grid(vec2(75.5, -34.0));
+        let node = sim.board.get_node_by_position(hq.x, hq.y).unwrap();
+        assert_eq!(node.node_type, Some(NodeType::Unwalkable));
+
+        sim.clear();
+        assert!(sim.world.is_empty());
+    }
+}
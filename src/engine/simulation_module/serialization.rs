@@ -0,0 +1,59 @@
+//! `#[serde(with = ...)]` helpers for the comfy types that appear in
+//! commands.
+
+/// `Vec2` as `[x, y]`.
+pub mod vec2 {
+    use comfy::Vec2;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Vec2, serializer: S) -> Result<S::Ok, S::Error> {
+        [value.x, value.y].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec2, D::Error> {
+        let [x, y] = <[f32; 2]>::deserialize(deserializer)?;
+        Ok(Vec2::new(x, y))
+    }
+}
+
+/// `Entity` as its bits. A deterministic simulation hands out the same
+/// entities in the same order, so they stay valid across runs.
+pub mod entity {
+    use comfy::Entity;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Entity, serializer: S) -> Result<S::Ok, S::Error> {
+        value.to_bits().get().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Entity, D::Error> {
+        let bits = u64::deserialize(deserializer)?;
+        Entity::from_bits(bits).ok_or_else(|| D::Error::custom(format!("bad entity {bits}")))
+    }
+}
+
+/// `Vec<Entity>`, see `entity`.
+pub mod entities {
+    use comfy::Entity;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &[Entity], serializer: S) -> Result<S::Ok, S::Error> {
+        value
+            .iter()
+            .map(|entity| entity.to_bits().get())
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Entity>, D::Error> {
+        Vec::<u64>::deserialize(deserializer)?
+            .into_iter()
+            .map(|bits| {
+                Entity::from_bits(bits)
+                    .ok_or_else(|| D::Error::custom(format!("bad entity {bits}")))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+
+use comfy::*;
+use serde::{Deserialize, Serialize};
+
+/// Unit definitions read by the front end and the headless binary, edits
+/// show up without recompiling.
+pub const UNIT_ARCHETYPES_PATH: &str = "assets/units.json";
+
+/// What every unit of a type starts with. Units copy the parts the
+/// simulation needs when they spawn, so a registry loaded later doesn't
+/// change the units already on the field.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UnitArchetype {
+    /// What `UnitType` and spawn commands refer to the type by.
+    pub name: String,
+    /// Tiles per second.
+    pub speed: f32,
+    /// Collision radius, in tiles.
+    pub radius: f32,
+    /// Image under `assets/` drawn instead of the owner coloured circle.
+    #[serde(default)]
+    pub sprite: Option<String>,
+    pub health: i32,
+    /// Taken off every hit, see `damage_after_armor`.
+    #[serde(default)]
+    pub armor: i32,
+    /// How far the unit sees, in tiles.
+    pub vision: f32,
+    #[serde(default)]
+    pub cost: u32,
+    #[serde(default)]
+    pub abilities: Vec<String>,
+    /// Units without one never fight.
+    #[serde(default)]
+    pub attack: Option<AttackStats>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AttackStats {
+    /// Per hit, before armor.
+    pub damage: i32,
+    /// Gap between the attacker and its target at which it can hit, in
+    /// tiles. Melee units have a range close to 0.
+    pub range: f32,
+    /// Seconds between hits.
+    pub cooldown: f32,
+}
+
+impl UnitArchetype {
+    pub fn has_ability(&self, ability: &str) -> bool {
+        self.abilities.iter().any(|a| a == ability)
+    }
+
+    /// Name of the texture `initialize_unit_sprites` loads the sprite as.
+    pub fn texture_name(&self) -> String {
+        format!("unit/{}", self.name)
+    }
+}
+
+#[derive(Debug)]
+pub enum ArchetypeError {
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    /// Two archetypes share this name.
+    Duplicate(String),
+    /// The archetype with this name has a value the game can't use.
+    Invalid { name: String, reason: &'static str },
+}
+
+impl fmt::Display for ArchetypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchetypeError::Io { path, source } => write!(f, "failed to read {path}: {source}"),
+            ArchetypeError::Parse { path, source } => {
+                write!(f, "failed to parse {path}: {source}")
+            }
+            ArchetypeError::Duplicate(name) => write!(f, "unit type {name:?} is defined twice"),
+            ArchetypeError::Invalid { name, reason } => write!(f, "unit type {name:?}: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for ArchetypeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ArchetypeError::Io { source, .. } => Some(source),
+            ArchetypeError::Parse { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Every unit type the game knows, in the order of the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnitArchetypes {
+    archetypes: Vec<UnitArchetype>,
+}
+
+impl UnitArchetypes {
+    /// The definitions in `UNIT_ARCHETYPES_PATH` at build time, so a
+    /// simulation always has the default types.
+    pub fn builtin() -> Self {
+        let source = include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/assets/units.json"));
+        Self::parse(source, UNIT_ARCHETYPES_PATH).expect("the built-in unit types are valid")
+    }
+
+    pub fn load(path: &str) -> Result<Self, ArchetypeError> {
+        let source = std::fs::read_to_string(path).map_err(|source| ArchetypeError::Io {
+            path: path.to_string(),
+            source,
+        })?;
+        Self::parse(&source, path)
+    }
+
+    /// Reads a JSON list of archetypes, `path` is only used in errors.
+    pub fn parse(source: &str, path: &str) -> Result<Self, ArchetypeError> {
+        let archetypes: Vec<UnitArchetype> =
+            serde_json::from_str(source).map_err(|source| ArchetypeError::Parse {
+                path: path.to_string(),
+                source,
+            })?;
+
+        for (i, archetype) in archetypes.iter().enumerate() {
+            let invalid = |reason| ArchetypeError::Invalid {
+                name: archetype.name.clone(),
+                reason,
+            };
+            if archetypes[..i].iter().any(|a| a.name == archetype.name) {
+                return Err(ArchetypeError::Duplicate(archetype.name.clone()));
+            }
+            if archetype.speed <= 0.0 {
+                return Err(invalid("speed must be positive"));
+            }
+            if archetype.radius <= 0.0 {
+                return Err(invalid("radius must be positive"));
+            }
+            if archetype.health <= 0 {
+                return Err(invalid("health must be positive"));
+            }
+            if archetype.armor < 0 {
+                return Err(invalid("armor can't be negative"));
+            }
+            if let Some(attack) = archetype.attack {
+                if attack.damage <= 0 {
+                    return Err(invalid("attack damage must be positive"));
+                }
+                if attack.range < 0.0 {
+                    return Err(invalid("attack range can't be negative"));
+                }
+                if attack.cooldown <= 0.0 {
+                    return Err(invalid("attack cooldown must be positive"));
+                }
+            }
+        }
+        Ok(Self { archetypes })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&UnitArchetype> {
+        self.archetypes
+            .iter()
+            .find(|archetype| archetype.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &UnitArchetype> {
+        self.archetypes.iter()
+    }
+
+    /// The type after `name`, wrapping around, or the first one if `name`
+    /// isn't known.
+    pub fn next_after(&self, name: &str) -> Option<&UnitArchetype> {
+        let next = self
+            .archetypes
+            .iter()
+            .position(|archetype| archetype.name == name)
+            .map_or(0, |i| (i + 1) % self.archetypes.len());
+        self.archetypes.get(next)
+    }
+}
+
+/// Loads the sprites of the archetypes that have one. Types whose sprite
+/// can't be read keep being drawn as circles.
+pub fn initialize_unit_sprites(c: &mut EngineContext, archetypes: &UnitArchetypes) {
+    for archetype in archetypes.iter() {
+        let Some(sprite) = &archetype.sprite else {
+            continue;
+        };
+        let path = format!("assets/{sprite}");
+        match std::fs::read(&path) {
+            Ok(bytes) => c.load_texture_from_bytes(&archetype.texture_name(), &bytes),
+            Err(err) => error!("Failed to load the sprite of {}: {err}", archetype.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_matches_the_file() {
+        let builtin = UnitArchetypes::builtin();
+        assert_eq!(UnitArchetypes::load(UNIT_ARCHETYPES_PATH).unwrap(), builtin);
+
+        let tank = builtin.get("Tank").unwrap();
+        assert_eq!(tank.radius, 1.5);
+        assert!(tank.has_ability("attack"));
+        assert_eq!(builtin.next_after("Tank").unwrap().name, "Worker");
+        assert_eq!(builtin.next_after("Dragon").unwrap().name, "Worker");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let unit = |name: &str, speed: f32| {
+            format!(
+                r#"{{"name": "{name}", "speed": {speed}, "radius": 0.5, "health": 10, "vision": 5}}"#
+            )
+        };
+        let parse = |units: &[String]| {
+            UnitArchetypes::parse(&format!("[{}]", units.join(",")), "units.json")
+        };
+
+        let archetypes = parse(&[unit("Scout", 12.0)]).unwrap();
+        let scout = archetypes.get("Scout").unwrap();
+        assert_eq!(scout.sprite, None);
+        assert!(scout.abilities.is_empty());
+
+        assert!(matches!(
+            parse(&[unit("Scout", 12.0), unit("Scout", 8.0)]),
+            Err(ArchetypeError::Duplicate(name)) if name == "Scout"
+        ));
+        assert!(matches!(
+            parse(&[unit("Scout", 0.0)]),
+            Err(ArchetypeError::Invalid { .. })
+        ));
+        assert!(matches!(
+            UnitArchetypes::parse(r#"[{"name": "Scout"}]"#, "units.json"),
+            Err(ArchetypeError::Parse { .. })
+        ));
+    }
+}
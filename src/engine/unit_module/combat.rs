@@ -0,0 +1,481 @@
+use comfy::*;
+
+use super::{
+    order_move,
+    orders::{halt, is_moving},
+    AttackStats, CollisionAvoidance, Damage, Health, Order, Orders, Owner,
+};
+use crate::engine::{
+    level_module::Building,
+    simulation_module::{Simulation, TICK_RATE},
+    UNIT_Z_INDEX,
+};
+
+/// Ticks between re-paths of a unit chasing a target out of its range.
+pub const CHASE_REPATH_TICKS: u64 = 10;
+// ticks a hit stays drawn
+const HIT_FLASH_TICKS: u64 = 3;
+
+/// Fighting state of a unit that can attack, from its archetype.
+#[derive(Debug)]
+pub struct Attack {
+    pub damage: i32,
+    /// Gap to the target at which it can be hit, in tiles.
+    pub range: f32,
+    pub cooldown_ticks: u64,
+    /// First tick the next hit can land on.
+    pub ready_at: u64,
+    /// What the unit is fighting, if anything.
+    pub target: Option<Entity>,
+    /// Tick of the last hit, for drawing it.
+    pub last_hit: Option<u64>,
+}
+
+impl Attack {
+    pub fn new(stats: &AttackStats) -> Self {
+        Self {
+            damage: stats.damage,
+            range: stats.range,
+            cooldown_ticks: ((stats.cooldown * TICK_RATE as f32).round() as u64).max(1),
+            ready_at: 0,
+            target: None,
+            last_hit: None,
+        }
+    }
+}
+
+/// How far a unit sees, in tiles. Attack-moving units go for hostiles this
+/// close.
+#[derive(Debug)]
+pub struct Vision(pub f32);
+
+pub fn is_hostile(owner: u32, other: u32) -> bool {
+    owner != other
+}
+
+/// Whether `attacker` can be ordered to attack `target`: it has an attack,
+/// and `target` is something else with health owned by someone hostile.
+pub fn can_attack(world: &World, attacker: Entity, target: Entity) -> bool {
+    if attacker == target || !world.satisfies::<&Attack>(attacker).unwrap_or(false) {
+        return false;
+    }
+    let (Ok(owner), Ok(target_owner)) =
+        (world.get::<&Owner>(attacker), world.get::<&Owner>(target))
+    else {
+        return false;
+    };
+    world.satisfies::<&Health>(target).unwrap_or(false) && is_hostile(owner.0, target_owner.0)
+}
+
+/// Whether `entity` is fighting something right now.
+pub fn is_engaged(world: &World, entity: Entity) -> bool {
+    world
+        .get::<&Attack>(entity)
+        .is_ok_and(|attack| attack.target.is_some())
+}
+
+#[derive(Clone, Copy)]
+enum Shape {
+    Circle(f32),
+    Rect(Vec2),
+}
+
+#[derive(Clone, Copy)]
+struct Target {
+    entity: Entity,
+    position: Vec2,
+    shape: Shape,
+    owner: u32,
+}
+
+impl Target {
+    // space between the target and a unit of `radius` at `position`
+    fn gap(&self, position: Vec2, radius: f32) -> f32 {
+        match self.shape {
+            Shape::Circle(target_radius) => {
+                position.distance(self.position) - radius - target_radius
+            }
+            Shape::Rect(size) => {
+                let outside = ((position - self.position).abs() - size / 2.0).max(Vec2::ZERO);
+                outside.length() - radius
+            }
+        }
+    }
+
+    // where a unit at `position` walks to get within `range` of the target;
+    // buildings block their own centre, so the unit heads for their side
+    fn approach(&self, position: Vec2, radius: f32, range: f32) -> Vec2 {
+        match self.shape {
+            Shape::Circle(_) => self.position,
+            Shape::Rect(size) => {
+                let reach = size / 2.0 + radius + range / 2.0;
+                self.position + (position - self.position).clamp(-reach, reach)
+            }
+        }
+    }
+}
+
+struct Attacker {
+    entity: Entity,
+    position: Vec2,
+    radius: f32,
+    owner: u32,
+    order: Option<Order>,
+    vision: f32,
+    range: f32,
+    target: Option<Entity>,
+}
+
+/// Picks targets and lands hits. Units given `Order::Attack` chase their
+/// target until it is destroyed, attack-moving units fight whatever hostile
+/// comes into view on their way, and idle or holding units hit the nearest
+/// hostile in range without leaving their spot. Units busy with any other
+/// order don't fight. Hits go to `Simulation::damage`.
+pub fn combat_system(sim: &mut Simulation) {
+    let mut targets = sim
+        .world
+        .query::<(
+            &Transform,
+            &Owner,
+            &Health,
+            Option<&CollisionAvoidance>,
+            Option<&Building>,
+        )>()
+        .iter()
+        .map(
+            |(entity, (transform, owner, _, avoidance, building))| Target {
+                entity,
+                position: transform.position,
+                shape: match (building, avoidance) {
+                    (Some(building), _) => Shape::Rect(building.size),
+                    (None, Some(avoidance)) => Shape::Circle(avoidance.radius),
+                    (None, None) => Shape::Circle(0.0),
+                },
+                owner: owner.0,
+            },
+        )
+        .collect::<Vec<_>>();
+    // only a tie-break: `pick_target` takes the nearest, and the first of
+    // equally near ones is the same on every client
+    targets.sort_by_key(|target| target.entity.to_bits());
+
+    let attackers = sim
+        .world
+        .query::<(
+            &Transform,
+            &Owner,
+            &Attack,
+            Option<&Orders>,
+            Option<&Vision>,
+            Option<&CollisionAvoidance>,
+        )>()
+        .iter()
+        .map(
+            |(entity, (transform, owner, attack, orders, vision, avoidance))| Attacker {
+                entity,
+                position: transform.position,
+                radius: avoidance.map_or(0.0, |avoidance| avoidance.radius),
+                owner: owner.0,
+                order: orders.and_then(|orders| orders.current().copied()),
+                vision: vision.map_or(0.0, |vision| vision.0),
+                range: attack.range,
+                target: attack.target,
+            },
+        )
+        .collect::<Vec<_>>();
+
+    let mut halted = Vec::new();
+    let mut chases = Vec::new();
+    for attacker in attackers {
+        let target = pick_target(&attacker, &targets);
+        let Ok(mut attack) = sim.world.get::<&mut Attack>(attacker.entity) else {
+            continue;
+        };
+        attack.target = target.map(|target| target.entity);
+        let Some(target) = target else {
+            continue;
+        };
+
+        if matches!(attacker.order, Some(Order::AttackMove(_))) {
+            if let Ok(mut orders) = sim.world.get::<&mut Orders>(attacker.entity) {
+                orders.restart_current();
+            }
+        }
+
+        if target.gap(attacker.position, attacker.radius) <= attack.range {
+            if is_moving(sim, attacker.entity) {
+                halted.push(attacker.entity);
+            }
+            if sim.tick >= attack.ready_at {
+                sim.damage.push(Damage {
+                    target: target.entity,
+                    amount: attack.damage,
+                    source: Some(attacker.entity),
+                });
+                attack.ready_at = sim.tick + attack.cooldown_ticks;
+                attack.last_hit = Some(sim.tick);
+            }
+        } else if matches!(
+            attacker.order,
+            Some(Order::Attack(_) | Order::AttackMove(_))
+        ) && (attacker.target != Some(target.entity)
+            || sim.tick.is_multiple_of(CHASE_REPATH_TICKS)
+            || !is_moving(sim, attacker.entity))
+        {
+            let point = target.approach(attacker.position, attacker.radius, attack.range);
+            chases.push((attacker.entity, point));
+        }
+    }
+
+    halt(sim, &halted);
+    for (entity, point) in chases {
+        order_move(sim, &[entity], point);
+    }
+}
+
+fn pick_target(attacker: &Attacker, targets: &[Target]) -> Option<Target> {
+    let reach = match attacker.order {
+        Some(Order::Attack(target)) => {
+            return targets
+                .iter()
+                .find(|t| {
+                    t.entity == target
+                        && t.entity != attacker.entity
+                        && is_hostile(attacker.owner, t.owner)
+                })
+                .copied();
+        }
+        None | Some(Order::Hold) => attacker.range,
+        Some(Order::AttackMove(_)) => attacker.vision.max(attacker.range),
+        _ => return None,
+    };
+
+    let in_reach = |target: &&Target| {
+        target.entity != attacker.entity
+            && is_hostile(attacker.owner, target.owner)
+            && target.gap(attacker.position, attacker.radius) <= reach
+    };
+    // stick with the current target while it stays in reach
+    if let Some(current) = targets
+        .iter()
+        .filter(in_reach)
+        .find(|target| Some(target.entity) == attacker.target)
+    {
+        return Some(*current);
+    }
+    targets
+        .iter()
+        .filter(in_reach)
+        .min_by(|a, b| {
+            let gap = |target: &Target| target.gap(attacker.position, attacker.radius);
+            gap(a).total_cmp(&gap(b))
+        })
+        .copied()
+}
+
+/// Lines from the units that just hit something to what they hit.
+pub fn draw_attacks(world: &World, tick: u64) {
+    for (_, (transform, attack)) in world.query::<(&Transform, &Attack)>().iter() {
+        let (Some(last_hit), Some(target)) = (attack.last_hit, attack.target) else {
+            continue;
+        };
+        if tick.saturating_sub(last_hit) > HIT_FLASH_TICKS {
+            continue;
+        }
+        if let Ok(target) = world.get::<&Transform>(target) {
+            draw_line(
+                transform.position,
+                target.position,
+                0.06,
+                YELLOW.alpha(0.8),
+                UNIT_Z_INDEX + 1,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        level_module::Resource,
+        pathfinding_module::PathfindingTerrain,
+        simulation_module::Command,
+        unit_module::{spawn_unit, UnitArchetype},
+    };
+
+    fn archetype(sim: &Simulation, name: &str) -> UnitArchetype {
+        sim.archetypes.get(name).unwrap().clone()
+    }
+
+    fn open_field() -> Simulation {
+        let mut sim = Simulation::new();
+        sim.board = PathfindingTerrain::new(40, 20, None);
+        sim
+    }
+
+    fn health_of(sim: &Simulation, entity: Entity) -> Option<i32> {
+        sim.world
+            .get::<&Health>(entity)
+            .ok()
+            .map(|health| health.current)
+    }
+
+    #[test]
+    fn test_idle_units_fight_hostiles_in_range() {
+        let mut sim = open_field();
+        let soldier = archetype(&sim, "Soldier");
+        let worker = archetype(&sim, "Worker");
+        let guard = spawn_unit(&mut sim.world, &soldier, vec2(5.0, 5.0), 0, None, 0.0);
+        let friend = spawn_unit(&mut sim.world, &worker, vec2(5.0, 8.0), 0, None, 0.0);
+        let enemy = spawn_unit(&mut sim.world, &worker, vec2(10.0, 5.0), 1, None, 0.0);
+        let far = spawn_unit(&mut sim.world, &worker, vec2(30.0, 5.0), 1, None, 0.0);
+
+        // 9 damage a second through no armor
+        for _ in 0..TICK_RATE * 6 {
+            sim.step(&[]);
+        }
+        assert_eq!(health_of(&sim, friend), Some(60));
+        assert_eq!(health_of(&sim, far), Some(60));
+        assert_eq!(health_of(&sim, enemy), Some(60 - 6 * 9));
+        // the worker hits back only within its melee range
+        assert_eq!(health_of(&sim, guard), Some(100));
+        assert_eq!(position_of(&sim, guard), vec2(5.0, 5.0));
+
+        for _ in 0..TICK_RATE {
+            sim.step(&[]);
+        }
+        assert_eq!(health_of(&sim, enemy), None);
+        assert!(!is_engaged(&sim.world, guard));
+    }
+
+    fn position_of(sim: &Simulation, entity: Entity) -> Vec2 {
+        sim.world.get::<&Transform>(entity).unwrap().position
+    }
+
+    #[test]
+    fn test_attack_order_chases_a_fleeing_target() {
+        let mut sim = open_field();
+        let soldier = archetype(&sim, "Soldier");
+        let tank = archetype(&sim, "Tank");
+        let hunter = spawn_unit(&mut sim.world, &soldier, vec2(2.0, 10.0), 0, None, 0.0);
+        let prey = spawn_unit(&mut sim.world, &tank, vec2(16.0, 10.0), 1, Some(30), 0.0);
+        let mut prey_attack = sim.world.get::<&mut Attack>(prey).unwrap();
+        // out of range of the hunter at first, and not shooting back
+        prey_attack.range = 0.0;
+        drop(prey_attack);
+
+        sim.step(&[
+            Command::Order {
+                units: vec![hunter],
+                order: Order::Attack(prey),
+                queued: false,
+                player: None,
+            },
+            Command::Order {
+                units: vec![prey],
+                order: Order::Move(vec2(38.0, 10.0)),
+                queued: false,
+                player: None,
+            },
+        ]);
+        while sim.world.contains(prey) && sim.tick < 40 * TICK_RATE as u64 {
+            sim.step(&[]);
+        }
+        assert!(!sim.world.contains(prey), "the hunter never caught up");
+        assert!(position_of(&sim, hunter).x > 20.0);
+        assert!(sim.world.get::<&Orders>(hunter).unwrap().queue.is_empty());
+    }
+
+    #[test]
+    fn test_attack_move_fights_on_the_way() {
+        let mut sim = open_field();
+        let soldier = archetype(&sim, "Soldier");
+        let worker = archetype(&sim, "Worker");
+        let unit = spawn_unit(&mut sim.world, &soldier, vec2(2.0, 10.0), 0, None, 0.0);
+        let enemy = spawn_unit(&mut sim.world, &worker, vec2(20.0, 14.0), 1, None, 0.0);
+
+        let target = vec2(36.0, 10.0);
+        sim.step(&[Command::Order {
+            units: vec![unit],
+            order: Order::AttackMove(target),
+            queued: false,
+            player: None,
+        }]);
+        let mut engaged = false;
+        while sim.tick < 60 * TICK_RATE as u64
+            && (sim.world.contains(enemy) || position_of(&sim, unit).distance(target) > 1.0)
+        {
+            sim.step(&[]);
+            engaged |= is_engaged(&sim.world, unit);
+        }
+        assert!(engaged);
+        assert!(!sim.world.contains(enemy));
+        assert!(position_of(&sim, unit).distance(target) <= 1.0);
+    }
+
+    #[test]
+    fn test_attack_orders_on_what_cant_be_fought_are_dropped() {
+        let mut sim = open_field();
+        let soldier = archetype(&sim, "Soldier");
+        let worker = archetype(&sim, "Worker");
+        let unit = spawn_unit(&mut sim.world, &soldier, vec2(5.0, 5.0), 0, None, 0.0);
+        let friend = spawn_unit(&mut sim.world, &worker, vec2(6.0, 5.0), 0, None, 0.0);
+        let gold = sim.world.spawn((
+            Transform::position(vec2(5.0, 6.0)),
+            Resource {
+                resource_type: "Gold".to_string(),
+                amount: 100,
+            },
+        ));
+
+        for target in [friend, gold, unit] {
+            sim.step(&[Command::Order {
+                units: vec![unit],
+                order: Order::Attack(target),
+                queued: false,
+                player: None,
+            }]);
+            for _ in 0..TICK_RATE {
+                sim.step(&[]);
+            }
+            assert!(sim.world.get::<&Orders>(unit).unwrap().queue.is_empty());
+            assert!(!is_engaged(&sim.world, unit));
+        }
+        assert_eq!(health_of(&sim, friend), Some(60));
+        assert_eq!(health_of(&sim, unit), Some(100));
+    }
+
+    #[test]
+    fn test_attack_targets_are_checked_when_picked() {
+        let mut sim = open_field();
+        let soldier = archetype(&sim, "Soldier");
+        let worker = archetype(&sim, "Worker");
+        let unit = spawn_unit(&mut sim.world, &soldier, vec2(5.0, 5.0), 0, None, 0.0);
+        let friend = spawn_unit(&mut sim.world, &worker, vec2(6.0, 5.0), 0, None, 0.0);
+        let enemy = spawn_unit(&mut sim.world, &worker, vec2(5.0, 6.0), 1, None, 0.0);
+        let targets = [unit, friend, enemy]
+            .map(|entity| Target {
+                entity,
+                position: position_of(&sim, entity),
+                shape: Shape::Circle(0.5),
+                owner: sim.world.get::<&Owner>(entity).unwrap().0,
+            })
+            .to_vec();
+        let attacker = |order| Attacker {
+            entity: unit,
+            position: vec2(5.0, 5.0),
+            radius: 0.5,
+            owner: 0,
+            order: Some(order),
+            vision: 8.0,
+            range: 3.0,
+            target: None,
+        };
+
+        let picked = |order| pick_target(&attacker(order), &targets).map(|target| target.entity);
+        assert_eq!(picked(Order::Attack(enemy)), Some(enemy));
+        assert_eq!(picked(Order::Attack(friend)), None);
+        assert_eq!(picked(Order::Attack(unit)), None);
+    }
+}
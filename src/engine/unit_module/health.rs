@@ -0,0 +1,352 @@
+use comfy::*;
+
+use super::{drop_orders_on, interpolated_position, CollisionAvoidance, PreviousPosition};
+use crate::engine::{
+    level_module::{block_building_footprints, unblock_footprint, Building},
+    selection_module::SelectedUnit,
+    simulation_module::Simulation,
+    UNIT_Z_INDEX,
+};
+
+const HEALTH_BAR_HEIGHT: f32 = 0.15;
+// gap between the top of a unit and its bar, in tiles
+const HEALTH_BAR_MARGIN: f32 = 0.2;
+
+#[derive(Debug)]
+pub struct Health {
+    pub current: i32,
+    pub max: i32,
+}
+
+impl Health {
+    pub fn new(max: i32) -> Self {
+        Self { current: max, max }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0
+    }
+
+    /// Share of `max` left, between 0 and 1. A level can set a building's
+    /// health to 0, which counts as none left.
+    pub fn fraction(&self) -> f32 {
+        if self.max <= 0 {
+            return 0.0;
+        }
+        (self.current.max(0) as f32 / self.max as f32).min(1.0)
+    }
+}
+
+/// Flat reduction of every hit taken.
+#[derive(Debug)]
+pub struct Armor(pub i32);
+
+/// A hit waiting in `DamageQueue`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Damage {
+    pub target: Entity,
+    pub amount: i32,
+    /// What dealt the damage, if anything did.
+    pub source: Option<Entity>,
+}
+
+/// Damage dealt during a tick, applied in the order it was dealt by
+/// `apply_damage_system` near the end of the tick, so every system sees the
+/// same health for the whole tick.
+#[derive(Default)]
+pub struct DamageQueue {
+    hits: Vec<Damage>,
+}
+
+impl DamageQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, damage: Damage) {
+        self.hits.push(damage);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hits.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.hits.clear();
+    }
+}
+
+/// Health lost to a hit of `amount` through `armor`. Armor never turns a
+/// hit into nothing.
+pub fn damage_after_armor(amount: i32, armor: i32) -> i32 {
+    if amount <= 0 {
+        return 0;
+    }
+    (amount - armor).max(1)
+}
+
+/// Takes the queued damage off the health of its targets. Hits on entities
+/// that are already gone are dropped.
+pub fn apply_damage_system(sim: &mut Simulation) {
+    for damage in std::mem::take(&mut sim.damage.hits) {
+        let Ok(mut entity) = sim
+            .world
+            .query_one::<(&mut Health, Option<&Armor>)>(damage.target)
+        else {
+            continue;
+        };
+        let Some((health, armor)) = entity.get() else {
+            continue;
+        };
+        health.current -= damage_after_armor(damage.amount, armor.map_or(0, |armor| armor.0));
+    }
+}
+
+/// Despawns everything whose health ran out, with whatever it had going:
+/// pending paths, orders following it and the grid nodes a building
+/// blocked. Returns the entities removed.
+pub fn remove_dead_system(sim: &mut Simulation) -> Vec<Entity> {
+    let mut dead = sim
+        .world
+        .query::<&Health>()
+        .iter()
+        .filter(|(_, health)| health.is_dead())
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+    if dead.is_empty() {
+        return dead;
+    }
+    dead.sort_by_key(|entity| entity.to_bits());
+
+    let mut unblocked = false;
+    for entity in &dead {
+        sim.path_requests.cancel(*entity);
+        if let Ok(building) = sim.world.get::<&Building>(*entity) {
+            unblock_footprint(&mut sim.board, &sim.terrain, &building);
+            unblocked = true;
+        }
+    }
+    for entity in &dead {
+        let _ = sim.world.despawn(*entity);
+    }
+    // the freed nodes may still be under another building
+    if unblocked {
+        block_building_footprints(&sim.world, &mut sim.board);
+    }
+    drop_orders_on(sim, &dead);
+    dead
+}
+
+/// Bars over the units and buildings that are hurt or selected.
+pub fn draw_health_bars(world: &World, alpha: f32) {
+    for (entity, (transform, previous, health, avoidance, building)) in world
+        .query::<(
+            &Transform,
+            Option<&PreviousPosition>,
+            &Health,
+            Option<&CollisionAvoidance>,
+            Option<&Building>,
+        )>()
+        .iter()
+    {
+        let selected = world.satisfies::<&SelectedUnit>(entity).unwrap_or(false);
+        if health.current >= health.max && !selected {
+            continue;
+        }
+        let size = match (avoidance, building) {
+            (_, Some(building)) => building.size,
+            (Some(avoidance), None) => Vec2::splat(avoidance.radius * 2.0),
+            (None, None) => Vec2::ONE,
+        };
+        let position = interpolated_position(transform, previous, alpha)
+            + vec2(0.0, size.y / 2.0 + HEALTH_BAR_MARGIN);
+
+        let fraction = health.fraction();
+        let width = size.x.max(1.0);
+        draw_rect(
+            position,
+            vec2(width, HEALTH_BAR_HEIGHT),
+            BLACK.alpha(0.6),
+            UNIT_Z_INDEX + 1,
+        );
+        draw_rect(
+            position - vec2(width * (1.0 - fraction) / 2.0, 0.0),
+            vec2(width * fraction, HEALTH_BAR_HEIGHT),
+            RED.mix(GREEN, fraction),
+            UNIT_Z_INDEX + 2,
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::{
+        level_module::{spawn_level_entities, LevelEntity, Map, DEFAULT_LEVEL, RTS_LEVEL_LDTK},
+        pathfinding_module::{NodeType, PathfindingTerrain},
+        unit_module::{spawn_unit, Order, Orders},
+    };
+
+    #[test]
+    fn test_armor() {
+        assert_eq!(damage_after_armor(10, 3), 7);
+        assert_eq!(damage_after_armor(2, 3), 1);
+        assert_eq!(damage_after_armor(0, 3), 0);
+    }
+
+    #[test]
+    fn test_health_fraction() {
+        assert_eq!(
+            Health {
+                current: 5,
+                max: 20
+            }
+            .fraction(),
+            0.25
+        );
+        assert_eq!(
+            Health {
+                current: -5,
+                max: 20
+            }
+            .fraction(),
+            0.0
+        );
+        assert_eq!(Health::new(0).fraction(), 0.0);
+    }
+
+    #[test]
+    fn test_units_die_and_are_forgotten() {
+        let mut sim = Simulation::new();
+        sim.board = PathfindingTerrain::new(20, 20, None);
+        let tank = sim.archetypes.get("Tank").unwrap().clone();
+        let worker = sim.archetypes.get("Worker").unwrap().clone();
+        let target = spawn_unit(&mut sim.world, &tank, vec2(10.0, 10.0), 1, Some(20), 0.0);
+        let follower = spawn_unit(&mut sim.world, &worker, vec2(2.0, 2.0), 0, None, 0.0);
+        sim.step(&[crate::engine::simulation_module::Command::Order {
+            units: vec![follower],
+            order: Order::Follow(target),
+            queued: false,
+            player: None,
+        }]);
+
+        // armor takes 3 off every hit
+        for _ in 0..2 {
+            sim.damage.push(Damage {
+                target,
+                amount: 10,
+                source: Some(follower),
+            });
+        }
+        sim.step(&[]);
+        assert_eq!(sim.world.get::<&Health>(target).unwrap().current, 6);
+
+        sim.damage.push(Damage {
+            target,
+            amount: 10,
+            source: None,
+        });
+        sim.step(&[]);
+        assert!(!sim.world.contains(target));
+        assert!(sim.damage.is_empty());
+        assert!(sim.world.get::<&Orders>(follower).unwrap().queue.is_empty());
+        assert!(!sim.path_requests.is_pending(follower));
+
+        // hits on the dead don't land anywhere
+        sim.damage.push(Damage {
+            target,
+            amount: 10,
+            source: None,
+        });
+        sim.step(&[]);
+    }
+
+    #[test]
+    fn test_destroyed_buildings_free_their_footprint() {
+        let map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut sim = Simulation::new();
+        sim.load_level(&map);
+        let (building, position) = sim
+            .world
+            .query::<(&Transform, &Building)>()
+            .iter()
+            .map(|(entity, (transform, _))| (entity, transform.position))
+            .next()
+            .unwrap();
+        let node = sim.board.world_to_grid(position);
+        let node_type = |sim: &Simulation| {
+            sim.board
+                .get_node_by_position(node.x, node.y)
+                .unwrap()
+                .node_type
+        };
+        assert_eq!(node_type(&sim), Some(NodeType::Unwalkable));
+
+        sim.world.get::<&mut Health>(building).unwrap().current = 0;
+        sim.step(&[]);
+        assert!(!sim.world.contains(building));
+        assert_eq!(node_type(&sim), Some(NodeType::Walkable));
+    }
+
+    #[test]
+    fn test_destroyed_buildings_leave_overlaps_and_edits_blocked() {
+        let mut map = Map::load(RTS_LEVEL_LDTK, DEFAULT_LEVEL).unwrap();
+        let mut sim = Simulation::new();
+        sim.load_level(&map);
+        let (building, position, size, covered) = sim
+            .world
+            .query::<(&Transform, &Building)>()
+            .iter()
+            .map(|(entity, (transform, building))| {
+                (
+                    entity,
+                    transform.position,
+                    building.size,
+                    building.covered.clone(),
+                )
+            })
+            .next()
+            .unwrap();
+        let min = covered
+            .iter()
+            .copied()
+            .min_by_key(|cell| (cell.x, cell.y))
+            .unwrap();
+        let max = covered
+            .iter()
+            .copied()
+            .max_by_key(|cell| (cell.x, cell.y))
+            .unwrap();
+        let node_type = |sim: &Simulation, cell: IVec2| {
+            sim.board
+                .get_node_by_position(cell.x, cell.y)
+                .unwrap()
+                .node_type
+        };
+
+        // another building one tile to the right, sharing all but a column
+        spawn_level_entities(
+            &mut sim.world,
+            &[LevelEntity::Building {
+                position: position + vec2(1.0, 0.0),
+                size,
+                owner: 1,
+                building_type: "Barracks".to_string(),
+                health: 100,
+            }],
+            &sim.archetypes,
+            &mut sim.board,
+        );
+        // and a wall painted under the corner after the building went up
+        let wall = map.tile_with_tag("Unwalkable").unwrap();
+        assert!(map.set_tile(min, Some(wall)));
+        sim.update_board_from_level(&map);
+
+        sim.world.get::<&mut Health>(building).unwrap().current = 0;
+        sim.step(&[]);
+        assert!(!sim.world.contains(building));
+        assert_eq!(node_type(&sim, min), Some(NodeType::Unwalkable));
+        assert_eq!(node_type(&sim, min + ivec2(0, 1)), Some(NodeType::Walkable));
+        assert_eq!(node_type(&sim, max), Some(NodeType::Unwalkable));
+    }
+}
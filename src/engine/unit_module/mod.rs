@@ -1,98 +1,549 @@
-use comfy::{hecs::With, serde_json::de, *};
-use pathfinding::matrix::directions::S;
+use comfy::{hecs::With, *};
 
-use crate::GameState;
+mod archetypes;
+mod combat;
+mod health;
+mod orders;
+pub use archetypes::*;
+pub use combat::*;
+pub use health::*;
+// comfy has a `Health` and a `Damage` of its own
+pub use health::{Damage, Health};
+pub use orders::*;
 
-use super::{selection_module::SelectedUnit, UNIT_Z_INDEX};
+use super::pathfinding_module::{
+    has_line_of_sight_with_clearance, required_clearance, smooth_path, DStarLite, PathRequest,
+    PathfindingTerrain,
+};
+use super::{
+    level_module::Building,
+    selection_module::{selected_units, SelectedUnit},
+    simulation_module::{Command, Simulation},
+    UNIT_Z_INDEX,
+};
 
+// selections at least this big move along a shared flow field
+pub const FLOW_FIELD_MIN_GROUP_SIZE: usize = 5;
+
+pub const DEFAULT_UNIT_TYPE: &str = "Worker";
+
+#[derive(Debug)]
 pub struct Unit;
+
+/// Player controlling a unit or building.
+#[derive(Debug)]
+pub struct Owner(pub u32);
+
+#[derive(Debug)]
+pub struct UnitType(pub String);
+
+/// Tiles per second, from the unit's archetype.
+#[derive(Debug)]
+pub struct MoveSpeed(pub f32);
+
+/// Position at the start of the last simulation tick, units are drawn
+/// between it and `Transform` while the next tick is pending.
+pub struct PreviousPosition(pub Vec2);
+
+#[derive(Debug)]
 pub struct UnitPath {
     pub path: Vec<Vec2>,
     pub current_node: usize,
 }
 
+/// Alternative to `UnitPath` for group orders: the unit follows the cached
+/// flow field towards `goal` and finishes on `target`.
+#[derive(Debug)]
+pub struct UnitFlowField {
+    pub goal: IVec2,
+    pub clearance: i32,
+    pub target: Vec2,
+}
+
+/// D* Lite state kept by a unit whose path was repaired after the terrain
+/// changed, so later edits only redo the affected part of the search.
+pub struct PathPlanner(pub DStarLite);
+
 pub struct MovePoint {
     pub point: Vec2,
     pub time: f32,
 }
 
+#[derive(Debug)]
 pub struct CollisionAvoidance {
     pub radius: f32,
 }
 
-pub fn initialize_units() {
-    commands().spawn((
-        Transform::position(vec2(10.0, 2.1)),
-        Unit,
-        CollisionAvoidance { radius: 0.5 },
-    ));
-    commands().spawn((
-        Transform::position(vec2(11.2, 2.1)),
-        Unit,
-        CollisionAvoidance { radius: 0.5 },
-    ));
-    commands().spawn((
-        Transform::position(vec2(11.5, 2.1)),
+pub fn owner_color(owner: u32) -> Color {
+    [RED, BLUE, GREEN, PURPLE][owner as usize % 4]
+}
+
+/// Spawns a unit of `archetype`, with the archetype's health unless
+/// `health` is given. `facing` is in degrees.
+pub fn spawn_unit(
+    world: &mut World,
+    archetype: &UnitArchetype,
+    position: Vec2,
+    owner: u32,
+    health: Option<i32>,
+    facing: f32,
+) -> Entity {
+    let mut transform = Transform::position(position);
+    transform.rotation = facing.to_radians();
+    let unit = world.spawn((
+        transform,
+        PreviousPosition(position),
         Unit,
-        CollisionAvoidance { radius: 0.5 },
+        Orders::default(),
+        Owner(owner),
+        UnitType(archetype.name.clone()),
+        Health::new(health.unwrap_or(archetype.health)),
+        Armor(archetype.armor),
+        MoveSpeed(archetype.speed),
+        Vision(archetype.vision),
+        CollisionAvoidance {
+            radius: archetype.radius,
+        },
     ));
+    if let Some(attack) = &archetype.attack {
+        let _ = world.insert_one(unit, Attack::new(attack));
+    }
+    unit
+}
+
+/// Starting units for levels without an Entities layer.
+pub fn initialize_units(
+    world: &mut World,
+    archetypes: &UnitArchetypes,
+    board: &PathfindingTerrain,
+) {
+    let origin = board.origin();
+    let units = [
+        ("Worker", vec2(10.0, 2.1)),
+        ("Soldier", vec2(11.2, 2.1)),
+        ("Tank", vec2(11.5, 2.1)),
+    ];
+    for (unit_type, offset) in units {
+        match archetypes.get(unit_type) {
+            Some(archetype) => {
+                spawn_unit(world, archetype, origin + offset, 0, None, 0.0);
+            }
+            None => warn!("No unit type {unit_type}, the starting unit is left out"),
+        }
+    }
+}
+
+/// Despawns the move order markers of the front end.
+pub fn teardown_move_points() {
+    for (entity, _) in world().query::<&MovePoint>().iter() {
+        commands().despawn(entity);
+    }
+}
+
+/// Turns mouse and keyboard input into orders for the selected units of
+/// `world` that belong to `player`. Holding shift queues them after the
+/// current ones.
+pub fn issue_orders_system(world: &World, player: u32, input: &mut Vec<Command>) {
+    let target = mouse_world();
+    let order = if is_mouse_button_pressed(MouseButton::Right) {
+        match unit_at(world, target).or_else(|| building_at(world, target)) {
+            Some(entity) if is_enemy_of(world, entity, player) => Order::Attack(entity),
+            Some(unit)
+                if world.satisfies::<&Unit>(unit).unwrap_or(false)
+                    && !world.satisfies::<&SelectedUnit>(unit).unwrap_or(false) =>
+            {
+                Order::Follow(unit)
+            }
+            _ => Order::Move(target),
+        }
+    } else if is_key_pressed(KeyCode::F) {
+        Order::AttackMove(target)
+    } else if is_key_pressed(KeyCode::Q) {
+        Order::Patrol(target)
+    } else if is_key_pressed(KeyCode::H) {
+        Order::Hold
+    } else if is_key_pressed(KeyCode::X) {
+        Order::Stop
+    } else {
+        return;
+    };
+
+    let units = selected_units(world)
+        .into_iter()
+        .filter(|unit| {
+            world
+                .get::<&Owner>(*unit)
+                .is_ok_and(|owner| owner.0 == player)
+        })
+        .collect::<Vec<_>>();
+    if units.is_empty() {
+        return;
+    }
+
+    input.push(Command::Order {
+        units,
+        order,
+        queued: is_key_down(KeyCode::LShift) || is_key_down(KeyCode::RShift),
+        player: Some(player),
+    });
+    if let Some(point) = order.point() {
+        commands().spawn((MovePoint { point, time: 0.0 }, Transform::position(point)));
+    }
+}
+
+// The unit whose circle contains `point`.
+fn unit_at(world: &World, point: Vec2) -> Option<Entity> {
+    world
+        .query::<With<(&Transform, &CollisionAvoidance), &Unit>>()
+        .iter()
+        .find(|(_, (transform, avoidance))| transform.position.distance(point) <= avoidance.radius)
+        .map(|(entity, _)| entity)
+}
+
+// The building whose footprint contains `point`.
+fn building_at(world: &World, point: Vec2) -> Option<Entity> {
+    world
+        .query::<(&Transform, &Building)>()
+        .iter()
+        .find(|(_, (transform, building))| {
+            let offset = (point - transform.position).abs();
+            offset.x <= building.size.x / 2.0 && offset.y <= building.size.y / 2.0
+        })
+        .map(|(entity, _)| entity)
+}
+
+fn is_enemy_of(world: &World, entity: Entity, player: u32) -> bool {
+    world.satisfies::<&Health>(entity).unwrap_or(false)
+        && world
+            .get::<&Owner>(entity)
+            .is_ok_and(|owner| is_hostile(player, owner.0))
+}
+
+/// Sends `units` towards `target`. Big groups share one flow field,
+/// smaller ones get a path request each.
+pub fn order_move(sim: &mut Simulation, units: &[Entity], target: Vec2) {
+    sim.board.refresh();
+
+    let units = units
+        .iter()
+        .filter_map(|entity| {
+            let position = sim.world.get::<&Transform>(*entity).ok()?.position;
+            Some((*entity, position))
+        })
+        .collect::<Vec<_>>();
+
+    let goal = sim.board.world_to_grid(target);
+    let Some(goal_node) = sim.board.get_node_by_position(goal.x, goal.y) else {
+        return;
+    };
+
+    let mut buffer = CommandBuffer::new();
+    // big groups share one flow field instead of running A* per unit
+    if units.len() >= FLOW_FIELD_MIN_GROUP_SIZE {
+        let goal = ivec2(goal_node.x, goal_node.y);
+        // one field sized for the biggest unit keeps the group together
+        let clearance = units
+            .iter()
+            .map(|(entity, _)| required_clearance(radius_of(&sim.world, *entity)))
+            .max()
+            .unwrap_or(1);
+        sim.flow_fields.get_or_build(&sim.board, goal, clearance);
+        for (entity, _) in &units {
+            sim.path_requests.cancel(*entity);
+            buffer.remove_one::<UnitPath>(*entity);
+            buffer.remove_one::<PathPlanner>(*entity);
+            buffer.insert_one(
+                *entity,
+                UnitFlowField {
+                    goal,
+                    clearance,
+                    target,
+                },
+            );
+        }
+    } else {
+        for (entity, position) in units {
+            buffer.remove_one::<UnitPath>(entity);
+            buffer.remove_one::<PathPlanner>(entity);
+            buffer.remove_one::<UnitFlowField>(entity);
+
+            sim.path_requests.submit(
+                entity,
+                PathRequest {
+                    start: sim.board.world_to_grid(position),
+                    goal: ivec2(goal_node.x, goal_node.y),
+                    target,
+                    agent_radius: radius_of(&sim.world, entity),
+                },
+            );
+        }
+    }
+    buffer.run_on(&mut sim.world);
+}
+
+fn radius_of(world: &World, entity: Entity) -> f32 {
+    world
+        .get::<&CollisionAvoidance>(entity)
+        .map(|avoidance| avoidance.radius)
+        .unwrap_or(0.5)
+}
+
+pub fn apply_path_results_system(sim: &mut Simulation) {
+    let mut buffer = CommandBuffer::new();
+    for result in sim.take_due_path_results() {
+        let Some(path) = result.path else {
+            continue;
+        };
+        let Ok(start) = sim
+            .world
+            .get::<&Transform>(result.owner)
+            .map(|transform| transform.position)
+        else {
+            continue;
+        };
+        // too tight goals are moved to the closest node the unit fits on
+        let target = match path.last() {
+            Some(last) if ivec2(last.x, last.y) != result.request.goal => {
+                sim.board.grid_to_world(ivec2(last.x, last.y))
+            }
+            _ => result.request.target,
+        };
+        let points = smooth_path(
+            &sim.board,
+            start,
+            &path,
+            target,
+            sim.path_smoothing,
+            result.request.agent_radius,
+        );
+        buffer.remove_one::<PathPlanner>(result.owner);
+        buffer.insert_one(
+            result.owner,
+            UnitPath {
+                path: points,
+                current_node: 0,
+            },
+        );
+    }
+    buffer.run_on(&mut sim.world);
 }
 
-pub fn get_path_for_selected_units_system(state: &mut GameState) {
-    if is_mouse_button_pressed(MouseButton::Right) {
-        let target = mouse_world();
-
-        for (entity, transform) in world().query::<With<&Transform, &SelectedUnit>>().iter() {
-            commands().remove_one::<&UnitPath>(entity);
-
-            let start_node = state
-                .board
-                .get_node_by_position(transform.position.x as i32, transform.position.y as i32);
-            let goal_node = state
-                .board
-                .get_node_by_position(target.x as i32, target.y as i32);
-            if let Some((start_node, goal_node)) = start_node.zip(goal_node) {
-                let path = state.board.get_path(start_node, goal_node);
-                if let Some(path) = path {
-                    let points = path
-                        .iter()
-                        .map(|node| vec2(node.x as f32, node.y as f32))
-                        .collect();
-                    commands().insert_one(
-                        entity,
-                        UnitPath {
-                            path: points,
-                            current_node: 0,
-                        },
-                    );
-                    commands().spawn((
-                        MovePoint {
-                            point: target,
-                            time: 0.0,
-                        },
-                        Transform::position(target),
-                    ));
-                }
+/// Repairs the paths that cross nodes edited since the last frame. With
+/// `sim.incremental_replanning` the unit keeps a D* Lite planner across
+/// edits, otherwise a fresh request goes to the worker pool.
+pub fn replan_paths_system(sim: &mut Simulation) {
+    let changes = sim.board.take_changes();
+    if changes.is_empty() {
+        return;
+    }
+    sim.board.refresh();
+
+    let mut buffer = CommandBuffer::new();
+    for (entity, (transform, path, avoidance, mut planner)) in sim
+        .world
+        .query::<(
+            &Transform,
+            &mut UnitPath,
+            &CollisionAvoidance,
+            Option<&mut PathPlanner>,
+        )>()
+        .iter()
+    {
+        let position = transform.position;
+        let from = sim.board.world_to_grid(position);
+        let clearance = required_clearance(avoidance.radius);
+
+        // planners must see every edit to stay consistent, even the ones
+        // that don't touch the current path
+        if let Some(PathPlanner(planner)) = &mut planner {
+            planner.notify_changes(&sim.board, from, &changes);
+        }
+
+        let Some(&target) = path.path.last() else {
+            continue;
+        };
+        let origin = sim.board.origin();
+        let mut previous = position - origin;
+        let blocked = path.path[path.current_node.min(path.path.len())..]
+            .iter()
+            .any(|point| {
+                let point = *point - origin;
+                let visible =
+                    has_line_of_sight_with_clearance(&sim.board, previous, point, clearance);
+                previous = point;
+                !visible
+            });
+        if !blocked {
+            continue;
+        }
+
+        let goal = sim.board.world_to_grid(target);
+        if !sim.incremental_replanning {
+            buffer.remove_one::<UnitPath>(entity);
+            sim.path_requests.submit(
+                entity,
+                PathRequest {
+                    start: from,
+                    goal,
+                    target,
+                    agent_radius: avoidance.radius,
+                },
+            );
+            continue;
+        }
+
+        let repaired = match &mut planner {
+            Some(PathPlanner(planner)) => planner.path(&sim.board),
+            None => {
+                let mut planner = DStarLite::new(from, goal, clearance);
+                let repaired = planner.path(&sim.board);
+                buffer.insert_one(entity, PathPlanner(planner));
+                repaired
+            }
+        };
+
+        match repaired {
+            Some(nodes) => {
+                path.path = smooth_path(
+                    &sim.board,
+                    position,
+                    &nodes,
+                    target,
+                    sim.path_smoothing,
+                    avoidance.radius,
+                );
+                path.current_node = 0;
+            }
+            // the goal was walled off, stop where we are
+            None => {
+                buffer.remove_one::<UnitPath>(entity);
+                buffer.remove_one::<PathPlanner>(entity);
             }
         }
     }
+    buffer.run_on(&mut sim.world);
+}
+
+pub fn remember_positions_system(world: &World) {
+    for (_, (transform, previous)) in world.query::<(&Transform, &mut PreviousPosition)>().iter() {
+        previous.0 = transform.position;
+    }
+}
+
+// Moves `position` up to `distance` towards `target`, returns whether it
+// got there.
+fn step_towards(position: &mut Vec2, target: Vec2, distance: f32) -> bool {
+    let offset = target - *position;
+    if offset.length() <= distance {
+        *position = target;
+        true
+    } else {
+        *position += offset.normalize() * distance;
+        false
+    }
 }
 
-pub fn move_units_along_path_system() {
-    for (entity, (transform, path)) in world().query::<(&mut Transform, &mut UnitPath)>().iter() {
-        if path.current_node < path.path.len() {
+pub fn move_units_along_path_system(sim: &mut Simulation, dt: f32) {
+    let mut buffer = CommandBuffer::new();
+
+    for (entity, (transform, path, speed)) in sim
+        .world
+        .query::<(&mut Transform, &mut UnitPath, &MoveSpeed)>()
+        .iter()
+    {
+        // a tick covers several path points when they are close together
+        let mut budget = speed.0 * dt;
+        while budget > 0.0 && path.current_node < path.path.len() {
             let target = path.path[path.current_node];
-            let direction = target - transform.position;
-            let distance = direction.length();
-            let speed = 10.0;
-            let velocity = direction.normalize() * speed * delta();
-            if distance < 0.1 {
+            let distance = transform.position.distance(target);
+            if step_towards(&mut transform.position, target, budget) {
                 path.current_node += 1;
-            } else {
-                transform.position += velocity;
             }
-        } else {
-            commands().remove_one::<&UnitPath>(entity);
+            budget -= distance;
+        }
+        if path.current_node >= path.path.len() {
+            buffer.remove_one::<UnitPath>(entity);
+            buffer.remove_one::<PathPlanner>(entity);
+        }
+    }
+
+    for (entity, (transform, flow, speed)) in sim
+        .world
+        .query::<(&mut Transform, &UnitFlowField, &MoveSpeed)>()
+        .iter()
+    {
+        let field = sim
+            .flow_fields
+            .get_or_build(&sim.board, flow.goal, flow.clearance);
+        let node = sim.board.world_to_grid(transform.position);
+
+        // head for the centre of the next node, or the clicked point once in
+        // the goal node
+        let target = match field.direction_at(node.x, node.y) {
+            Some(IVec2::ZERO) => flow.target,
+            Some(step) => sim.board.grid_to_world(node + step),
+            None => {
+                buffer.remove_one::<UnitFlowField>(entity);
+                continue;
+            }
+        };
+
+        if step_towards(&mut transform.position, target, speed.0 * dt) && target == flow.target {
+            buffer.remove_one::<UnitFlowField>(entity);
+        }
+    }
+    buffer.run_on(&mut sim.world);
+}
+pub fn cleanup_flow_fields_system(sim: &mut Simulation) {
+    let goals = sim
+        .world
+        .query::<&UnitFlowField>()
+        .iter()
+        .map(|(_, flow)| (flow.goal, flow.clearance))
+        .collect::<HashSet<_>>();
+    sim.flow_fields.retain_goals(&goals);
+}
+
+/// Requests a fresh path for every unit following one, e.g. after the grid
+/// was rebuilt and the old paths and planners no longer match it.
+pub fn repath_units(sim: &mut Simulation) {
+    let mut buffer = CommandBuffer::new();
+    for (entity, (transform, path, avoidance)) in sim
+        .world
+        .query::<(&Transform, &UnitPath, &CollisionAvoidance)>()
+        .iter()
+    {
+        buffer.remove_one::<UnitPath>(entity);
+        buffer.remove_one::<PathPlanner>(entity);
+        let Some(&target) = path.path.last() else {
+            continue;
+        };
+        sim.path_requests.submit(
+            entity,
+            PathRequest {
+                start: sim.board.world_to_grid(transform.position),
+                goal: sim.board.world_to_grid(target),
+                target,
+                agent_radius: avoidance.radius,
+            },
+        );
+    }
+    buffer.run_on(&mut sim.world);
+}
+/// Moves units standing on nodes they no longer fit on to the closest node
+/// they do. The terrain should be refreshed.
+pub fn relocate_stranded_units(world: &World, board: &PathfindingTerrain) {
+    for (_, (transform, avoidance)) in world
+        .query::<With<(&mut Transform, &CollisionAvoidance), &Unit>>()
+        .iter()
+    {
+        let clearance = required_clearance(avoidance.radius);
+        let node = board.world_to_grid(transform.position);
+        if board.clearance_at(node.x, node.y) >= clearance {
+            continue;
+        }
+        if let Some(free) = board.nearest_node_with_clearance(node.x, node.y, clearance) {
+            transform.position = board.grid_to_world(ivec2(free.x, free.y));
         }
     }
 }
@@ -111,20 +562,25 @@ pub fn cleanup_move_points_system() {
     }
 }
 
-pub fn collision_avoidance_system() {
+pub fn collision_avoidance_system(world: &World, dt: f32) {
     let mut unit_positions = Vec::new();
 
-    for (entity, (transform, _, avoidance)) in world()
-        .query::<(&Transform, &Unit, &CollisionAvoidance)>()
+    for (entity, (transform, _, avoidance, orders)) in world
+        .query::<(&Transform, &Unit, &CollisionAvoidance, Option<&Orders>)>()
         .iter()
     {
-        unit_positions.push((entity, transform.position, avoidance.radius));
+        let holding = orders.is_some_and(|orders| orders.is_holding());
+        unit_positions.push((entity, transform.position, avoidance.radius, holding));
     }
 
-    for (entity, position, radius) in &unit_positions {
+    for (entity, position, radius, holding) in &unit_positions {
+        // units holding their position push others away but don't give way
+        if *holding {
+            continue;
+        }
         let mut avoidance_vector = Vec2::ZERO;
 
-        for (other_entity, other_position, other_radius) in &unit_positions {
+        for (other_entity, other_position, other_radius, _) in &unit_positions {
             if entity == other_entity {
                 continue;
             }
@@ -138,29 +594,70 @@ pub fn collision_avoidance_system() {
         }
 
         if avoidance_vector != Vec2::ZERO {
-            if let Ok(mut transform_q) = world().query_one::<(&mut Transform, &Unit)>(*entity) {
-                if let Some((transform, _)) = transform_q.get() {
-                    transform.position += avoidance_vector * delta();
-                }
+            if let Ok(mut transform) = world.get::<&mut Transform>(*entity) {
+                transform.position += avoidance_vector * dt;
             }
         }
     }
 }
 
-pub fn spawn_unit_at_mouse_position_system() {
-    let mouse_pos = mouse_world();
+/// Space spawns a unit of `unit_type` under the cursor.
+pub fn spawn_unit_at_mouse_position_system(player: u32, unit_type: &str, input: &mut Vec<Command>) {
     if is_key_pressed(KeyCode::Space) {
-        commands().spawn((
-            Transform::position(mouse_pos),
-            Unit,
-            CollisionAvoidance { radius: 0.5 },
-        ));
+        input.push(Command::SpawnUnit {
+            position: mouse_world(),
+            owner: player,
+            unit_type: unit_type.to_string(),
+            health: None,
+            facing: 0.0,
+            player: Some(player),
+        });
     }
 }
 
-pub fn draw_units() {
-    for (_, (transform, _)) in world().query::<(&Transform, &Unit)>().iter() {
-        draw_circle(transform.position, 0.5, RED, UNIT_Z_INDEX);
+/// Where to draw a unit `alpha` of the way between the last two ticks.
+pub fn interpolated_position(
+    transform: &Transform,
+    previous: Option<&PreviousPosition>,
+    alpha: f32,
+) -> Vec2 {
+    match previous {
+        Some(previous) => previous.0.lerp(transform.position, alpha),
+        None => transform.position,
+    }
+}
+
+/// Draws units with the sprite of their archetype, tinted with the owner's
+/// colour, or as circles when it has none.
+pub fn draw_units(world: &World, archetypes: &UnitArchetypes, alpha: f32) {
+    for (_, (transform, previous, _, avoidance, owner, unit_type)) in world
+        .query::<(
+            &Transform,
+            Option<&PreviousPosition>,
+            &Unit,
+            &CollisionAvoidance,
+            Option<&Owner>,
+            Option<&UnitType>,
+        )>()
+        .iter()
+    {
+        let color = owner.map(|owner| owner_color(owner.0)).unwrap_or(RED);
+        let position = interpolated_position(transform, previous, alpha);
+        let sprite = unit_type
+            .and_then(|unit_type| archetypes.get(&unit_type.0))
+            .filter(|archetype| archetype.sprite.is_some())
+            .and_then(|archetype| texture_id_safe(&archetype.texture_name()));
+        match sprite {
+            Some(texture) => draw_sprite_rot(
+                texture,
+                position,
+                color,
+                UNIT_Z_INDEX,
+                transform.rotation,
+                Vec2::splat(avoidance.radius * 2.0),
+            ),
+            None => draw_circle(position, avoidance.radius, color, UNIT_Z_INDEX),
+        }
     }
 }
 
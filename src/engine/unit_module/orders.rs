@@ -0,0 +1,260 @@
+use comfy::{hecs::With, *};
+use serde::{Deserialize, Serialize};
+
+use super::{
+    can_attack, is_engaged, order_move, PathPlanner, UnitFlowField, UnitPath, UNIT_Z_INDEX,
+};
+use crate::engine::{
+    selection_module::SelectedUnit,
+    simulation_module::{serialization, Simulation},
+};
+
+/// Ticks between re-paths of a unit following another one.
+pub const FOLLOW_REPATH_TICKS: u64 = 10;
+/// How close a following unit stays to its target, in tiles.
+pub const FOLLOW_DISTANCE: f32 = 2.0;
+
+/// Something a unit was told to do. Orders reach the simulation through
+/// `Command::Order`, whether they come from the player, the AI, a replay or
+/// the network.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Order {
+    /// Walk to a point.
+    Move(#[serde(with = "serialization::vec2")] Vec2),
+    /// Drop every order and stand still. Never queued, it always applies
+    /// right away.
+    Stop,
+    /// Stand still without being pushed aside, until given another order.
+    Hold,
+    /// Walk back and forth between where the order started and a point.
+    Patrol(#[serde(with = "serialization::vec2")] Vec2),
+    /// Walk to a point like `Move`, fighting what is met on the way.
+    AttackMove(#[serde(with = "serialization::vec2")] Vec2),
+    /// Keep close to another unit for as long as it exists.
+    Follow(#[serde(with = "serialization::entity")] Entity),
+    /// Chase a hostile unit or building and fight it until it is destroyed.
+    /// Dropped right away when it can't be fought, see `can_attack`.
+    Attack(#[serde(with = "serialization::entity")] Entity),
+}
+
+impl Order {
+    /// The point the order walks to, if it has a fixed one.
+    pub fn point(&self) -> Option<Vec2> {
+        match self {
+            Order::Move(point) | Order::Patrol(point) | Order::AttackMove(point) => Some(*point),
+            Order::Stop | Order::Hold | Order::Follow(_) | Order::Attack(_) => None,
+        }
+    }
+}
+
+/// Orders of a unit, the front one is being executed and the rest wait
+/// their turn.
+#[derive(Debug, Default)]
+pub struct Orders {
+    pub queue: VecDeque<Order>,
+    // where the unit stood when the front order started, `None` until then
+    started_at: Option<Vec2>,
+}
+
+impl Orders {
+    pub fn current(&self) -> Option<&Order> {
+        self.queue.front()
+    }
+
+    /// Where the unit stood when the front order started.
+    pub fn started_at(&self) -> Option<Vec2> {
+        self.started_at
+    }
+
+    pub fn is_holding(&self) -> bool {
+        self.current() == Some(&Order::Hold)
+    }
+
+    fn finish_current(&mut self) {
+        self.queue.pop_front();
+        self.started_at = None;
+    }
+
+    /// Starts the front order over, e.g. an attack-move picking up its
+    /// walk again after a fight.
+    pub fn restart_current(&mut self) {
+        self.started_at = None;
+    }
+}
+
+/// Gives `order` to `units`. Queued orders run once the current ones are
+/// done, otherwise they replace them and the units stop what they were
+/// doing.
+pub fn give_order(sim: &mut Simulation, units: &[Entity], order: Order, queued: bool) {
+    let mut interrupted = Vec::new();
+    for entity in units {
+        let Ok(mut orders) = sim.world.get::<&mut Orders>(*entity) else {
+            continue;
+        };
+        if !queued || order == Order::Stop {
+            orders.queue.clear();
+            orders.started_at = None;
+            interrupted.push(*entity);
+        }
+        if order != Order::Stop {
+            orders.queue.push_back(order);
+        }
+    }
+    halt(sim, &interrupted);
+}
+
+// Drops whatever movement the units had going.
+pub(super) fn halt(sim: &mut Simulation, units: &[Entity]) {
+    let mut buffer = CommandBuffer::new();
+    for entity in units {
+        sim.path_requests.cancel(*entity);
+        buffer.remove_one::<UnitPath>(*entity);
+        buffer.remove_one::<PathPlanner>(*entity);
+        buffer.remove_one::<UnitFlowField>(*entity);
+    }
+    buffer.run_on(&mut sim.world);
+}
+
+/// Drops the orders following or attacking any of `targets`, which are
+/// gone. Units that were on their way stop.
+pub fn drop_orders_on(sim: &mut Simulation, targets: &[Entity]) {
+    let aimed_at_target = |order: &Order| matches!(order, Order::Follow(target) | Order::Attack(target) if targets.contains(target));
+    let mut stopped = Vec::new();
+    for (entity, orders) in sim.world.query::<&mut Orders>().iter() {
+        let was_following = orders.current().is_some_and(aimed_at_target);
+        orders.queue.retain(|order| !aimed_at_target(order));
+        if was_following {
+            orders.started_at = None;
+            stopped.push(entity);
+        }
+    }
+    halt(sim, &stopped);
+}
+
+pub(super) fn is_moving(sim: &Simulation, entity: Entity) -> bool {
+    sim.path_requests.is_pending(entity)
+        || sim
+            .world
+            .entity(entity)
+            .is_ok_and(|e| e.has::<UnitPath>() || e.has::<UnitFlowField>())
+}
+
+/// Finishes the orders that are done and starts the ones next in line.
+pub fn execute_orders_system(sim: &mut Simulation) {
+    let mut stop = Vec::new();
+    let mut follow = Vec::new();
+    let mut orders_query = sim.world.query::<(&Transform, &mut Orders)>();
+    for (entity, (transform, orders)) in orders_query.iter() {
+        let Some(started_at) = orders.started_at else {
+            continue;
+        };
+        match *orders.current().unwrap() {
+            // the combat system does the chasing
+            Order::Attack(target) => {
+                if !can_attack(&sim.world, entity, target) {
+                    orders.finish_current();
+                    stop.push(entity);
+                }
+            }
+            Order::Move(_) | Order::AttackMove(_) => {
+                if !is_moving(sim, entity) {
+                    orders.finish_current();
+                }
+            }
+            Order::Patrol(_) => {
+                if !is_moving(sim, entity) {
+                    // walk the leg back, the queue behind a patrol never runs
+                    orders.queue[0] = Order::Patrol(started_at);
+                    orders.started_at = None;
+                }
+            }
+            Order::Follow(target) => {
+                let Ok(target_position) = sim.world.get::<&Transform>(target).map(|t| t.position)
+                else {
+                    orders.finish_current();
+                    stop.push(entity);
+                    continue;
+                };
+                if transform.position.distance(target_position) <= FOLLOW_DISTANCE {
+                    stop.push(entity);
+                } else if sim.tick.is_multiple_of(FOLLOW_REPATH_TICKS) || !is_moving(sim, entity) {
+                    follow.push((entity, target_position));
+                }
+            }
+            Order::Hold | Order::Stop => {}
+        }
+    }
+    drop(orders_query);
+
+    // units given the same point walk there as a group, so big selections
+    // still share a flow field
+    let mut groups: Vec<(Vec2, Vec<Entity>)> = Vec::new();
+    for (entity, (transform, orders)) in sim.world.query::<(&Transform, &mut Orders)>().iter() {
+        if orders.started_at.is_some() {
+            continue;
+        }
+        let Some(order) = orders.current().copied() else {
+            continue;
+        };
+        // an attack-move fighting on its way walks on after the fight
+        if matches!(order, Order::AttackMove(_)) && is_engaged(&sim.world, entity) {
+            continue;
+        }
+        orders.started_at = Some(transform.position);
+        let target = match order {
+            Order::Attack(target) => {
+                if !can_attack(&sim.world, entity, target) {
+                    orders.finish_current();
+                }
+                continue;
+            }
+            Order::Follow(target) => match sim.world.get::<&Transform>(target) {
+                Ok(target) => target.position,
+                Err(_) => continue,
+            },
+            Order::Hold | Order::Stop => {
+                stop.push(entity);
+                continue;
+            }
+            order => order.point().unwrap(),
+        };
+        match groups.iter_mut().find(|(point, _)| *point == target) {
+            Some((_, group)) => group.push(entity),
+            None => groups.push((target, vec![entity])),
+        }
+    }
+
+    halt(sim, &stop);
+    for (target, group) in groups {
+        order_move(sim, &group, target);
+    }
+    for (entity, target) in follow {
+        order_move(sim, &[entity], target);
+    }
+}
+
+/// Lines through the points queued for the selected units.
+pub fn draw_queued_orders(world: &World) {
+    for (_, (transform, orders)) in world
+        .query::<With<(&Transform, &Orders), &SelectedUnit>>()
+        .iter()
+    {
+        let mut from = transform.position;
+        for order in &orders.queue {
+            let to = match order {
+                Order::Follow(target) | Order::Attack(target) => {
+                    match world.get::<&Transform>(*target) {
+                        Ok(target) => target.position,
+                        Err(_) => break,
+                    }
+                }
+                order => match order.point() {
+                    Some(point) => point,
+                    None => break,
+                },
+            };
+            draw_line(from, to, 0.05, GREEN.alpha(0.5), UNIT_Z_INDEX - 1);
+            from = to;
+        }
+    }
+}
@@ -1,21 +1,26 @@
 use comfy::*;
-mod engine;
+use rts_game_engine::engine;
 
+use engine::editor_module::*;
 use engine::game_module::*;
-use engine::pathfinding_module::*;
+use engine::level_module::draw_level_entities;
+use engine::replay_module::playback_system;
 use engine::selection_module::deselect_units_system;
 use engine::selection_module::draw_selection_box;
 use engine::selection_module::draw_selection_on_units;
 use engine::selection_module::get_units_in_selection_system;
 use engine::selection_module::initialize_selection_module;
+use engine::selection_module::selected_units;
 use engine::selection_module::selection_box_system;
+use engine::simulation_module::Command;
 use engine::unit_module::cleanup_move_points_system;
-use engine::unit_module::collision_avoidance_system;
+use engine::unit_module::draw_attacks;
+use engine::unit_module::draw_health_bars;
 use engine::unit_module::draw_move_points;
+use engine::unit_module::draw_queued_orders;
 use engine::unit_module::draw_units;
-use engine::unit_module::get_path_for_selected_units_system;
-use engine::unit_module::initialize_units;
-use engine::unit_module::move_units_along_path_system;
+use engine::unit_module::initialize_unit_sprites;
+use engine::unit_module::issue_orders_system;
 use engine::unit_module::spawn_unit_at_mouse_position_system;
 use engine::unit_module::update_move_point_timer_system;
 
@@ -30,145 +35,121 @@ fn config(config: GameConfig) -> GameConfig {
 
 fn setup(state: &mut GameState, c: &mut EngineContext) {
     state.level.initialize(c);
-    state.set_board_based_on_tilemap();
-    initialize_units();
+    state.start_level();
     initialize_selection_module(c);
+    initialize_unit_sprites(c, &state.sim.archetypes);
 }
 
-fn place(state: &mut GameState, x: i32, y: i32) {
-    match state.place_mode {
-        PlaceMode::Start => {
-            let node = Node {
-                x,
-                y,
-                node_type: Some(NodeType::Start),
-            };
-            for y in 0..state.board.height {
-                for x in 0..state.board.width {
-                    let node = state.board.get_node_by_position(x, y);
-                    if let Some(node) = node {
-                        if node.node_type == Some(NodeType::Start) {
-                            state.board.set_node_type(x, y, NodeType::Walkable);
-                        }
-                    }
-                }
-            }
-
-            state.set_start(node);
-            state.board.set_node_type(x, y, NodeType::Start);
-        }
-        PlaceMode::End => {
-            for y in 0..state.board.height {
-                for x in 0..state.board.width {
-                    let node = state.board.get_node_by_position(x, y);
-                    if let Some(node) = node {
-                        if node.node_type == Some(NodeType::End) {
-                            state.board.set_node_type(x, y, NodeType::Walkable);
-                        }
-                    }
-                }
-            }
+fn update(state: &mut GameState, _c: &mut EngineContext) {
+    // input becomes commands for the simulation, so it can be recorded
+    let mut input = Vec::new();
 
-            state.set_end(Node {
-                x,
-                y,
-                node_type: Some(NodeType::End),
-            });
-            state.board.set_node_type(x, y, NodeType::End);
+    if is_key_pressed(KeyCode::P) {
+        state.draw_pathfinding = !state.draw_pathfinding;
+    }
+    let mut config = state.sim.config();
+    if is_key_pressed(KeyCode::N) {
+        config.neighborhood = config.neighborhood.next();
+    }
+    if is_key_pressed(KeyCode::C) {
+        config.path_smoothing.curve_samples = match config.path_smoothing.curve_samples {
+            0 => 4,
+            _ => 0,
+        };
+    }
+    if is_key_pressed(KeyCode::R) {
+        config.incremental_replanning = !config.incremental_replanning;
+    }
+    if config != state.sim.config() {
+        input.push(Command::Configure(config));
+    }
+    let offline = state.playback.is_none() && state.network.is_none();
+    if is_key_pressed(KeyCode::L) && offline {
+        let next = state.level.next_level().to_string();
+        if let Err(err) = state.switch_level(&next) {
+            error!("Failed to switch to {next}: {err}");
         }
-        PlaceMode::Wall => {
-            state.board.set_node_type(x, y, NodeType::Unwalkable);
+    }
+    if is_key_pressed(KeyCode::U) {
+        if let Some(next) = state.sim.archetypes.next_after(&state.spawn_unit_type) {
+            state.spawn_unit_type = next.name.clone();
         }
-        PlaceMode::Walkable => {
-            state.board.set_node_type(x, y, NodeType::Walkable);
+    }
+    if is_key_pressed(KeyCode::F6) {
+        match state.save_replay() {
+            Ok(path) => info!("Saved the replay to {path}"),
+            Err(err) => error!("Failed to save the replay: {err}"),
         }
     }
-}
 
-fn update(state: &mut GameState, _c: &mut EngineContext) {
-    if is_key_pressed(KeyCode::Num1) {
-        state.place_mode = PlaceMode::Start;
-    }
-    if is_key_pressed(KeyCode::Num2) {
-        state.place_mode = PlaceMode::End;
-    }
-    if is_key_pressed(KeyCode::Num3) {
-        state.place_mode = PlaceMode::Wall;
-    }
-    if is_key_pressed(KeyCode::Num4) {
-        state.place_mode = PlaceMode::Walkable;
+    // a replay plays on the level as it was recorded
+    if state.hot_reload && state.playback.is_none() {
+        state.reload_level_if_changed();
     }
 
-    if is_key_pressed(KeyCode::P) {
-        state.draw_pathfinding = !state.draw_pathfinding;
+    if state.playback.is_some() {
+        playback_system(state);
+    } else {
+        if offline {
+            editor_system(state);
+        }
+        // selecting is applied right away so orders given on the same frame
+        // see it, the command only records it
+        let selected = selected_units(&state.sim.world);
+        if !state.editor.enabled {
+            selection_box_system(&mut state.selection);
+            deselect_units_system(&mut state.sim.world);
+            spawn_unit_at_mouse_position_system(
+                state.local_player,
+                &state.spawn_unit_type,
+                &mut input,
+            );
+            get_units_in_selection_system(&state.selection, &mut state.sim.world);
+        }
+        let units = selected_units(&state.sim.world);
+        if units != selected {
+            input.push(Command::Select { units });
+        }
+        issue_orders_system(&state.sim.world, state.local_player, &mut input);
     }
+    state.advance(input, delta());
 
-    selection_box_system();
-    deselect_units_system();
-    spawn_unit_at_mouse_position_system();
-    get_units_in_selection_system();
-    get_path_for_selected_units_system(state);
-    move_units_along_path_system();
-    collision_avoidance_system();
     update_move_point_timer_system();
     cleanup_move_points_system();
 
-    // if is_mouse_button_down(MouseButton::Left) {
-    //     let mouse_pos = mouse_world();
-    //     // let node = match state.board.offset {
-    //     //     Some(offset) => state.board.get_node_by_position(
-    //     //         (mouse_pos.x - offset.x) as i32,
-    //     //         (mouse_pos.y - offset.y) as i32,
-    //     //     ),
-    //     //     None => state
-    //     //         .board
-    //     //         .get_node_by_position(mouse_pos.x as i32, mouse_pos.y as i32),
-    //     // };
-    //     let node = state
-    //         .board
-    //         .get_node_by_position(mouse_pos.x as i32, mouse_pos.y as i32);
-    //     println!("Clicked on node: {:?}", node);
-    //     if let Some(node) = node {
-    //         place(state, node.x, node.y);
-    //     }
-    // }
-
     state.rts_camera.update();
 
-    if is_key_pressed(KeyCode::Space) {}
-
-    state.board.clear_path();
-
-    if let Some(start) = state.start {
-        if let Some(end) = state.end {
-            let path = state.board.get_path(start, end);
-            if let Some(path) = path {
-                for node in path {
-                    if node.node_type == Some(NodeType::Start)
-                        || node.node_type == Some(NodeType::End)
-                    {
-                        continue;
-                    }
-                    state.board.set_node_type(node.x, node.y, NodeType::Path);
-                }
-            }
-        }
-    }
-
     if state.draw_pathfinding {
-        state.board.draw(false);
+        state.sim.board.draw(false);
     }
 
+    let mode = match (&state.playback, &state.network) {
+        (Some(player), _) => player.status(state.sim.tick),
+        (None, Some(session)) => session.status(state.sim.tick),
+        (None, None) => format!("editor: {}", state.editor.status()),
+    };
     draw_text(
-        format!("Current mode: {:?}", state.place_mode).as_str(),
+        format!(
+            "Level: {}, {mode}, neighborhood: {:?}, spawning: {}",
+            state.level.level().identifier,
+            state.sim.board.neighborhood(),
+            state.spawn_unit_type
+        )
+        .as_str(),
         vec2(-6.0, 6.0),
         RED,
         TextAlign::Center,
     );
 
-    state.level.draw();
-    draw_units();
+    state.level.draw(state.rts_camera.visible_bounds(1.0));
+    draw_level_entities(&state.sim.world);
+    let alpha = state.timestep.alpha();
+    draw_units(&state.sim.world, &state.sim.archetypes, alpha);
     draw_move_points();
-    draw_selection_box();
-    draw_selection_on_units();
+    draw_queued_orders(&state.sim.world);
+    draw_selection_box(&state.selection);
+    draw_selection_on_units(&state.sim.world, alpha);
+    draw_health_bars(&state.sim.world, alpha);
+    draw_attacks(&state.sim.world, state.sim.tick);
+    draw_editor(state);
 }